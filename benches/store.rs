@@ -0,0 +1,63 @@
+// Times a pattern query against each fragment store: reading all of verso's JSON and then
+// searching it, as `recto` does by default, and opening an `IndexedStore` and asking it. Run it
+// with `cargo bench`.
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::time::{Duration, Instant};
+
+use regex::Regex;
+use verso::{Annotations, Fragment, FragmentStore, IndexedStore};
+
+const FRAGMENTS: usize = 50_000;
+const RUNS: u32 = 5;
+
+// The average time taken by `f` over several runs.
+fn time<T>(mut f: impl FnMut() -> Result<T, Box<dyn Error>>) -> Result<Duration, Box<dyn Error>> {
+    let start = Instant::now();
+    for _ in 0..RUNS {
+        f()?;
+    }
+    Ok(start.elapsed() / RUNS)
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let dir = std::env::temp_dir().join(format!("verso-bench-{}", std::process::id()));
+    fs::create_dir_all(&dir)?;
+    let fragments: Vec<Fragment> = (0..FRAGMENTS)
+        .map(|i| Fragment {
+            id: format!("{}/{:05}", if i % 100 == 0 { "listing" } else { "code" }, i),
+            file: format!("src/file{}.rs", i / 100),
+            body: "fn example() {\n    let x = 1;\n    println!(\"{}\", x);\n}".repeat(4),
+            line: i % 100 * 20 + 1,
+            index: i + 1,
+            ..Default::default()
+        })
+        .collect();
+    let json = dir.join("fragments.json");
+    fs::write(&json, serde_json::to_string(&fragments)?)?;
+    let store_dir = dir.join("store");
+    IndexedStore::write(&store_dir, &fragments)?;
+
+    let pattern = Regex::new("^listing/")?;
+    let from_json = time(|| {
+        let read: Vec<Fragment> = serde_json::from_str(&fs::read_to_string(&json)?)?;
+        let mut annotations: Annotations = BTreeMap::new();
+        for f in read {
+            annotations.entry(f.id.to_owned()).or_default().push(f);
+        }
+        annotations.matching(&pattern)
+    })?;
+    let from_store = time(|| IndexedStore::open(&store_dir)?.matching(&pattern))?;
+
+    println!(
+        "{} fragments, matching {}: JSON {:?}, indexed store {:?}",
+        FRAGMENTS,
+        pattern.as_str(),
+        from_json,
+        from_store
+    );
+    fs::remove_dir_all(&dir)?;
+    Ok(())
+}
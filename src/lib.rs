@@ -1,9 +1,20 @@
+use ignore::WalkBuilder;
 use regex::Regex;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use std::collections::BTreeMap;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
 
 use std::error::Error;
 use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead};
+use std::ops::Bound;
+use std::path::{Component, Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::{Arc, OnceLock};
+use std::thread;
 
 // These are built using compile-time macros so that verso does not see them as starting a fragment
 // in this file.
@@ -11,29 +22,149 @@ const FRAGMENT_OPEN_SYMBOL: &str = concat!("@", "<");
 const FRAGMENT_CLOSE_SYMBOL: &str = concat!(">", "@");
 
 const ID_SAFE_CHARS: &[char] = &['/', '_', '-'];
+// Text before a fragment symbol with one of these in it is code, when a comment prefix is required.
+const QUOTE_CHARS: &[char] = &['"', '\'', '`'];
 
 const HALT_SYMBOL: &str = "@!halt";
+// Built in pieces, like the fragment symbols, so that it doesn't resume extraction of this file.
+const RESUME_SYMBOL: &str = concat!("@!", "resume");
+// Lines between these are left out of the fragments they are in.
+const SKIP_SYMBOL: &str = concat!("@!", "skip");
+const END_SKIP_SYMBOL: &str = concat!("@!", "endskip");
 const INSERTION_SYMBOL: &str = "@@";
+const FENCED_INSERTION_SYMBOL: &str = "@@@";
+const VERBATIM_INSERTION_SYMBOL: &str = "@=";
 const PATTERN_SYMBOL: &str = "@*";
 const METADATA_SYMBOL: &str = "@?";
+const TOC_SYMBOL: &str = "@toc";
+// Before a symbol in prose, as in `\@@id` or `\@?id.file`, makes it literal text.
+const ESCAPE_CHAR: char = '\\';
+// Each fragment listed by `@toc` is formatted with this, replacing `{id}` with its ID and other
+// names in braces with the metadata property of that name.
+const TOC_TEMPLATE: &str = "- `{id}` ({loc})";
+// Follows a pattern to choose the order of the fragments it inserts, as in `@* ^step/ sort=loc`.
+const SORT_OPTION: &str = "sort=";
+const SORT_BY_ID: &str = "id";
+const SORT_BY_LOC: &str = "loc";
+// Follows a pattern to put a line between the fragments it inserts, as in `@* ^step/ sep="---"`.
+const SEPARATOR_OPTION: &str = " sep=\"";
+// Introduces text to insert when an insertion's fragment is missing, as in `@@id ?? "TODO"`.
+const DEFAULT_SEPARATOR: &str = " ?? ";
+// Separates a fragment ID from a metadata property, as in `@?id.file`, by default. It is the one
+// character which may not appear in IDs, so a project whose IDs contain dots can choose another.
 const METADATA_SEPARATOR: char = '.';
+// Qualifies a metadata reference's ID with the file that defines it, as in `@?src/main.rs::id.line`.
+const NAMESPACE_SEPARATOR: &str = "::";
+const RENDER_OPTION_SEPARATOR: char = '!';
+// Selects some of a fragment's lines, counting from 1: `@@id:3-7`, or `@@id:3` for just one.
+const LINE_RANGE_SEPARATOR: char = ':';
+// Global metadata is looked up under this pseudo-fragment ID. It isn't safe for IDs, so it can't
+// collide with a real fragment.
+const GLOBAL_METADATA_ID: &str = "@";
+
+const WRAP_OPTION: &str = "wrap";
+// Precedes an insertion with a comment giving the fragment's source, as in `@@id!source` or
+// `@@id!source=#` to use `#` as the comment token.
+const SOURCE_OPTION: &str = "source";
+const SOURCE_COMMENT_STYLE: &str = "//";
+// Expand tabs to spaces, with tab stops every N columns: `tabs=N` only in each line's indentation,
+// and `alltabs=N` anywhere in the line.
+const TABS_OPTION: &str = "tabs";
+const ALL_TABS_OPTION: &str = "alltabs";
+// Precedes a nested fragment with the line that introduces it in its parent, such as a function
+// signature.
+const WITH_HEADER_OPTION: &str = "withheader";
+// Numbers each inserted line with its line in the source file.
+const LINE_NUMBERS_OPTION: &str = "lines";
+
+// Conditional insertions, `@@?flag:id` and `@@!flag:id`, insert the fragment only if the flag is
+// defined or undefined respectively.
+const CONDITION_DEFINED: char = '?';
+const CONDITION_UNDEFINED: char = '!';
+const CONDITION_SEPARATOR: char = ':';
+
+// Code fences are at least this many fence characters long, and longer if the body needs it.
+const CODE_FENCE_LEN: usize = 3;
+const CODE_FENCE_CHAR: char = '`';
+
+// The languages known by source file extension: the name for a code fence's info string, and the
+// name `@?id.lang` inserts.
+const LANGUAGES: &[(&str, &str, &str)] = &[
+    ("abap", "abap", "ABAP"),
+    ("c", "c", "C"),
+    ("cc", "cpp", "C++"),
+    ("cpp", "cpp", "C++"),
+    ("go", "go", "Go"),
+    ("h", "c", "C"),
+    ("hpp", "cpp", "C++"),
+    ("hs", "haskell", "Haskell"),
+    ("java", "java", "Java"),
+    ("js", "javascript", "JavaScript"),
+    ("md", "markdown", "Markdown"),
+    ("py", "python", "Python"),
+    ("rb", "ruby", "Ruby"),
+    ("rs", "rust", "Rust"),
+    ("sh", "bash", "Shell"),
+    ("tex", "latex", "LaTeX"),
+    ("ts", "typescript", "TypeScript"),
+];
+
+// Marks a fragment as deprecated when it follows the open symbol, as in `@<id @deprecated="use x"`.
+const DEPRECATED_TAG: &str = "@deprecated=";
+
+// Delimiters for references in the mustache syntax, e.g. `{{verso:id}}`.
+const MUSTACHE_OPEN: &str = "{{verso:";
+const MUSTACHE_CLOSE: &str = "}}";
 
 const FILENAME_REF: &str = "file";
 const LINE_NO_REF: &str = "line";
+const END_LINE_REF: &str = "endline";
+const LINES_REF: &str = "lines";
+const CHARS_REF: &str = "chars";
+const LANG_REF: &str = "lang";
 const COL_NO_REF: &str = "col";
 const LOC_REF: &str = "loc";
 const ABS_PATH_REF: &str = "abspath";
 const REL_PATH_REF: &str = "relpath";
+const INDEX_REF: &str = "index";
+const NEXT_REF: &str = "next";
+const PREV_REF: &str = "prev";
+// Takes the number of lines as an argument, as in `snippet(3)`.
+const SNIPPET_REF: &str = "snippet";
+const SNIPPET_ELLIPSIS: &str = "...";
+
+// Sets the symbols for a project when present in the working directory.
+pub const SYMBOL_FILE: &str = "verso.toml";
 
 #[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct SymbolKey {
     fragment_open: String,
     fragment_close: String,
+    // When set, the open symbol both opens and closes fragments, and the close symbol is unused.
+    toggle: bool,
+    // When set, fragment symbols only count if nothing but whitespace and punctuation other than
+    // quotes comes before them on their line, as with a comment token.
+    require_comment_prefix: bool,
 
     halt: String,
+    // Ends the region skipped by a halt symbol. A halt without one stops extraction for good.
+    resume: String,
+    skip: String,
+    end_skip: String,
     insertion: String,
+    fenced_insertion: String,
+    verbatim_insertion: String,
     pattern: String,
     metadata: String,
+    toc: String,
+    metadata_separator: char,
+    // The characters besides letters and digits which IDs may contain. The metadata separator is
+    // never allowed, even if it is listed.
+    id_chars: String,
+    // Names for `@?id.lang` by file extension, as in `cbl = "COBOL"` under `[language_names]`.
+    // Only `verso.toml` sets these.
+    language_names: BTreeMap<String, String>,
 }
 
 impl Default for SymbolKey {
@@ -41,46 +172,445 @@ impl Default for SymbolKey {
         Self {
             fragment_open: FRAGMENT_OPEN_SYMBOL.to_string(),
             fragment_close: FRAGMENT_CLOSE_SYMBOL.to_string(),
+            toggle: false,
+            require_comment_prefix: false,
             halt: HALT_SYMBOL.to_string(),
+            resume: RESUME_SYMBOL.to_string(),
+            skip: SKIP_SYMBOL.to_string(),
+            end_skip: END_SKIP_SYMBOL.to_string(),
             insertion: INSERTION_SYMBOL.to_string(),
+            fenced_insertion: FENCED_INSERTION_SYMBOL.to_string(),
+            verbatim_insertion: VERBATIM_INSERTION_SYMBOL.to_string(),
             pattern: PATTERN_SYMBOL.to_string(),
             metadata: METADATA_SYMBOL.to_string(),
+            toc: TOC_SYMBOL.to_string(),
+            metadata_separator: METADATA_SEPARATOR,
+            id_chars: ID_SAFE_CHARS.iter().chain(&[METADATA_SEPARATOR]).collect(),
+            language_names: BTreeMap::new(),
         }
     }
 }
 
 impl SymbolKey {
-    pub fn from_environment() -> Self {
-        use std::env::var;
+    // The symbols set by `VERSO_*` and `RECTO_*` environment variables, or their defaults. A
+    // metadata separator or ID characters which can't be used are an error, naming the variable.
+    pub fn from_environment() -> Result<Self, Box<dyn Error>> {
+        Self::from_vars(|name| std::env::var(name).ok())
+    }
 
+    fn from_vars(var: impl Fn(&str) -> Option<String>) -> Result<Self, Box<dyn Error>> {
         let defaults = Self::default();
+        let var = |name: &str| var(name).ok_or(());
+        let metadata_separator = match var("VERSO_METADATA_SEPARATOR") {
+            Ok(v) => single_char(&v).ok_or(SymbolError::MetadataSeparator(v)),
+            Err(_) => Ok(defaults.metadata_separator),
+        };
 
-        Self {
+        let symbols = Self {
             fragment_open: var("VERSO_FRAGMENT_OPEN_SYMBOL").unwrap_or(defaults.fragment_open),
             fragment_close: var("VERSO_FRAGMENT_CLOSE_SYMBOL").unwrap_or(defaults.fragment_close),
+            toggle: var("VERSO_TOGGLE_FRAGMENTS")
+                .map_or(defaults.toggle, |v| !v.is_empty() && v != "0"),
+            require_comment_prefix: var("VERSO_REQUIRE_COMMENT_PREFIX")
+                .map_or(defaults.require_comment_prefix, |v| {
+                    !v.is_empty() && v != "0"
+                }),
             halt: var("VERSO_HALT_SYMBOL").unwrap_or(defaults.halt),
+            resume: var("VERSO_RESUME_SYMBOL").unwrap_or(defaults.resume),
+            skip: var("VERSO_SKIP_SYMBOL").unwrap_or(defaults.skip),
+            end_skip: var("VERSO_END_SKIP_SYMBOL").unwrap_or(defaults.end_skip),
             insertion: var("RECTO_INSERTION_SYMBOL").unwrap_or(defaults.insertion),
+            fenced_insertion: var("RECTO_FENCED_INSERTION_SYMBOL")
+                .unwrap_or(defaults.fenced_insertion),
+            verbatim_insertion: var("RECTO_VERBATIM_INSERTION_SYMBOL")
+                .unwrap_or(defaults.verbatim_insertion),
             pattern: var("RECTO_PATTERN_SYMBOL").unwrap_or(defaults.pattern),
             metadata: var("RECTO_METADATA_SYMBOL").unwrap_or(defaults.metadata),
+            toc: var("RECTO_TOC_SYMBOL").unwrap_or(defaults.toc),
+            metadata_separator: metadata_separator
+                .map_err(|e| format!("Error: (VERSO_METADATA_SEPARATOR) {}", e))?,
+            id_chars: var("VERSO_ID_CHARS").unwrap_or(defaults.id_chars),
+            language_names: defaults.language_names,
+        };
+        symbols.validate().map_err(|e| {
+            let name = match e {
+                SymbolError::MetadataSeparator(_) => "VERSO_METADATA_SEPARATOR",
+                SymbolError::IdChars(_) => "VERSO_ID_CHARS",
+            };
+            format!("Error: ({}) {}", name, e).into()
+        })
+    }
+
+    // Read symbols from a TOML file whose keys are the field names, as in `fragment_open = "%<"`.
+    // Symbols which the file doesn't set keep their defaults.
+    pub fn from_file(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let contents = std::fs::read_to_string(path)?;
+        let symbols: Self = toml::from_str(&contents)
+            .map_err(|e| format!("Error: ({}) invalid symbols: {}", path.display(), e))?;
+        symbols
+            .validate()
+            .map_err(|e| format!("Error: ({}) {}", path.display(), e).into())
+    }
+
+    // The symbols for the project in the working directory: those in its `verso.toml` if it has
+    // one, or else those from the environment.
+    pub fn from_project() -> Result<Self, Box<dyn Error>> {
+        let path = Path::new(SYMBOL_FILE);
+        if path.exists() {
+            Self::from_file(path)
+        } else {
+            Self::from_environment()
+        }
+    }
+
+    // These symbols with those that `overrides` sets. A metadata separator or ID characters which
+    // can't be used are an error.
+    pub fn with_overrides(&self, overrides: &SymbolOverrides) -> Result<Self, SymbolError> {
+        let pick = |o: &Option<String>, s: &String| o.clone().unwrap_or_else(|| s.clone());
+
+        let symbols = Self {
+            fragment_open: pick(&overrides.fragment_open, &self.fragment_open),
+            fragment_close: pick(&overrides.fragment_close, &self.fragment_close),
+            toggle: self.toggle,
+            require_comment_prefix: self.require_comment_prefix,
+            halt: pick(&overrides.halt, &self.halt),
+            resume: pick(&overrides.resume, &self.resume),
+            skip: pick(&overrides.skip, &self.skip),
+            end_skip: pick(&overrides.end_skip, &self.end_skip),
+            insertion: pick(&overrides.insertion, &self.insertion),
+            fenced_insertion: pick(&overrides.fenced_insertion, &self.fenced_insertion),
+            verbatim_insertion: pick(&overrides.verbatim_insertion, &self.verbatim_insertion),
+            pattern: pick(&overrides.pattern, &self.pattern),
+            metadata: pick(&overrides.metadata, &self.metadata),
+            toc: pick(&overrides.toc, &self.toc),
+            metadata_separator: overrides
+                .metadata_separator
+                .unwrap_or(self.metadata_separator),
+            id_chars: pick(&overrides.id_chars, &self.id_chars),
+            language_names: self.language_names.clone(),
+        };
+        symbols.validate()
+    }
+
+    // These symbols, if their metadata separator and ID characters can be used.
+    fn validate(self) -> Result<Self, SymbolError> {
+        if !is_separator(self.metadata_separator) {
+            return Err(SymbolError::MetadataSeparator(
+                self.metadata_separator.to_string(),
+            ));
+        }
+        if !is_id_charset(&self.id_chars) {
+            return Err(SymbolError::IdChars(self.id_chars));
         }
+        Ok(self)
+    }
+
+    // The names for `@?id.lang` given in the project's settings, by lowercase file extension.
+    pub fn language_names(&self) -> impl Iterator<Item = (String, &str)> {
+        self.language_names
+            .iter()
+            .map(|(e, name)| (e.trim_start_matches('.').to_lowercase(), name.as_str()))
+    }
+
+    // Whether IDs may contain `c`.
+    fn is_id_char(&self, c: char) -> bool {
+        c != self.metadata_separator && c.is_safe_for_ids(&self.id_chars)
     }
 }
 
-#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+// Whether `c` can separate IDs from metadata properties without being mistaken for part of either,
+// or for one of the other separators in a directive.
+fn is_separator(c: char) -> bool {
+    !c.is_alphanumeric()
+        && !c.is_whitespace()
+        && ![RENDER_OPTION_SEPARATOR, LINE_RANGE_SEPARATOR, ESCAPE_CHAR].contains(&c)
+}
+
+// Whether IDs can be allowed to contain every character of `chars`. Whitespace and the other
+// separators in a directive would make the end of an ID ambiguous.
+fn is_id_charset(chars: &str) -> bool {
+    !chars.chars().any(|c| {
+        c.is_whitespace()
+            || !is_separator(c) && !c.is_alphanumeric()
+            || GLOBAL_METADATA_ID.contains(c)
+    })
+}
+
+// The character given by a setting like `VERSO_METADATA_SEPARATOR`, which must be just one.
+fn single_char(value: &str) -> Option<char> {
+    let mut chars = value.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => Some(c),
+        _ => None,
+    }
+}
+
+// A symbol setting which can't be used, with the value it was given.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum SymbolError {
+    // Not a single character which can be told apart from IDs and properties.
+    MetadataSeparator(String),
+    // Includes whitespace or a character which already means something in a directive.
+    IdChars(String),
+}
+
+impl Error for SymbolError {}
+
+impl fmt::Display for SymbolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SymbolError::MetadataSeparator(value) => {
+                write!(f, "invalid metadata separator '{}'", value)
+            }
+            SymbolError::IdChars(value) => write!(f, "invalid ID characters '{}'", value),
+        }
+    }
+}
+
+// How an insertion renders its fragment. Each mode has its own insertion symbol.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum InsertionMode {
+    // The fragment body, with any render options applied.
+    Plain,
+    // As with Plain, but wrapped in a Markdown code fence.
+    Fenced,
+    // The fragment body exactly as extracted. Render options are not accepted.
+    Verbatim,
+}
+
+impl SymbolKey {
+    // Find the insertion symbol which starts `line`, if any. Symbols may be prefixes of one another
+    // (`@@` and `@@@`, for instance), so the longest match wins.
+    pub fn match_insertion(&self, line: &str) -> Option<(&str, InsertionMode)> {
+        let mut symbols = [
+            (self.insertion.as_str(), InsertionMode::Plain),
+            (self.fenced_insertion.as_str(), InsertionMode::Fenced),
+            (self.verbatim_insertion.as_str(), InsertionMode::Verbatim),
+        ];
+        symbols.sort_by_key(|(symbol, _)| std::cmp::Reverse(symbol.len()));
+        symbols
+            .iter()
+            .find(|(symbol, _)| !symbol.is_empty() && line.starts_with(symbol))
+            .copied()
+    }
+}
+
+// A partial SymbolKey, used to layer settings from a more specific source (such as a prose file's
+// front matter) over the global ones. Fields which are not set leave the base symbol unchanged.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SymbolOverrides {
+    pub fragment_open: Option<String>,
+    pub fragment_close: Option<String>,
+
+    pub halt: Option<String>,
+    pub resume: Option<String>,
+    pub skip: Option<String>,
+    pub end_skip: Option<String>,
+    pub insertion: Option<String>,
+    pub fenced_insertion: Option<String>,
+    pub verbatim_insertion: Option<String>,
+    pub pattern: Option<String>,
+    pub metadata: Option<String>,
+    pub toc: Option<String>,
+    pub metadata_separator: Option<char>,
+    pub id_chars: Option<String>,
+}
+
+// What `verso --show-config` and `recto --show-config` print: the resolved options, the project's
+// symbols, and the symbols of each prose file whose own settings change them.
+pub fn describe_config<'a>(
+    options: &dyn fmt::Debug,
+    symbols: &SymbolKey,
+    prose_symbols: impl IntoIterator<Item = (&'a str, &'a SymbolKey)>,
+) -> String {
+    let mut lines = vec![
+        format!("{:#?}", options),
+        format!("Symbols: {:#?}", symbols),
+    ];
+    for (filename, own) in prose_symbols {
+        if own != symbols {
+            lines.push(format!("Symbols for {}: {:#?}", filename, own));
+        }
+    }
+    lines.join("\n")
+}
+
+#[derive(Debug, Default, PartialEq, Eq, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct Fragment {
     pub body: String,
     pub id: String,
     pub file: String,
     pub line: usize,
     pub col: usize,
+    // The line of the open symbol. That is the line before `line`, except for inline fragments, whose
+    // bodies share the open symbol's line. Zero if it wasn't recorded, as by older versions of verso.
+    #[serde(default)]
+    pub marker_line: usize,
+    // The last line of the fragment, just before its close symbol. Zero if it wasn't recorded, as
+    // by older versions of verso.
+    #[serde(default)]
+    pub end_line: usize,
+    // The text preceding the open symbol on its line, usually a comment token such as `//` or `#`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub open_prefix: Option<String>,
+    // The fragment's position, counting from 1, among all of the fragments `verso` emitted in one
+    // run. Zero if it wasn't assigned.
+    #[serde(default)]
+    pub index: usize,
+    // Why the fragment shouldn't be used any more, and what to use instead. References to it are
+    // reported with this message.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deprecated: Option<String>,
+    // The line of the source file that each line of the body came from, when extracted with
+    // `ExtractOptions::source_lines`. Empty otherwise.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub source_lines: Vec<usize>,
+}
+
+// The JSON Schema of `verso`'s output, a list of fragments, for tools which read or validate it.
+pub fn fragments_schema() -> schemars::schema::RootSchema {
+    schemars::schema_for!(Vec<Fragment>)
+}
+
+// A Fragment with only the fields emitted by verso 0.3.0, for consumers which reject unknown keys,
+// as written by `verso --compat`.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize)]
+pub struct CompatFragment<'a> {
+    pub body: &'a str,
+    pub id: &'a str,
+    pub file: &'a str,
+    pub line: usize,
+    pub col: usize,
+}
+
+impl<'a> From<&'a Fragment> for CompatFragment<'a> {
+    fn from(f: &'a Fragment) -> CompatFragment<'a> {
+        CompatFragment {
+            body: &f.body,
+            id: &f.id,
+            file: &f.file,
+            line: f.line,
+            col: f.col,
+        }
+    }
+}
+
+impl Fragment {
+    // The line of the open symbol. Fragments written by older versions of verso didn't record it,
+    // and give the line before their body, where it usually is.
+    pub fn open_line(&self) -> usize {
+        match self.marker_line {
+            0 => self.line.saturating_sub(1),
+            line => line,
+        }
+    }
+
+    // Add another block with the same ID to the end of this one, as when a fragment is reopened
+    // further down a file. The fragment keeps its start, and ends where `next` does.
+    pub fn append(&mut self, next: &Fragment) {
+        self.body.push('\n');
+        self.body.push_str(&next.body);
+        self.end_line = next.end_line;
+        self.source_lines.extend(&next.source_lines);
+        if self.deprecated.is_none() {
+            self.deprecated = next.deprecated.clone();
+        }
+    }
+
+    // The fragment's body as a Markdown code block, the way `@@@id` inserts it but without trimming
+    // or render options.
+    pub fn to_fenced_block(&self, options: &FenceOptions) -> String {
+        let info = options
+            .info
+            .as_deref()
+            .or_else(|| fence_language(&self.file))
+            .unwrap_or_default();
+        let block = code_block(&self.body, options.fence_char, info);
+        match &options.caption {
+            Some(caption) => format!("{}\n\n{}", caption, block),
+            None => block,
+        }
+    }
+}
+
+// How `Fragment::to_fenced_block` writes a code block.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct FenceOptions {
+    // Either '`' or '~'.
+    pub fence_char: char,
+    // The info string after the opening fence, usually a language. If not given, the language is
+    // guessed from the fragment's file extension.
+    pub info: Option<String>,
+    // A paragraph to put before the block.
+    pub caption: Option<String>,
+}
+
+impl Default for FenceOptions {
+    fn default() -> FenceOptions {
+        FenceOptions {
+            fence_char: CODE_FENCE_CHAR,
+            info: None,
+            caption: None,
+        }
+    }
+}
+
+fn fence_language(filename: &str) -> Option<&'static str> {
+    let extension = Path::new(filename).extension()?.to_str()?;
+    known_language(extension).map(|(_, language, _)| *language)
+}
+
+// The entry in `LANGUAGES` for a file extension, in any case.
+fn known_language(extension: &str) -> Option<&'static (&'static str, &'static str, &'static str)> {
+    LANGUAGES
+        .iter()
+        .find(|(e, _, _)| e.eq_ignore_ascii_case(extension))
+}
+
+// The name of the language a file is written in, from its extension. Extensions which aren't known
+// are returned as they are, and files without one have no name.
+fn language_name(filename: &str, options: &WeaveOptions) -> String {
+    let extension = match Path::new(filename).extension().and_then(|e| e.to_str()) {
+        Some(extension) => extension,
+        None => return String::new(),
+    };
+    let lowercase = extension.to_lowercase();
+    match options.language_names.get(&lowercase) {
+        Some(name) => name.to_owned(),
+        None => known_language(extension)
+            .map_or(extension, |(_, _, name)| name)
+            .to_owned(),
+    }
 }
 
+// `body` between code fences. The fences are made longer than any run of fence characters which
+// begins a line of the body, so that the body can't close the block early.
+fn code_block(body: &str, fence_char: char, info: &str) -> String {
+    let longest = body
+        .lines()
+        .map(|line| {
+            line.trim_start()
+                .chars()
+                .take_while(|&c| c == fence_char)
+                .count()
+        })
+        .max()
+        .unwrap_or_default();
+    let fence = fence_char
+        .to_string()
+        .repeat(CODE_FENCE_LEN.max(longest + 1));
+    format!("{}{}\n{}\n{}", fence, info, body, fence)
+}
+
+// Why `extract_id` found no fragment ID.
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub enum IdExtractError {
     NoIdFound,
     ReservedCharacterUsed(char),
 }
 
+// Why `extract_pattern` found no ID pattern.
 #[derive(Debug, PartialEq, Clone)]
 pub enum PatternExtractError {
     NoPatternFound,
@@ -94,6 +624,13 @@ pub enum ParseError {
     MissingId,
     IdExtractError,
     HaltWhileOpen,
+    MismatchedToggle,
+    DoubleOpen,
+    NestingTooDeep,
+    // A close symbol named a fragment other than the one it closes: (expected, found).
+    MismatchedClose(String, String),
+    // A fragment's ID doesn't match the pattern required of every ID.
+    IdPatternMismatch(String),
 }
 
 // @<errors
@@ -106,6 +643,11 @@ pub enum WeaveError {
     MetadataParseError,
     BadMetadata(String),
     UnknownProperty(String),
+    BadRenderOption(String),
+    // The fragment ID and the line range, as written, which doesn't fit in it.
+    RangeOutOfBounds(String, String),
+    // Text which wove to more than one line where only one fits, as in a word of a Pandoc tree.
+    MultilineInsertion(String),
 }
 
 #[derive(Debug, PartialEq, Eq, Clone)]
@@ -118,6 +660,32 @@ pub struct FileError<T: fmt::Debug> {
 }
 // >@errors
 
+impl<T: fmt::Debug> FileError<T> {
+    // The line the error was found on, counting from 1.
+    pub fn line(&self) -> usize {
+        self.line
+    }
+}
+
+// A problem which doesn't stop processing, but is probably a mistake.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Warning {
+    pub filename: String,
+    pub line: usize,
+    pub col: usize,
+    pub message: String,
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Warning: ({}:{}:{}) {}",
+            self.filename, self.line, self.col, self.message
+        )
+    }
+}
+
 impl<T: fmt::Debug> Error for FileError<T> {}
 
 impl<T: fmt::Debug> fmt::Display for FileError<T> {
@@ -134,14 +702,141 @@ impl<T: fmt::Debug> fmt::Display for FileError<T> {
     }
 }
 
+// A piece of a line, as split up by a `Scanner`.
+#[derive(Debug, PartialEq, Eq, Clone)]
+struct Token<'a> {
+    kind: TokenKind<'a>,
+    // All of the text covered by the token, including any symbol.
+    text: &'a str,
+    // Where the token starts in the line, in bytes and in characters respectively.
+    start: usize,
+    col: usize,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum TokenKind<'a> {
+    // Text which isn't part of a symbol.
+    Text,
+    // A symbol, and the word (such as an ID) which immediately follows it. The word may be empty.
+    Symbol { symbol: &'a str, word: &'a str },
+}
+
+impl<'a> Token<'a> {
+    // The word following `symbol`, if this token is that symbol.
+    fn word_after(&self, symbol: &str) -> Option<&'a str> {
+        match self.kind {
+            TokenKind::Symbol { symbol: s, word } if s == symbol => Some(word),
+            _ => None,
+        }
+    }
+}
+
+// Splits a line into text and symbol tokens, left to right. The words which follow symbols are
+// measured by `word_len`, but never run into the next symbol, so `@<a@<b` holds two symbols.
+struct Scanner<'a> {
+    line: &'a str,
+    symbols: Vec<&'a str>,
+    word_len: &'a dyn Fn(&str) -> usize,
+    // Only accept the first symbol on the line if the text before it looks like a comment token.
+    comment_prefix_only: bool,
+    seen_symbol: bool,
+    pos: usize,
+    col: usize,
+}
+
+impl<'a> Scanner<'a> {
+    fn new(line: &'a str, symbols: &[&'a str], word_len: &'a dyn Fn(&str) -> usize) -> Self {
+        let mut symbols: Vec<&str> = symbols.iter().copied().filter(|s| !s.is_empty()).collect();
+        // Symbols may be prefixes of one another, so the longest match wins.
+        symbols.sort_by_key(|s| std::cmp::Reverse(s.len()));
+        Scanner {
+            line,
+            symbols,
+            word_len,
+            comment_prefix_only: false,
+            seen_symbol: false,
+            pos: 0,
+            col: 0,
+        }
+    }
+
+    fn comment_prefix_only(mut self, on: bool) -> Self {
+        self.comment_prefix_only = on;
+        self
+    }
+
+    fn symbol_at(&self, pos: usize) -> Option<&'a str> {
+        // Symbols after the first are within the same comment, so need no check of their own.
+        if self.comment_prefix_only
+            && !self.seen_symbol
+            && self.line[..pos]
+                .chars()
+                .any(|c| c.is_alphanumeric() || QUOTE_CHARS.contains(&c))
+        {
+            return None;
+        }
+        let rest = &self.line[pos..];
+        self.symbols.iter().copied().find(|s| rest.starts_with(s))
+    }
+
+    // The byte offset of the first symbol in `self.line[from..to]`, or `to` if there is none.
+    fn next_symbol(&self, from: usize, to: usize) -> usize {
+        self.line[from..to]
+            .char_indices()
+            .map(|(i, _)| from + i)
+            .find(|&i| self.symbol_at(i).is_some())
+            .unwrap_or(to)
+    }
+}
+
+impl<'a> Iterator for Scanner<'a> {
+    type Item = Token<'a>;
+
+    fn next(&mut self) -> Option<Token<'a>> {
+        let start = self.pos;
+        if start >= self.line.len() {
+            return None;
+        }
+
+        let kind = match self.symbol_at(start) {
+            Some(symbol) => {
+                self.seen_symbol = true;
+                let word_start = start + symbol.len();
+                let word_end = word_start + (self.word_len)(&self.line[word_start..]);
+                self.pos = self.next_symbol(word_start, word_end);
+                TokenKind::Symbol {
+                    symbol,
+                    word: &self.line[word_start..self.pos],
+                }
+            }
+            None => {
+                let first_len = self.line[start..].chars().next().map_or(0, char::len_utf8);
+                self.pos = self.next_symbol(start + first_len, self.line.len());
+                TokenKind::Text
+            }
+        };
+
+        let text = &self.line[start..self.pos];
+        let col = self.col;
+        self.col += text.chars().count();
+        Some(Token {
+            kind,
+            text,
+            start,
+            col,
+        })
+    }
+}
+
 trait IdSafe {
-    fn is_safe_for_ids(&self) -> bool;
+    // IDs may hold letters and digits, and the characters in `extra`.
+    fn is_safe_for_ids(&self, extra: &str) -> bool;
     fn is_safe_for_refs(&self) -> bool;
 }
 
 impl IdSafe for char {
-    fn is_safe_for_ids(&self) -> bool {
-        self.is_alphanumeric() || ID_SAFE_CHARS.contains(self)
+    fn is_safe_for_ids(&self, extra: &str) -> bool {
+        self.is_alphanumeric() || extra.contains(*self)
     }
 
     fn is_safe_for_refs(&self) -> bool {
@@ -149,930 +844,8292 @@ impl IdSafe for char {
     }
 }
 
-pub fn extract_fragments(
-    contents: &str,
-    filename: &str,
-    symbols: &SymbolKey,
-) -> Result<Vec<Fragment>, FileError<ParseError>> {
-    let mut fragments: Vec<Fragment> = vec![];
-    let mut fragment_stack: Vec<Fragment> = vec![];
-
-    for (line, content) in contents.split('\n').enumerate().map(|(l, c)| (l + 1, c)) {
-        if let Some(col) = content.find(&symbols.fragment_open) {
-            match extract_id(content, col + symbols.fragment_open.len()) {
-                Ok(id) => {
-                    // Push a new Fragment onto the stack.
-                    fragment_stack.push(Fragment {
-                        body: String::new(),
-                        id,
-                        file: filename.to_owned(),
-                        // The Fragment starts on the line after the opening symbol.
-                        line: line + 1,
-                        col: 0,
-                    });
-                }
-                Err(IdExtractError::NoIdFound) => {
-                    return Err(FileError {
-                        err_type: ParseError::MissingId,
-                        filename: filename.to_owned(),
-                        line,
-                        col,
-                        message: Some(format!(
-                            "no fragment identifier found in fragment open symbol: {}",
-                            line
-                        )),
-                    });
-                }
-                Err(IdExtractError::ReservedCharacterUsed(c)) => {
-                    return Err(FileError {
-                        err_type: ParseError::IdExtractError,
-                        filename: filename.to_owned(),
-                        line,
-                        col,
-                        message: Some(format!(
-                            "error parsing fragment identifier in fragment open symbol: {}
-                                     (used reserved character {})",
-                            line, c
-                        )),
-                    });
-                }
+// Settings which change how `extract_fragments` behaves, beyond the symbols it looks for.
+#[derive(Debug, Default, PartialEq, Eq, Clone)]
+pub struct ExtractOptions {
+    // Store nested fragments dedented relative to their own first line, so that they read naturally
+    // when inserted on their own. Their parents still receive the original indentation.
+    pub dedent_nested: bool,
+    // Remove the indentation shared by every line of each fragment, nested or not.
+    pub dedent: bool,
+    // How to trim each fragment's body once it is closed.
+    pub trim: TrimPolicy,
+    // When a nested fragment sits between blank lines in its parent, keep only one of them in the
+    // parent's body.
+    pub collapse_nested_blanks: bool,
+    // Record file paths with forward slashes, however they were given, so that output is the same
+    // on every platform.
+    pub posix_paths: bool,
+    // Only recognize symbols on lines which begin with this comment token, so that symbols in
+    // string literals and the like are left alone.
+    pub comment_prefix: Option<String>,
+    // The most fragments which may be open at once. Going deeper is usually a missing close.
+    pub max_nesting: Option<usize>,
+    // Record the source line of every line of each fragment's body, in `Fragment::source_lines`.
+    pub source_lines: bool,
+    // Warn about fragments whose bodies are empty once trimmed, which usually means two markers
+    // were put side by side by mistake.
+    pub warn_empty: bool,
+}
+
+// Comment tokens for `ExtractOptions::comment_prefix`, chosen by file extension.
+#[derive(Debug, Default, PartialEq, Eq, Clone)]
+pub struct CommentPrefixes {
+    by_extension: BTreeMap<String, String>,
+    // For files whose extension isn't listed.
+    default: Option<String>,
+}
+
+impl CommentPrefixes {
+    // Parse a list like `rs=//,py=#,sql=--`. An entry without an extension, or with the extension
+    // `*`, is the default.
+    pub fn parse(spec: &str) -> Result<CommentPrefixes, &'static str> {
+        let mut prefixes = CommentPrefixes::default();
+        for entry in spec.split(',') {
+            let (extension, prefix) = entry.split_once('=').unwrap_or(("*", entry));
+            let extension = extension.trim().trim_start_matches('.');
+            let prefix = prefix.trim();
+            if extension.is_empty() || prefix.is_empty() {
+                return Err("Comment prefixes must be given as 'ext=prefix' or 'prefix'");
             }
-        } else if let Some(col) = content.find(&symbols.fragment_close) {
-            if let Some(closed_fragment) = fragment_stack.pop() {
-                let trimmed_body = closed_fragment.body.trim_end_matches('\n').to_string();
-                if let Some(parent_fragment) = fragment_stack.last_mut() {
-                    // Special handling of "empty" fragments.
-                    if !trimmed_body.is_empty() {
-                        // Add the child fragments body to the parent fragment.
-                        parent_fragment.body.push_str(&trimmed_body);
-                        parent_fragment.body.push('\n');
-                    }
-                }
-                // Add the closed fragment to the results list
-                fragments.push(Fragment {
-                    body: trimmed_body,
-                    ..closed_fragment
-                });
+            if extension == "*" {
+                prefixes.default = Some(prefix.to_owned());
             } else {
-                return Err(FileError {
-                    err_type: ParseError::CloseBeforeOpen,
-                    filename: filename.to_owned(),
-                    line,
-                    col,
-                    message: Some("fragment close symbol found without an open symbol".to_string()),
-                });
-            }
-        } else if let Some(col) = content.find(&symbols.halt) {
-            // If the Fragment stack is not empty, we have an error as there is at least 1 open
-            // Fragment.
-            if !fragment_stack.is_empty() {
-                return Err(FileError {
-                    err_type: ParseError::HaltWhileOpen,
-                    filename: filename.to_owned(),
-                    line,
-                    col,
-                    message: Some(format!(
-                        "halt symbol found while a fragment was open: {}",
-                        line
-                    )),
-                });
+                prefixes
+                    .by_extension
+                    .insert(extension.to_owned(), prefix.to_owned());
             }
-            // Otherwise stop processing and break out.
-            break;
-        } else if let Some(fragment) = fragment_stack.last_mut() {
-            fragment.body.push_str(content);
-            fragment.body.push('\n');
         }
+        Ok(prefixes)
     }
 
-    if !fragment_stack.is_empty() {
-        return Err(FileError {
-            err_type: ParseError::UnclosedFragment,
-            filename: filename.to_owned(),
-            line: contents.lines().count(),
-            col: 0,
-            message: Some("not all fragments were closed".to_string()),
-        });
+    // The comment token for the file `filename`, if there is one.
+    pub fn for_file(&self, filename: &str) -> Option<&str> {
+        Path::new(filename)
+            .extension()
+            .and_then(|e| self.by_extension.get(e.to_string_lossy().as_ref()))
+            .or(self.default.as_ref())
+            .map(String::as_str)
     }
+}
 
-    Ok(fragments)
+// A pattern which every fragment ID must match in full, as given to `verso --id-pattern`.
+#[derive(Debug, Clone)]
+pub struct IdPattern {
+    pattern: String,
+    re: Regex,
 }
 
-// @<extractid
-fn extract_id(content: &str, col: usize) -> Result<String, IdExtractError> {
-    let it = content.chars().skip(col);
-    let id: String = it.take_while(|c| !c.is_whitespace()).collect();
-    if id.is_empty() {
-        Err(IdExtractError::NoIdFound)
-    } else if let Some(idx) = id.find(|c: char| !c.is_safe_for_ids()) {
-        Err(IdExtractError::ReservedCharacterUsed(
-            id.chars().nth(idx).unwrap(),
-        ))
-    } else {
-        Ok(id)
+impl IdPattern {
+    pub fn new(pattern: &str) -> Result<IdPattern, regex::Error> {
+        Ok(IdPattern {
+            pattern: pattern.to_owned(),
+            re: Regex::new(&format!("^(?:{})$", pattern))?,
+        })
+    }
+
+    // Fails with an error for each of `fragments` whose ID doesn't match, at the line of its open
+    // symbol.
+    pub fn check(&self, fragments: &[Fragment]) -> Result<(), Vec<FileError<ParseError>>> {
+        let errors: Vec<FileError<ParseError>> = fragments
+            .iter()
+            .filter(|f| !self.re.is_match(&f.id))
+            .map(|f| FileError {
+                err_type: ParseError::IdPatternMismatch(f.id.to_owned()),
+                filename: f.file.to_owned(),
+                line: f.open_line(),
+                col: f.col,
+                message: Some(format!(
+                    "fragment ID '{}' does not match the pattern '{}'",
+                    f.id, self.pattern
+                )),
+            })
+            .collect();
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
     }
 }
-// >@extractid
 
-fn extract_pattern(content: &str, col: usize) -> Result<Regex, PatternExtractError> {
-    // Remove leading characters to get just the pattern
-    let pat = &content[col..];
-    // Remove leading and trailing whitespace; patterns should use ^/$ to include it
-    let pat = pat.trim_start().trim_end();
-    if pat.is_empty() {
-        Err(PatternExtractError::NoPatternFound)
-    } else {
-        Regex::new(pat).map_err(PatternExtractError::RegexConstruction)
+// How much whitespace to trim from a fragment's body.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub enum TrimPolicy {
+    // Leave the body exactly as it appeared, including the final line's newline.
+    None,
+    // Remove newlines from the end of the body.
+    #[default]
+    TrailingNewlines,
+    // Remove whitespace from the end of every line, and blank lines from the end of the body.
+    TrailingWhitespace,
+    // As with TrailingWhitespace, and also remove blank lines from the start of the body.
+    Full,
+}
+
+impl TrimPolicy {
+    // Parse a policy from its name on the command line.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "none" => Some(TrimPolicy::None),
+            "trailing-newlines" => Some(TrimPolicy::TrailingNewlines),
+            "trailing-ws" => Some(TrimPolicy::TrailingWhitespace),
+            "full" => Some(TrimPolicy::Full),
+            _ => None,
+        }
+    }
+
+    pub fn apply(&self, body: &str) -> String {
+        match self {
+            TrimPolicy::None => body.to_owned(),
+            TrimPolicy::TrailingNewlines => body.trim_end_matches('\n').to_owned(),
+            TrimPolicy::TrailingWhitespace | TrimPolicy::Full => {
+                let lines: Vec<&str> = body.lines().map(str::trim_end).collect();
+                let start = match self {
+                    TrimPolicy::Full => lines.iter().position(|l| !l.is_empty()),
+                    _ => Some(0),
+                };
+                let end = lines.iter().rposition(|l| !l.is_empty()).map(|i| i + 1);
+                match (start, end) {
+                    (Some(start), Some(end)) => lines[start..end].join("\n"),
+                    _ => String::new(),
+                }
+            }
+        }
     }
 }
 
-pub fn weave(
+pub fn extract_fragments(
+    contents: &str,
     filename: &str,
+    symbols: &SymbolKey,
+) -> Result<Vec<Fragment>, FileError<ParseError>> {
+    extract_fragments_with_options(contents, filename, symbols, &ExtractOptions::default())
+}
+
+pub fn extract_fragments_with_options(
     contents: &str,
-    annotations: &BTreeMap<String, Fragment>,
+    filename: &str,
     symbols: &SymbolKey,
-) -> Result<String, FileError<WeaveError>> {
-    let mut substrings: Vec<String> = vec![];
+    options: &ExtractOptions,
+) -> Result<Vec<Fragment>, FileError<ParseError>> {
+    extract_fragments_with_warnings(contents, filename, symbols, options)
+        .map(|(fragments, _)| fragments)
+}
 
-    for (line_no, line) in contents.lines().enumerate().map(|(l, c)| (l + 1, c)) {
-        if line.trim_start().starts_with(&symbols.insertion) {
-            let id = extract_id(line.trim_start(), symbols.insertion.len());
-            match id {
-                Ok(id) => {
-                    let fragment = annotations.get(&id);
-                    match fragment {
-                        // TODO Add indexing information.
-                        Some(f) => substrings.push(f.body.to_owned()),
-                        None => {
-                            return Err(FileError {
-                                err_type: WeaveError::MissingFragment(id.to_owned()),
-                                filename: filename.to_owned(),
-                                line: line_no,
-                                col: symbols.insertion.len(),
-                                message: Some(format!("no fragment found with identifier {}", id)),
-                            })
-                        }
-                    }
-                }
-                Err(IdExtractError::NoIdFound) => {
-                    return Err(FileError {
-                        err_type: WeaveError::MissingId,
-                        filename: filename.to_owned(),
-                        line: line_no,
-                        col: 0,
-                        message: Some(format!("no fragment identifier found in line: {}", line)),
-                    })
-                }
-                Err(IdExtractError::ReservedCharacterUsed(c)) => {
-                    return Err(FileError {
-                        err_type: WeaveError::IdExtractError,
-                        filename: filename.to_owned(),
-                        line: line_no,
-                        col: 0,
-                        message: Some(format!(
-                            "error parsing identifier in fragment open symbol: {}
-                             (used reserved character {})",
-                            line, c
-                        )),
-                    })
-                }
-            }
-        } else if line.trim_start().starts_with(&symbols.pattern) {
-            let re = extract_pattern(line.trim_start(), symbols.pattern.len());
-            match re {
-                Ok(re) => {
-                    annotations
-                        .iter()
-                        .filter(|(k, _)| re.is_match(k))
-                        .for_each(|(_, v)| substrings.push(v.body.to_owned()));
-                }
-                Err(PatternExtractError::NoPatternFound) => {
-                    return Err(FileError {
-                        err_type: WeaveError::PatternExtractError,
-                        filename: filename.to_owned(),
-                        line: line_no,
-                        col: 0,
-                        message: Some(format!("no fragment pattern found in line: {}", line)),
-                    })
-                }
-                Err(PatternExtractError::RegexConstruction(e)) => {
-                    return Err(FileError {
-                        err_type: WeaveError::PatternExtractError,
-                        filename: filename.to_owned(),
-                        line: line_no,
-                        col: 0,
-                        message: Some(format!(
-                            "error parsing pattern at insertion symbol: {}
-                             (regex construction failed with {})",
-                            line, e
-                        )),
-                    })
-                }
-            }
-        } else if line.contains(&symbols.metadata) {
-            let expanded = expand_metadata_refs(line, filename, line_no, annotations, symbols)?;
-            substrings.push(expanded);
-        } else {
-            substrings.push(line.to_owned());
-        }
-    }
+// The fragments found in a file and the warnings about them, or every error found in it.
+pub type ExtractResult = Result<(Vec<Fragment>, Vec<Warning>), Vec<FileError<ParseError>>>;
 
-    // Account for final newline, which str.lines() may drop.
-    if contents.ends_with('\n') && substrings.last().map_or(true, |c| !c.contains('\n')) {
-        substrings.push("".to_owned());
-    }
+// As `extract_fragments_with_options`, but also report things which are legal but likely mistakes.
+pub fn extract_fragments_with_warnings(
+    contents: &str,
+    filename: &str,
+    symbols: &SymbolKey,
+    options: &ExtractOptions,
+) -> Result<(Vec<Fragment>, Vec<Warning>), FileError<ParseError>> {
+    extract_fragments_all_with_warnings(contents, filename, symbols, options)
+        .map_err(|mut errors| errors.remove(0))
+}
 
-    let document = substrings.join("\n");
+// As `extract_fragments`, but carry on past errors so that all of them can be reported at once.
+pub fn extract_fragments_all(
+    contents: &str,
+    filename: &str,
+    symbols: &SymbolKey,
+) -> Result<Vec<Fragment>, Vec<FileError<ParseError>>> {
+    extract_fragments_all_with_warnings(contents, filename, symbols, &ExtractOptions::default())
+        .map(|(fragments, _)| fragments)
+}
 
-    Ok(document)
+// As `extract_fragments_with_warnings`, but collecting every error. After an error the parser
+// recovers as best it can: a stray close symbol is ignored, and an open symbol without a usable ID
+// still opens a fragment, so that its close symbol balances, but one which is dropped when closed.
+pub fn extract_fragments_all_with_warnings(
+    contents: &str,
+    filename: &str,
+    symbols: &SymbolKey,
+    options: &ExtractOptions,
+) -> ExtractResult {
+    // A final newline doesn't start another line.
+    let contents = contents.strip_suffix('\n').unwrap_or(contents);
+    extract_lines(contents.split('\n'), filename, symbols, options)
 }
 
-#[derive(Debug, PartialEq, Clone)]
-enum ScannerState {
-    SearchingForRefStart,
-    ReadingMetaStart,
-    ReadingId,
-    ReadingRefType,
+// As `extract_fragments_all_with_warnings`, but reading the file a line at a time, so that only the
+// fragments are held in memory rather than the whole file. Fails if the file can't be read, or
+// isn't UTF-8 text.
+pub fn extract_fragments_reader<R: BufRead>(
+    reader: R,
+    filename: &str,
+    symbols: &SymbolKey,
+    options: &ExtractOptions,
+) -> io::Result<ExtractResult> {
+    let mut read_error = None;
+    let lines = reader
+        .split(b'\n')
+        .map(|line| {
+            String::from_utf8(line?).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        })
+        .map_while(|line| line.map_err(|e| read_error = Some(e)).ok());
+    let extracted = extract_lines(lines, filename, symbols, options);
+    match read_error {
+        Some(e) => Err(e),
+        None => Ok(extracted),
+    }
 }
 
-fn expand_metadata_refs(
-    line: &str,
+// As `extract_fragments_reader`, for the file at `filename`. Gives None for a file which isn't
+// UTF-8 text, which `verso` skips with a warning rather than failing.
+pub fn extract_fragments_file(
     filename: &str,
-    line_no: usize,
-    annotations: &BTreeMap<String, Fragment>,
     symbols: &SymbolKey,
-) -> Result<String, FileError<WeaveError>> {
-    let mut pieces: Vec<String> = vec![];
+    options: &ExtractOptions,
+) -> io::Result<Option<ExtractResult>> {
+    let reader = io::BufReader::new(File::open(filename)?);
+    match extract_fragments_reader(reader, filename, symbols, options) {
+        Ok(extracted) => Ok(Some(extracted)),
+        Err(e) if e.kind() == io::ErrorKind::InvalidData => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+// The parser behind every `extract_fragments` variant, which takes the file's lines one by one.
+fn extract_lines<S: AsRef<str>>(
+    lines: impl Iterator<Item = S>,
+    filename: &str,
+    symbols: &SymbolKey,
+    options: &ExtractOptions,
+) -> ExtractResult {
+    let mut fragments: Vec<Fragment> = vec![];
+    let mut fragment_stack: Vec<OpenFragment> = vec![];
+    let mut warnings: Vec<Warning> = vec![];
+    let mut errors: Vec<FileError<ParseError>> = vec![];
+    let mut depth = 0;
+    let mut line_count = 0;
+    // Between a halt symbol and the next resume symbol, lines are skipped.
+    let mut halted = false;
+    // Where each skip symbol that hasn't been ended is. Skips may be nested.
+    let mut skips: Vec<(usize, usize)> = vec![];
+    let posix_filename;
+    let filename = if options.posix_paths {
+        posix_filename = posix_path(filename);
+        posix_filename.as_str()
+    } else {
+        filename
+    };
+
+    for (line, content) in lines.enumerate().map(|(l, c)| (l + 1, c)) {
+        // Files with Windows line endings are read as if they had Unix ones.
+        let content = content.as_ref();
+        let content = content.strip_suffix('\r').unwrap_or(content);
+        line_count = line;
+        // Whether the previous line closed a nested fragment, leaving its parent open.
+        let follows_child = fragment_stack.len() < depth && !fragment_stack.is_empty();
+        depth = fragment_stack.len();
 
-    let mut state = ScannerState::SearchingForRefStart;
-    let mut start_col: usize = 0;
+        // In toggle mode the close symbol isn't used at all.
+        let mut line_symbols = vec![
+            symbols.fragment_open.as_str(),
+            symbols.halt.as_str(),
+            symbols.resume.as_str(),
+            symbols.skip.as_str(),
+            symbols.end_skip.as_str(),
+        ];
+        if !symbols.toggle {
+            line_symbols.push(symbols.fragment_close.as_str());
+        }
+        // Lines which aren't comments can't hold symbols, when a comment token is required.
+        let uncommented = options
+            .comment_prefix
+            .as_deref()
+            .is_some_and(|prefix| !content.trim_start().starts_with(prefix));
+        let tokens: Vec<Token> = if uncommented {
+            vec![]
+        } else {
+            Scanner::new(content, &line_symbols, &id_len)
+                .comment_prefix_only(symbols.require_comment_prefix)
+                .collect()
+        };
+        let opens: Vec<(&Token, &str)> = tokens
+            .iter()
+            .filter_map(|t| Some((t, t.word_after(&symbols.fragment_open)?)))
+            .collect();
+        let find = |symbol: &str| tokens.iter().find(|t| t.word_after(symbol).is_some());
+        let is_symbol = |t: &&Token| matches!(t.kind, TokenKind::Symbol { .. });
+        if halted {
+            halted = find(&symbols.resume).is_none();
+            continue;
+        }
+        // A line whose first symbol is a close symbol closes a fragment, whatever follows.
+        let leading_close = tokens
+            .iter()
+            .find(is_symbol)
+            .filter(|t| t.word_after(&symbols.fragment_close).is_some());
 
-    for (col, c) in line.chars().enumerate() {
-        match &state {
-            ScannerState::SearchingForRefStart => {
-                if line[col..].starts_with(&symbols.metadata) {
-                    if col > start_col {
-                        pieces.push(line[start_col..col].to_owned());
+        if let Some(close) = leading_close {
+            match close_fragment(
+                &mut fragment_stack,
+                &mut fragments,
+                filename,
+                line,
+                close.col,
+                close
+                    .word_after(&symbols.fragment_close)
+                    .unwrap_or_default(),
+                options,
+            ) {
+                Ok(warning) => warnings.extend(warning),
+                Err(e) => errors.push(e),
+            }
+            // Symbols in the rest of the line are ignored, which is almost never what was meant.
+            for token in tokens.iter().filter(is_symbol).skip(1) {
+                warnings.push(Warning {
+                    filename: filename.to_owned(),
+                    line,
+                    col: token.col,
+                    message: format!(
+                        "'{}' follows a fragment close symbol on the same line, and is ignored",
+                        token.text
+                    ),
+                });
+            }
+        } else if let Some(&(first, first_id)) = opens.first() {
+            let col = first.col;
+            let id = extract_id_with_symbols(first_id, 0, symbols);
+            // In toggle mode the open symbol also closes the innermost fragment, when it is bare or
+            // repeats that fragment's ID.
+            if symbols.toggle {
+                let closes = match (&id, fragment_stack.last()) {
+                    (Err(IdExtractError::NoIdFound), _) => true,
+                    (Ok(id), Some(open)) => *id == open.fragment.id,
+                    _ => false,
+                };
+                if closes {
+                    match close_fragment(
+                        &mut fragment_stack,
+                        &mut fragments,
+                        filename,
+                        line,
+                        col,
+                        "",
+                        options,
+                    ) {
+                        Ok(warning) => warnings.extend(warning),
+                        Err(e) => errors.push(e),
                     }
-                    start_col = col;
-                    state = ScannerState::ReadingMetaStart;
+                    continue;
                 }
-            }
-            ScannerState::ReadingMetaStart => {
-                let chars_read = col - start_col;
-                if chars_read >= symbols.metadata.len() {
-                    state = ScannerState::ReadingId;
-                } else if c != symbols.metadata.chars().nth(chars_read).unwrap() {
-                    return Err(FileError {
-                        err_type: WeaveError::MetadataParseError,
+                // Closing an outer fragment would leave the inner ones dangling.
+                if let Some(open) = id
+                    .as_ref()
+                    .ok()
+                    .and_then(|id| fragment_stack.iter().find(|o| o.fragment.id == *id))
+                {
+                    errors.push(FileError {
+                        err_type: ParseError::MismatchedToggle,
                         filename: filename.to_owned(),
-                        line: line_no,
+                        line,
                         col,
                         message: Some(format!(
-                            "unexpected character '{}' while reading metadata symbol in line {}",
-                            c, line
+                            "toggle for fragment '{}' found while '{}' is still open",
+                            open.fragment.id,
+                            fragment_stack.last().map_or("", |o| o.fragment.id.as_str())
                         )),
                     });
+                    continue;
                 }
             }
-            ScannerState::ReadingId => {
-                if !c.is_safe_for_ids() {
-                    if c == METADATA_SEPARATOR {
-                        state = ScannerState::ReadingRefType;
-                    } else {
-                        return Err(FileError {
-                            err_type: WeaveError::MetadataParseError,
+            // Every fragment opened on this line shares the text before the first open symbol.
+            let prefix = content[..first.start].trim();
+            let opened_here = fragment_stack.len();
+            // A deprecation tag belongs to the nearest open symbol before it.
+            let deprecation = extract_deprecation(content);
+            let tagged = deprecation
+                .as_ref()
+                .and_then(|(start, _)| opens.iter().rposition(|(token, _)| token.start < *start));
+            for (i, &(token, id)) in opens.iter().enumerate() {
+                let col = token.col;
+                // A fragment which can't be opened properly gets an empty ID, which no real
+                // fragment has, and is dropped when it closes.
+                let id = match extract_id_with_symbols(id, 0, symbols) {
+                    Ok(id)
+                        if fragment_stack[opened_here..]
+                            .iter()
+                            .any(|o| o.fragment.id == id) =>
+                    {
+                        errors.push(FileError {
+                            err_type: ParseError::DoubleOpen,
                             filename: filename.to_owned(),
-                            line: line_no,
+                            line,
+                            col,
+                            message: Some(format!(
+                                "fragment '{}' opened twice on the same line",
+                                id
+                            )),
+                        });
+                        String::new()
+                    }
+                    Ok(id) => id,
+                    Err(IdExtractError::NoIdFound) => {
+                        errors.push(FileError {
+                            err_type: ParseError::MissingId,
+                            filename: filename.to_owned(),
+                            line,
+                            col,
+                            message: Some(format!(
+                                "no fragment identifier found in fragment open symbol: {}",
+                                line
+                            )),
+                        });
+                        String::new()
+                    }
+                    Err(IdExtractError::ReservedCharacterUsed(c)) => {
+                        errors.push(FileError {
+                            err_type: ParseError::IdExtractError,
+                            filename: filename.to_owned(),
+                            line,
                             col,
                             message: Some(format!(
-                                "expected '{}', got '{}' while reading metadata symbol in line {}",
-                                METADATA_SEPARATOR, c, line
+                                "error parsing fragment identifier in fragment open symbol: {}
+                                     (used reserved character {})",
+                                line, c
                             )),
                         });
-                    };
+                        String::new()
+                    }
+                };
+                // Push a new Fragment onto the stack.
+                let fragment = Fragment {
+                    body: String::new(),
+                    id,
+                    file: filename.to_owned(),
+                    // The Fragment starts on the line after the opening symbol.
+                    line: line + 1,
+                    col: 0,
+                    marker_line: line,
+                    // Set when the fragment is closed.
+                    end_line: 0,
+                    open_prefix: Some(prefix.to_owned()).filter(|p| !p.is_empty()),
+                    index: 0,
+                    deprecated: deprecation
+                        .as_ref()
+                        .filter(|_| tagged == Some(i))
+                        .map(|(_, message)| message.to_owned()),
+                    source_lines: vec![],
+                };
+                fragment_stack.push(OpenFragment {
+                    fragment,
+                    marker_col: col,
+                });
+                if options
+                    .max_nesting
+                    .is_some_and(|max| fragment_stack.len() > max)
+                {
+                    let open: Vec<&str> = fragment_stack
+                        .iter()
+                        .map(|o| o.fragment.id.as_str())
+                        .collect();
+                    errors.push(FileError {
+                        err_type: ParseError::NestingTooDeep,
+                        filename: filename.to_owned(),
+                        line,
+                        col,
+                        message: Some(format!(
+                            "fragments nested more than {} deep (open: {}); is a close symbol \
+                             missing?",
+                            fragment_stack.len() - 1,
+                            open.join(", ")
+                        )),
+                    });
                 }
             }
-            ScannerState::ReadingRefType => {
-                // TODO Clean up this code a little, to reduce duplication.
-                if !c.is_safe_for_refs() {
-                    state = ScannerState::SearchingForRefStart;
-                    let expansion = expand_metadata(
-                        &line[start_col..col],
-                        filename,
-                        line_no,
-                        start_col,
-                        annotations,
-                        symbols,
-                    )?;
-                    pieces.push(expansion);
-                    start_col = col;
-                } else if col == line.len() - 1 {
-                    state = ScannerState::SearchingForRefStart;
-                    let col = col + 1; // NOTE This differs from the fragment above.
-                    let expansion = expand_metadata(
-                        &line[start_col..col],
-                        filename,
-                        line_no,
-                        start_col,
-                        annotations,
-                        symbols,
-                    )?;
-                    pieces.push(expansion);
-                    start_col = col;
-                };
+            // A close symbol after the last open symbol makes that an inline fragment, as in
+            // `@<id text >@`, whose body is the text between the two.
+            let last_open = opens.last().map(|(token, _)| *token);
+            let inline_close = tokens
+                .iter()
+                .rfind(is_symbol)
+                .filter(|t| !symbols.toggle && t.word_after(&symbols.fragment_close).is_some());
+            if let (Some(open), Some(close)) = (last_open, inline_close) {
+                let close_id = close
+                    .word_after(&symbols.fragment_close)
+                    .unwrap_or_default();
+                let inline = fragment_stack.last_mut().map(|o| &mut o.fragment);
+                let inline = inline.expect("just opened a fragment");
+                if !close_id.is_empty() && !inline.id.is_empty() && inline.id != close_id {
+                    errors.push(FileError {
+                        err_type: ParseError::MismatchedClose(
+                            inline.id.to_owned(),
+                            close_id.to_owned(),
+                        ),
+                        filename: filename.to_owned(),
+                        line,
+                        col: close.col,
+                        message: Some(format!(
+                            "close symbol for fragment '{}' found while '{}' is the innermost open",
+                            close_id, inline.id
+                        )),
+                    });
+                }
+                // Unlike other fragments, the body starts partway through the line.
+                let text = &content[open.start + open.text.len()..close.start];
+                let leading = &text[..text.len() - text.trim_start().len()];
+                inline.line = line;
+                inline.col = open.col + open.text.chars().count() + leading.chars().count();
+                inline.body = text.trim().to_owned();
+                inline.body.push('\n');
+                if options.source_lines {
+                    inline.source_lines = vec![line];
+                }
+                // Rather than the inline fragment's text, the parent keeps the code on the line,
+                // without the comment which holds the marker. So the fragment is closed on its own.
+                let inline = fragment_stack.pop().expect("just opened a fragment");
+                if let Some(parent) = fragment_stack.last_mut().map(|o| &mut o.fragment) {
+                    let code = code_before_comment(
+                        &content[..first.start],
+                        options.comment_prefix.as_deref(),
+                    );
+                    if !code.is_empty() {
+                        parent.body.push_str(code);
+                        parent.body.push('\n');
+                        if options.source_lines {
+                            parent.source_lines.push(line);
+                        }
+                    }
+                }
+                // The fragment ends on this line, and `close_fragment` expects the line after it.
+                match close_fragment(
+                    &mut vec![inline],
+                    &mut fragments,
+                    filename,
+                    line + 1,
+                    close.col,
+                    "",
+                    options,
+                ) {
+                    Ok(warning) => warnings.extend(warning),
+                    Err(e) => errors.push(e),
+                }
+            }
+        } else if let Some(token) = find(&symbols.fragment_close).filter(|_| !symbols.toggle) {
+            match close_fragment(
+                &mut fragment_stack,
+                &mut fragments,
+                filename,
+                line,
+                token.col,
+                token
+                    .word_after(&symbols.fragment_close)
+                    .unwrap_or_default(),
+                options,
+            ) {
+                Ok(warning) => warnings.extend(warning),
+                Err(e) => errors.push(e),
+            }
+        } else if let Some(col) = find(&symbols.halt).map(|t| t.col) {
+            // If the Fragment stack is not empty, we have an error as there is at least 1 open
+            // Fragment.
+            if !fragment_stack.is_empty() {
+                errors.push(FileError {
+                    err_type: ParseError::HaltWhileOpen,
+                    filename: filename.to_owned(),
+                    line,
+                    col,
+                    message: Some(format!(
+                        "halt symbol found while a fragment was open: {}",
+                        line
+                    )),
+                });
+                // The open fragments have already been reported, so don't report them as unclosed.
+                fragment_stack.clear();
+            }
+            // Skip everything up to the next resume symbol, or the end of the file if there is none.
+            halted = true;
+        } else if let Some(token) = find(&symbols.resume) {
+            warnings.push(Warning {
+                filename: filename.to_owned(),
+                line,
+                col: token.col,
+                message: String::from("resume symbol found without a halt before it, and ignored"),
+            });
+        } else if let Some(token) = find(&symbols.skip) {
+            // A replacement for the skipped lines, as in `@!skip "..."`, goes in once, indented like
+            // the skip symbol's line. Skips within skips have nothing to replace.
+            let rest = &content[token.start + symbols.skip.len()..];
+            let replacement = rest
+                .trim_start()
+                .strip_prefix('"')
+                .and_then(|quoted| quoted.split_once('"'))
+                .map(|(replacement, _)| replacement);
+            let innermost = fragment_stack.last_mut().map(|o| &mut o.fragment);
+            if let (Some(replacement), Some(fragment), true) =
+                (replacement, innermost, skips.is_empty())
+            {
+                let indent = &content[..content.len() - content.trim_start().len()];
+                fragment.body.push_str(indent);
+                fragment.body.push_str(replacement);
+                fragment.body.push('\n');
+                if options.source_lines {
+                    fragment.source_lines.push(line);
+                }
+            }
+            skips.push((line, token.col));
+        } else if let Some(token) = find(&symbols.end_skip) {
+            if skips.pop().is_none() {
+                warnings.push(Warning {
+                    filename: filename.to_owned(),
+                    line,
+                    col: token.col,
+                    message: String::from("end skip symbol found without a skip, and ignored"),
+                });
+            }
+        } else if !skips.is_empty() {
+            // Skipped lines belong to no fragment, but symbols within them still count, so that
+            // fragments can open and close inside a skipped region.
+        } else if let Some(fragment) = fragment_stack.last_mut().map(|o| &mut o.fragment) {
+            // Removing a nested fragment's marker lines can leave two blank lines side by side.
+            let doubled_blank = follows_child
+                && content.trim().is_empty()
+                && fragment
+                    .body
+                    .lines()
+                    .last()
+                    .is_some_and(|l| l.trim().is_empty());
+            if !(options.collapse_nested_blanks && doubled_blank) {
+                fragment.body.push_str(content);
+                fragment.body.push('\n');
+                if options.source_lines {
+                    fragment.source_lines.push(line);
+                }
             }
         }
     }
 
-    // Pick up any remaining unparsed data.
-    if state != ScannerState::ReadingRefType {
-        pieces.push(line[start_col..].to_owned());
+    for (line, col) in skips {
+        warnings.push(Warning {
+            filename: filename.to_owned(),
+            line,
+            col,
+            message: String::from("skip symbol is never ended, so the rest of the file is skipped"),
+        });
+    }
+    if !fragment_stack.is_empty() {
+        errors.push(FileError {
+            err_type: ParseError::UnclosedFragment,
+            filename: filename.to_owned(),
+            line: line_count,
+            col: 0,
+            message: Some("not all fragments were closed".to_string()),
+        });
     }
 
-    Ok(pieces.join(""))
+    if errors.is_empty() {
+        Ok((fragments, warnings))
+    } else {
+        Err(errors)
+    }
 }
 
-fn expand_metadata(
-    word: &str,
+// The length of the fragment ID at the start of `s`, which runs up to the next whitespace. It is
+// validated separately, by `extract_id`.
+fn id_len(s: &str) -> usize {
+    s.find(char::is_whitespace).unwrap_or(s.len())
+}
+
+// Find a deprecation tag on a line, returning where it starts and its message. The message may be
+// quoted, as in `@deprecated="use x instead"`, or run up to the next whitespace.
+fn extract_deprecation(content: &str) -> Option<(usize, String)> {
+    let start = content.find(DEPRECATED_TAG)?;
+    let value = &content[start + DEPRECATED_TAG.len()..];
+    let message = match value.strip_prefix('"') {
+        Some(quoted) => quoted.split('"').next().unwrap_or_default(),
+        None => &value[..id_len(value)],
+    };
+    Some((start, message.to_owned()))
+}
+
+// A fragment which has been opened but not yet closed.
+struct OpenFragment {
+    fragment: Fragment,
+    // The column of the open symbol, which the fragment doesn't record.
+    marker_col: usize,
+}
+
+// Close the innermost open fragment, moving it into `fragments`. A close symbol may name the
+// fragment it closes, as in `>@id`, in which case it must be the innermost one. Returns a warning
+// if the fragment is empty and `options` ask for one.
+fn close_fragment(
+    fragment_stack: &mut Vec<OpenFragment>,
+    fragments: &mut Vec<Fragment>,
     filename: &str,
     line: usize,
     col: usize,
-    annotations: &BTreeMap<String, Fragment>,
-    symbols: &SymbolKey,
-) -> Result<String, FileError<WeaveError>> {
-    let word = word.trim_start_matches(&symbols.metadata);
-    let col = col + symbols.metadata.len(); // Offset column to account for the symbol we removed.
-    let pieces: Vec<&str> = word.split(METADATA_SEPARATOR).collect();
-    if pieces.len() == 2 {
-        let frag_id = pieces[0];
-        let prop = pieces[1];
-        let frag = annotations.get(frag_id);
-        match frag {
-            Some(f) => match prop.to_ascii_lowercase().as_str() {
-                FILENAME_REF => Ok(f.file.to_owned()),
-                LINE_NO_REF => Ok(f.line.to_string()),
-                COL_NO_REF => Ok(f.col.to_string()),
-                LOC_REF => Ok(format!("{} ({}:{})", f.file, f.line, f.col)),
-                ABS_PATH_REF => Ok(format!("/{}", f.file)),
-                REL_PATH_REF => {
-                    let from_path = std::path::Path::new(filename);
-                    let to_path = std::path::Path::new(&f.file);
-                    let rel_path = find_relative_path(from_path, to_path);
-                    Ok(rel_path.to_string_lossy().to_string())
-                }
-                _ => Err(FileError {
-                    err_type: WeaveError::UnknownProperty(prop.to_owned()),
-                    filename: filename.to_owned(),
-                    line,
-                    col: col + frag_id.len() + 1,
-                    message: Some(format!("unknown metadata type '{}'", prop)),
-                }),
-            },
-            None => Err(FileError {
-                err_type: WeaveError::MissingFragment(frag_id.to_owned()),
+    close_id: &str,
+    options: &ExtractOptions,
+) -> Result<Option<Warning>, FileError<ParseError>> {
+    if let Some(open) = fragment_stack.last().map(|o| &o.fragment) {
+        // Fragments which couldn't be opened properly have no ID to check against.
+        if !close_id.is_empty() && !open.id.is_empty() && open.id != close_id {
+            return Err(FileError {
+                err_type: ParseError::MismatchedClose(open.id.to_owned(), close_id.to_owned()),
                 filename: filename.to_owned(),
                 line,
                 col,
-                message: Some(format!("unknown fragment '{}'", frag_id)),
-            }),
+                message: Some(format!(
+                    "close symbol for fragment '{}' found while '{}' is the innermost open",
+                    close_id, open.id
+                )),
+            });
         }
-    } else {
-        // TODO Make these errors more granular.
-        Err(FileError {
-            err_type: WeaveError::BadMetadata(word.to_owned()),
-            filename: filename.to_owned(),
-            line,
-            col,
-            message: Some(format!("malformed property lookup '{}'", word)),
-        })
     }
-}
+    let OpenFragment {
+        fragment: closed_fragment,
+        marker_col,
+    } = fragment_stack.pop().ok_or_else(|| FileError {
+        err_type: ParseError::CloseBeforeOpen,
+        filename: filename.to_owned(),
+        line,
+        col,
+        message: Some("fragment close symbol found without an open symbol".to_string()),
+    })?;
 
-fn find_relative_path(a: &std::path::Path, b: &std::path::Path) -> std::path::PathBuf {
-    let apcs = a.components();
-    let mut bpcs = b.components();
-    let mut ups = std::path::PathBuf::new();
-    let mut downs = std::path::PathBuf::new();
-
-    // Cut off shared prefix.
-    let mut prefix_read = false;
-    for apc in apcs {
-        if !prefix_read {
-            match bpcs.next() {
-                Some(_x) if _x.eq(&apc) => {
-                    // Path components match; ignore them.
-                    continue;
-                }
-                Some(x) => {
-                    // Mismatch! End of shared prefix.
-                    prefix_read = true;
-                    downs.push(x);
-                }
-                None => {
-                    // Ran out of path components, all of which were shared.
-                    downs.push(".");
-                }
-            }
-        } else {
-            // Add enough "parent dir" tokens to get to shared directory path from A
-            ups.push("..");
+    let mut trimmed_body = options.trim.apply(&closed_fragment.body);
+    if let Some(parent_fragment) = fragment_stack.last_mut().map(|o| &mut o.fragment) {
+        // The parent receives the child's text regardless of how the child's own body is trimmed.
+        let child_text = closed_fragment.body.trim_end_matches('\n');
+        // Special handling of "empty" fragments.
+        if !child_text.is_empty() {
+            // Add the child fragments body to the parent fragment.
+            parent_fragment.body.push_str(child_text);
+            parent_fragment.body.push('\n');
+            let child_lines = closed_fragment.source_lines.iter();
+            parent_fragment
+                .source_lines
+                .extend(child_lines.take(child_text.lines().count()));
+        }
+        if options.dedent_nested {
+            trimmed_body = dedent_to_first_line(&trimmed_body);
         }
     }
-
-    // Add all of the remaining path components in B's path
-    for bpc in bpcs {
-        downs.push(bpc);
+    if options.dedent {
+        trimmed_body = dedent_body(&trimmed_body);
     }
+    // Trimming may have removed lines from either end of the body, and their sources with them.
+    let leading_blanks = match options.trim {
+        TrimPolicy::Full => closed_fragment
+            .body
+            .lines()
+            .take_while(|l| l.trim().is_empty())
+            .count(),
+        _ => 0,
+    };
+    let source_lines = closed_fragment
+        .source_lines
+        .iter()
+        .skip(leading_blanks)
+        .take(trimmed_body.lines().count())
+        .copied()
+        .collect();
+    // Add the closed fragment to the results list
+    let closed_fragment = Fragment {
+        body: trimmed_body,
+        source_lines,
+        end_line: line - 1,
+        ..closed_fragment
+    };
+    // Fragments which couldn't be opened properly have already been reported.
+    if closed_fragment.id.is_empty() {
+        return Ok(None);
+    }
+    // The open symbol locates the fragment, since an empty one has no first line to point to.
+    let warning = Some(Warning {
+        filename: filename.to_owned(),
+        line: closed_fragment.open_line(),
+        col: marker_col,
+        message: format!("fragment '{}' is empty", closed_fragment.id),
+    })
+    .filter(|_| options.warn_empty && closed_fragment.body.trim().is_empty());
+    // Reopening an ID continues the fragment, so that disconnected regions can be woven as one.
+    match fragments.iter_mut().find(|f| f.id == closed_fragment.id) {
+        Some(earlier) => earlier.append(&closed_fragment),
+        None => fragments.push(closed_fragment),
+    }
+    Ok(warning)
+}
 
-    ups.join(downs)
+// The code in `before`, the text which precedes a marker on its line, without the comment token that
+// introduces the marker. Without a known token, a trailing word made only of punctuation is taken
+// to be one, as with the `//` in `let x = 5; //`.
+fn code_before_comment<'a>(before: &'a str, comment_prefix: Option<&str>) -> &'a str {
+    let before = before.trim_end();
+    let code = match comment_prefix.and_then(|prefix| before.rfind(prefix)) {
+        Some(start) => &before[..start],
+        None => {
+            let word_start = before.rfind(char::is_whitespace).map_or(0, |i| i + 1);
+            let word = &before[word_start..];
+            if word
+                .chars()
+                .any(|c| c.is_alphanumeric() || QUOTE_CHARS.contains(&c))
+            {
+                before
+            } else {
+                &before[..word_start]
+            }
+        }
+    };
+    code.trim_end()
 }
 
-// @<tests
-#[cfg(test)]
-mod tests {
-    use super::*;
+// Remove the first line's indentation from every line of `body`. Lines which are indented less than
+// the first line lose only the whitespace they share with it.
+fn dedent_to_first_line(body: &str) -> String {
+    let first_line = body.lines().next().unwrap_or_default();
+    let indent = &first_line[..first_line.len() - first_line.trim_start().len()];
 
-    #[test]
-    fn test_extract_id_missing() {
-        let id = extract_id("", 0);
-        assert_eq!(
-            id,
-            Err(IdExtractError::NoIdFound),
-            "Expected NoIdFound, got {:?}",
-            id
-        );
-    }
-    // ... snip ...
-    // >@tests
+    body.split('\n')
+        .map(|line| {
+            let shared = line
+                .char_indices()
+                .zip(indent.chars())
+                .take_while(|((_, a), b)| a == b)
+                .last()
+                .map_or(0, |((i, c), _)| i + c.len_utf8());
+            &line[shared..]
+        })
+        .collect::<Vec<&str>>()
+        .join("\n")
+}
 
-    // @!halt
+// Remove the indentation which every non-blank line of a fragment's body shares.
+pub fn dedent(fragment: &mut Fragment) {
+    fragment.body = dedent_body(&fragment.body);
+}
 
-    #[test]
-    fn test_extract_id_good() {
-        let id = extract_id("foobarbaz", 0);
-        assert_eq!(
-            id.expect("Expected successful ID extraction"),
-            String::from("foobarbaz")
-        );
-    }
+// Tabs and spaces are different characters here, so lines indented with a mixture of them only lose
+// the whitespace they have in common, character for character. Blank lines are left as they are.
+fn dedent_body(body: &str) -> String {
+    let common = body
+        .split('\n')
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| &line[..line.len() - line.trim_start().len()])
+        .reduce(|common, indent| {
+            let shared = common
+                .char_indices()
+                .zip(indent.chars())
+                .take_while(|((_, a), b)| a == b)
+                .last()
+                .map_or(0, |((i, c), _)| i + c.len_utf8());
+            &common[..shared]
+        })
+        .unwrap_or_default();
 
-    #[test]
-    fn test_extract_id_whitespace() {
-        let id = extract_id("foo bar baz quuz", 0);
-        assert_eq!(
-            id.expect("Expected successful ID extraction"),
-            String::from("foo")
-        );
-    }
+    body.split('\n')
+        .map(|line| line.strip_prefix(common).unwrap_or(line))
+        .collect::<Vec<&str>>()
+        .join("\n")
+}
 
-    #[test]
-    fn test_extract_id_acceptable_separators() {
-        let id_elements = ["foo", "bar", "baz", "quuz"];
-        for sep in ID_SAFE_CHARS {
-            let input = &id_elements.join(&sep.to_string());
-            let id = extract_id(input, 0);
-            assert_eq!(&id.expect("Expected successful ID extraction"), input);
+// Whether a source file halts before any of its content and never resumes, and so was excluded from
+// extraction deliberately. Symbols are found as `extract_fragments` finds them, so a halt symbol in
+// a string or, when a comment token is required, outside a comment doesn't count. Reading stops at
+// the first line of content before a halt.
+pub fn halts_before_content<S: AsRef<str>>(
+    lines: impl Iterator<Item = S>,
+    symbols: &SymbolKey,
+    options: &ExtractOptions,
+) -> bool {
+    let mut halted = false;
+    for line in lines {
+        let content = line.as_ref();
+        let uncommented = options
+            .comment_prefix
+            .as_deref()
+            .is_some_and(|prefix| !content.trim_start().starts_with(prefix));
+        let found = |symbol: &str| {
+            !uncommented
+                && Scanner::new(content, &[&symbols.halt, &symbols.resume], &id_len)
+                    .comment_prefix_only(symbols.require_comment_prefix)
+                    .any(|t| t.word_after(symbol).is_some())
+        };
+        if halted && found(&symbols.resume) {
+            return false;
+        } else if !halted && found(&symbols.halt) {
+            halted = true;
+        } else if !halted && !content.trim().is_empty() {
+            return false;
         }
     }
+    halted
+}
 
-    #[test]
-    fn test_extract_id_reserved_chars() {
-        let id_elements = ["foo", "bar", "baz", "quuz"];
-        let id_reserved_chars = &[METADATA_SEPARATOR];
-        for sep in id_reserved_chars {
-            let input = &id_elements.join(&sep.to_string());
+// Extract fragments defined in a prose file, so that it can use them itself. Returns the fragments
+// and the prose with every line holding an open or close symbol removed, ready to be woven.
+pub fn extract_inline_fragments(
+    contents: &str,
+    filename: &str,
+    symbols: &SymbolKey,
+) -> Result<(Vec<Fragment>, String), FileError<ParseError>> {
+    let fragments = extract_fragments(contents, filename, symbols)?;
+
+    let markers = [
+        symbols.fragment_open.as_str(),
+        symbols.fragment_close.as_str(),
+    ];
+    let prose: Vec<&str> = contents
+        .split('\n')
+        .filter(|line| {
+            !Scanner::new(line, &markers, &id_len)
+                .comment_prefix_only(symbols.require_comment_prefix)
+                .any(|t| matches!(t.kind, TokenKind::Symbol { .. }))
+        })
+        .collect();
+
+    Ok((fragments, prose.join("\n")))
+}
+
+// Split a stream of concatenated files into `(path, contents)` pairs. Each file is introduced by a
+// line matching `header`, whose first capture group is the file's path. Anything before the first
+// header is discarded.
+pub fn split_archive<'a>(contents: &'a str, header: &Regex) -> Vec<(&'a str, &'a str)> {
+    let mut files: Vec<(&str, &str)> = vec![];
+    let mut current: Option<(&str, usize)> = None;
+    let mut offset = 0;
+
+    for line in contents.split_inclusive('\n') {
+        let text = line.trim_end_matches(&['\r', '\n'][..]);
+        if let Some(path) = header.captures(text).and_then(|c| c.get(1)) {
+            if let Some((prev_path, start)) = current {
+                files.push((prev_path, &contents[start..offset]));
+            }
+            current = Some((path.as_str(), offset + line.len()));
+        }
+        offset += line.len();
+    }
+    if let Some((path, start)) = current {
+        files.push((path, &contents[start..]));
+    }
+
+    files
+}
+
+// Reconstruct the new version of each file changed by a unified diff, as `(path, contents)` pairs.
+// Only the lines in the diff's hunks are known; the rest are left blank, so that every line keeps its
+// line number. Removed lines and deleted files are left out.
+pub fn split_patch(patch: &str) -> Vec<(String, String)> {
+    static HUNK_HEADER: OnceLock<Regex> = OnceLock::new();
+    let hunk_header = HUNK_HEADER
+        .get_or_init(|| Regex::new(r"^@@ -\d+(?:,(\d+))? \+(\d+)(?:,(\d+))? @@").unwrap());
+    let count =
+        |m: Option<regex::Match>| -> usize { m.map_or(1, |m| m.as_str().parse().unwrap_or(0)) };
+    let mut files: Vec<(String, Vec<&str>)> = vec![];
+    // Whether the hunks that follow belong to the last file in `files`.
+    let mut in_file = false;
+
+    let mut lines = patch.lines();
+    while let Some(line) = lines.next() {
+        if let Some(path) = line.strip_prefix("+++ ") {
+            // Paths may be followed by a timestamp, and usually have git's `b/` prefix.
+            let path = path.split('\t').next().unwrap_or_default().trim_end();
+            in_file = path != "/dev/null";
+            if in_file {
+                let path = path.strip_prefix("b/").unwrap_or(path);
+                files.push((path.to_owned(), vec![]));
+            }
+        } else if let Some(captures) = hunk_header.captures(line) {
+            let mut old_remaining = count(captures.get(1));
+            let start: usize = captures[2].parse().unwrap_or(1);
+            let mut new_remaining = count(captures.get(3));
+            let mut new_lines = files.last_mut().filter(|_| in_file).map(|(_, l)| l);
+            if let Some(new_lines) = &mut new_lines {
+                new_lines.resize(new_lines.len().max(start.saturating_sub(1)), "");
+            }
+            while old_remaining > 0 || new_remaining > 0 {
+                let line = match lines.next() {
+                    Some(line) => line,
+                    None => break,
+                };
+                // Some tools strip the space from blank context lines.
+                let (marker, text) = line.split_at(line.len().min(1));
+                match marker {
+                    "+" => new_remaining = new_remaining.saturating_sub(1),
+                    "-" => {
+                        old_remaining = old_remaining.saturating_sub(1);
+                        continue;
+                    }
+                    "\\" => continue,
+                    _ => {
+                        old_remaining = old_remaining.saturating_sub(1);
+                        new_remaining = new_remaining.saturating_sub(1);
+                    }
+                }
+                if let Some(new_lines) = &mut new_lines {
+                    new_lines.push(text);
+                }
+            }
+        }
+    }
+
+    files
+        .into_iter()
+        .map(|(path, lines)| (path, lines.join("\n")))
+        .collect()
+}
+
+// @<extractid
+// Extract the fragment ID starting at column `col` of `content`, such as just after the symbol
+// before it. The ID runs up to the next whitespace, and may not contain reserved characters.
+pub fn extract_id(content: &str, col: usize) -> Result<String, IdExtractError> {
+    extract_id_with_symbols(content, col, &SymbolKey::default())
+}
+
+// As `extract_id`, for IDs which may contain the characters that `symbols` allow rather than the
+// default ones.
+pub fn extract_id_with_symbols(
+    content: &str,
+    col: usize,
+    symbols: &SymbolKey,
+) -> Result<String, IdExtractError> {
+    let it = content[byte_offset(content, col)..].chars();
+    let id: String = it.take_while(|c| !c.is_whitespace()).collect();
+    if id.is_empty() {
+        Err(IdExtractError::NoIdFound)
+    } else if let Some(c) = id.chars().find(|&c| !symbols.is_id_char(c)) {
+        Err(IdExtractError::ReservedCharacterUsed(c))
+    } else {
+        Ok(id)
+    }
+}
+// >@extractid
+
+// Splits the condition off of an insertion like `@@?flag:id`. Returns whether the condition is
+// negated, the flag, and the insertion without its condition, or `None` if it has no condition.
+fn split_condition<'a>(
+    insertion: &'a str,
+    symbol: &str,
+    symbols: &SymbolKey,
+) -> Option<(bool, &'a str, String)> {
+    let rest = &insertion[symbol.len()..];
+    let negated = match rest.chars().next()? {
+        CONDITION_DEFINED => false,
+        CONDITION_UNDEFINED => true,
+        _ => return None,
+    };
+    let (flag, rest) = rest[1..].split_once(CONDITION_SEPARATOR)?;
+    if flag.is_empty() || !flag.chars().all(|c| symbols.is_id_char(c)) {
+        return None;
+    }
+    Some((negated, flag, format!("{}{}", symbol, rest)))
+}
+
+// Compile the ID pattern starting at column `col` of `content`, as in an `@*` line. Whitespace around
+// it is ignored, so a pattern must use `^` or `$` to match it.
+pub fn extract_pattern(content: &str, col: usize) -> Result<Regex, PatternExtractError> {
+    // Remove leading characters to get just the pattern
+    let pat = &content[byte_offset(content, col)..];
+    // Remove leading and trailing whitespace; patterns should use ^/$ to include it
+    let pat = pat.trim_start().trim_end();
+    if pat.is_empty() {
+        Err(PatternExtractError::NoPatternFound)
+    } else {
+        Regex::new(pat).map_err(PatternExtractError::RegexConstruction)
+    }
+}
+
+// The order in which `@*` inserts the fragments matching its pattern.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+enum PatternOrder {
+    // Lexicographic by ID.
+    #[default]
+    Id,
+    // By file, then line and column.
+    Location,
+}
+
+// Settings which follow the pattern in an `@*` line.
+#[derive(Debug, Default, PartialEq, Eq, Clone)]
+struct PatternOptions {
+    order: PatternOrder,
+    // A line to put between consecutive fragments, but not after the last.
+    separator: Option<String>,
+}
+
+// Splits the options, such as `sort=loc` and `sep="---"`, off the end of a pattern line. They may
+// come in either order. Returns a message if an option is malformed.
+fn split_pattern_options(line: &str) -> Result<(&str, PatternOptions), String> {
+    let mut rest = line.trim_end();
+    let mut options = PatternOptions::default();
+    loop {
+        if let Some(start) = rest
+            .strip_suffix('"')
+            .and_then(|r| r.rfind(SEPARATOR_OPTION))
+        {
+            let value = &rest[start + SEPARATOR_OPTION.len()..rest.len() - 1];
+            options.separator = Some(unescape(value));
+            rest = rest[..start].trim_end();
+            continue;
+        }
+        let (before, word) = match rest.rsplit_once(char::is_whitespace) {
+            Some(split) => split,
+            None => return Ok((rest, options)),
+        };
+        options.order = match word.strip_prefix(SORT_OPTION) {
+            None => return Ok((rest, options)),
+            Some(SORT_BY_ID) => PatternOrder::Id,
+            Some(SORT_BY_LOC) => PatternOrder::Location,
+            Some(key) => {
+                return Err(format!(
+                    "unknown sort key '{}'; expected '{}' or '{}'",
+                    key, SORT_BY_ID, SORT_BY_LOC
+                ))
+            }
+        };
+        rest = before.trim_end();
+    }
+}
+
+// Splits the default text off an insertion, as in `@@id ?? "TODO: write this"`. Quoted text may use
+// the same escapes as `sep`; otherwise the rest of the line is used as it is.
+fn split_default(line: &str) -> (&str, Option<String>) {
+    match line.split_once(DEFAULT_SEPARATOR) {
+        Some((insertion, default)) => {
+            let default = default.trim();
+            let default = match default.strip_prefix('"').and_then(|d| d.strip_suffix('"')) {
+                Some(quoted) => unescape(quoted),
+                None => default.to_owned(),
+            };
+            (insertion, Some(default))
+        }
+        None => (line, None),
+    }
+}
+
+// `value` with the escapes `\n`, `\t`, `\"` and `\\` replaced by the characters they stand for.
+// Other backslashes are kept as they are.
+fn unescape(value: &str) -> String {
+    let mut unescaped = String::new();
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => unescaped.push('\n'),
+            Some('t') => unescaped.push('\t'),
+            Some(c @ ('"' | '\\')) => unescaped.push(c),
+            Some(c) => {
+                unescaped.push('\\');
+                unescaped.push(c);
+            }
+            None => unescaped.push('\\'),
+        }
+    }
+    unescaped
+}
+
+// The lines of a fragment to insert, counting from 1 and including both ends.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+struct LineRange {
+    start: usize,
+    end: usize,
+}
+
+impl LineRange {
+    // `f` with only the lines in this range, if it has all of them.
+    fn slice(&self, f: &Fragment) -> Option<Fragment> {
+        let lines: Vec<&str> = f.body.split('\n').collect();
+        if self.start == 0 || self.start > self.end || self.end > lines.len() {
+            return None;
+        }
+        Some(Fragment {
+            body: lines[self.start - 1..self.end].join("\n"),
+            line: f.line + self.start - 1,
+            end_line: f.line + self.end - 1,
+            source_lines: f
+                .source_lines
+                .get(self.start - 1..self.end)
+                .map_or(vec![], <[usize]>::to_vec),
+            ..f.clone()
+        })
+    }
+}
+
+impl fmt::Display for LineRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.start == self.end {
+            write!(f, "{}", self.start)
+        } else {
+            write!(f, "{}-{}", self.start, self.end)
+        }
+    }
+}
+
+// The byte offset of the character at column `col` of `content`, or its length if it is shorter.
+// Columns count characters rather than bytes, so that they match what an editor shows.
+fn byte_offset(content: &str, col: usize) -> usize {
+    content
+        .char_indices()
+        .nth(col)
+        .map_or(content.len(), |(i, _)| i)
+}
+
+// Split a line range off of the directive token starting at `col`, returning the directive without
+// it. A suffix which isn't a range is left in place, so that the ID is reported as malformed.
+fn extract_line_range(content: &str, col: usize) -> (&str, Option<LineRange>) {
+    let col = byte_offset(content, col);
+    let token_end = content[col..]
+        .find(char::is_whitespace)
+        .map_or(content.len(), |i| col + i);
+
+    if let Some(i) = content[col..token_end].rfind(LINE_RANGE_SEPARATOR) {
+        let range = &content[col + i + 1..token_end];
+        let (start, end) = range.split_once('-').unwrap_or((range, range));
+        if let (Ok(start), Ok(end)) = (start.parse(), end.parse()) {
+            return (&content[..col + i], Some(LineRange { start, end }));
+        }
+    }
+    (content, None)
+}
+
+// Options that change how a fragment is rendered when it is inserted, written after its ID like so:
+// `id!wrap=80`.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum RenderOption {
+    Wrap(usize),
+    // The comment token to use, if not the default.
+    Source(Option<String>),
+    ExpandTabs(TabExpansion),
+    WithHeader,
+    LineNumbers,
+}
+
+// How to replace tabs with spaces in an inserted fragment.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct TabExpansion {
+    // The distance between tab stops. Must be at least 1.
+    pub width: usize,
+    // Expand tabs anywhere in a line, rather than only in its leading whitespace.
+    pub all: bool,
+}
+
+// Split any render options off of the directive token starting at `col`, returning the directive
+// without them and the parsed options. On failure, returns the text of the offending option.
+fn extract_render_options(content: &str, col: usize) -> Result<(&str, Vec<RenderOption>), String> {
+    let col = byte_offset(content, col);
+    let token_end = content[col..]
+        .find(char::is_whitespace)
+        .map_or(content.len(), |i| col + i);
+
+    match content[col..token_end].find(RENDER_OPTION_SEPARATOR) {
+        None => Ok((content, vec![])),
+        Some(i) => {
+            let split = col + i;
+            let options = content[split + 1..token_end]
+                .split(RENDER_OPTION_SEPARATOR)
+                .map(parse_render_option)
+                .collect::<Result<Vec<RenderOption>, String>>()?;
+            Ok((&content[..split], options))
+        }
+    }
+}
+
+fn parse_render_option(option: &str) -> Result<RenderOption, String> {
+    match option.split_once('=') {
+        Some((WRAP_OPTION, width)) => match width.parse() {
+            Ok(width) if width > 0 => Ok(RenderOption::Wrap(width)),
+            _ => Err(option.to_owned()),
+        },
+        Some((SOURCE_OPTION, comment)) if !comment.is_empty() => {
+            Ok(RenderOption::Source(Some(comment.to_owned())))
+        }
+        None if option == SOURCE_OPTION => Ok(RenderOption::Source(None)),
+        None if option == WITH_HEADER_OPTION => Ok(RenderOption::WithHeader),
+        None if option == LINE_NUMBERS_OPTION => Ok(RenderOption::LineNumbers),
+        Some((name @ (TABS_OPTION | ALL_TABS_OPTION), width)) => match width.parse() {
+            Ok(width) if width > 0 => Ok(RenderOption::ExpandTabs(TabExpansion {
+                width,
+                all: name == ALL_TABS_OPTION,
+            })),
+            _ => Err(option.to_owned()),
+        },
+        _ => Err(option.to_owned()),
+    }
+}
+
+// The text which inserts `f`, and the line of `f`'s body that each of its lines shows, counting
+// from 0. Lines which rendering added, such as a fence, or rewrote, such as wrapped prose, show none.
+fn render_insertion(
+    f: &Fragment,
+    mode: InsertionMode,
+    annotations: &Annotations,
+    weave_options: &WeaveOptions,
+    options: &[RenderOption],
+) -> (String, Vec<Option<usize>>) {
+    let body = &f.body;
+    // Tabs are expanded after trimming, so that trimming sees the original whitespace, and before
+    // any other render options, so that wrapping measures the final width of each line. A `tabs`
+    // render option overrides the default from `WeaveOptions`.
+    let tabs = options
+        .iter()
+        .find_map(|o| match o {
+            RenderOption::ExpandTabs(tabs) => Some(*tabs),
+            _ => None,
+        })
+        .or(weave_options.expand_tabs);
+    let trimmed = trim_inserted(body, weave_options);
+    let expanded = match tabs {
+        Some(tabs) => expand_tabs(&trimmed, tabs),
+        None => trimmed,
+    };
+    // Trimming may have removed blank lines from the start of the body, which still count.
+    let skipped = skipped_lines(body, weave_options);
+    let shown = (skipped..)
+        .take(expanded.split('\n').count())
+        .map(Some)
+        .collect();
+    let (mut rendered, mut shown) = render_body(&expanded, shown, options);
+    if weave_options.line_numbers || options.contains(&RenderOption::LineNumbers) {
+        rendered = number_lines(&rendered, f, skipped);
+    }
+    if options.contains(&RenderOption::WithHeader) {
+        if let Some(header) = context_header(f, annotations) {
+            rendered = format!("{}\n{}", header, rendered);
+            shown.insert(0, None);
+        }
+    }
+    // The source comment goes inside any code fence, where the comment token makes sense.
+    if let Some(comment) = options.iter().find_map(|o| match o {
+        RenderOption::Source(comment) => Some(comment),
+        _ => None,
+    }) {
+        let source = source_comment(f, comment.as_deref(), weave_options);
+        rendered = format!("{}\n{}", source, rendered);
+        shown.insert(0, None);
+    }
+    match mode {
+        InsertionMode::Plain if !weave_options.fence_all => (rendered, shown),
+        InsertionMode::Plain | InsertionMode::Fenced => {
+            let info = woven_fence_language(&f.file, weave_options).unwrap_or_default();
+            shown.insert(0, None);
+            shown.push(None);
+            (code_block(&rendered, CODE_FENCE_CHAR, info), shown)
+        }
+        InsertionMode::Verbatim => {
+            let shown = (0..body.split('\n').count()).map(Some).collect();
+            (body.to_owned(), shown)
+        }
+    }
+}
+
+// How many blank lines trimming removes from the start of `body` before it is inserted.
+fn skipped_lines(body: &str, options: &WeaveOptions) -> usize {
+    match options.trim {
+        Some(TrimPolicy::Full) => body.lines().take_while(|l| l.trim().is_empty()).count(),
+        _ => 0,
+    }
+}
+
+// The file and line that each of the lines of `f`'s body in `shown` came from.
+fn fragment_sources(f: &Fragment, shown: &[Option<usize>]) -> Sources {
+    shown
+        .iter()
+        .map(|n| Some((f.file.to_owned(), source_line(f, (*n)?))))
+        .collect()
+}
+
+// The line of `f`'s source file holding line `n` of its body, counting from 0.
+fn source_line(f: &Fragment, n: usize) -> usize {
+    f.source_lines.get(n).copied().unwrap_or(f.line + n)
+}
+
+// A fragment's body, trimmed as `options` says before it is inserted.
+fn trim_inserted(body: &str, options: &WeaveOptions) -> String {
+    match options.trim {
+        Some(trim) => trim.apply(body),
+        None => body.to_owned(),
+    }
+}
+
+// The language for a woven code fence, from the languages given in `options` or else the built-in
+// table.
+fn woven_fence_language<'a>(filename: &str, options: &'a WeaveOptions) -> Option<&'a str> {
+    let extension = Path::new(filename).extension()?.to_str()?;
+    match options.fence_languages.get(&extension.to_lowercase()) {
+        Some(language) => Some(language.as_str()),
+        None => fence_language(filename),
+    }
+}
+
+// A comment like `// from src/main.rs:12`. The style is a comment token, optionally followed by a
+// space and a closing token, as in `<!-- -->`.
+fn source_comment(f: &Fragment, comment: Option<&str>, options: &WeaveOptions) -> String {
+    let style = comment
+        .or(options.source_comment_style.as_deref())
+        .unwrap_or(SOURCE_COMMENT_STYLE);
+    let (open, close) = style.split_once(' ').unwrap_or((style, ""));
+    let source = format!("{} from {}:{}", open, f.file, f.line);
+    match close.trim() {
+        "" => source,
+        close => format!("{} {}", source, close),
+    }
+}
+
+// Guess the line which introduces `f`, such as the signature of the function it is in: the nearest
+// line before it, in the innermost fragment from the same file which encloses it, that is less
+// indented than its first line. Fragments which aren't nested in another have no header, since
+// nothing else records the text around them.
+fn context_header(f: &Fragment, annotations: &Annotations) -> Option<String> {
+    let indentation = |line: &str| line.len() - line.trim_start().len();
+    let (blanks, first) = f
+        .body
+        .lines()
+        .enumerate()
+        .find(|(_, line)| !line.trim().is_empty())?;
+
+    annotations
+        .values()
+        .flatten()
+        .filter(|parent| parent.file == f.file && parent.line < f.line)
+        .filter_map(|parent| {
+            let lines: Vec<&str> = parent.body.lines().collect();
+            // The marker lines of nested fragments aren't part of the parent's body, so the
+            // fragment's first line is at or before the line it had in the source.
+            let estimate = (f.line - parent.line + blanks).min(lines.len().checked_sub(1)?);
+            let start = (0..=estimate)
+                .rev()
+                .find(|&i| lines[i].trim() == first.trim())?;
+            let indent = indentation(lines[start]);
+            let header = lines[..start]
+                .iter()
+                .rev()
+                .find(|line| !line.trim().is_empty() && indentation(line) < indent)?;
+            // Remove as much indentation from the header as was removed from the fragment, as by
+            // `verso --dedent-nested`.
+            let dedent = indent
+                .saturating_sub(indentation(first))
+                .min(indentation(header));
+            Some((parent.line, header[dedent..].to_owned()))
+        })
+        .max_by_key(|(line, _)| *line)
+        .map(|(_, header)| header)
+}
+
+// Prefix each line of `body` with its line in `f`'s source file, as in `12 | code`, padding the
+// numbers to the same width. `skipped` lines were removed from the start of `f`'s body. Blank lines
+// at the end of the body aren't numbered.
+fn number_lines(body: &str, f: &Fragment, skipped: usize) -> String {
+    let lines: Vec<&str> = body.split('\n').collect();
+    let numbered = lines
+        .iter()
+        .rposition(|l| !l.trim().is_empty())
+        .map_or(0, |i| i + 1);
+    let number = |i: usize| source_line(f, skipped + i);
+    let width = (0..numbered).map(|i| number(i).to_string().len()).max();
+    lines
+        .iter()
+        .enumerate()
+        .map(|(i, line)| match width {
+            Some(width) if i < numbered && line.is_empty() => {
+                format!("{:>width$} |", number(i), width = width)
+            }
+            Some(width) if i < numbered => {
+                format!("{:>width$} | {}", number(i), line, width = width)
+            }
+            _ => line.to_string(),
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+// Apply the render options which rewrite `body`, keeping track of the line of the original body
+// that each line of it shows.
+fn render_body(
+    body: &str,
+    shown: Vec<Option<usize>>,
+    options: &[RenderOption],
+) -> (String, Vec<Option<usize>>) {
+    options.iter().fold(
+        (body.to_owned(), shown),
+        |(body, shown), option| match option {
+            RenderOption::Wrap(width) => {
+                let (lines, from): (Vec<String>, Vec<Option<usize>>) =
+                    wrap_lines(&body, *width).into_iter().unzip();
+                let shown = from.into_iter().map(|i| shown[i?]).collect();
+                (lines.join("\n"), shown)
+            }
+            RenderOption::Source(_)
+            | RenderOption::ExpandTabs(_)
+            | RenderOption::WithHeader
+            | RenderOption::LineNumbers => (body, shown),
+        },
+    )
+}
+
+// Replace tabs with spaces up to the next tab stop. Unless `tabs.all` is set, only tabs in each
+// line's leading whitespace are replaced.
+fn expand_tabs(body: &str, tabs: TabExpansion) -> String {
+    body.split('\n')
+        .map(|line| {
+            let mut expanded = String::new();
+            let mut width = 0;
+            for (i, c) in line.char_indices() {
+                if c == '\t' {
+                    let spaces = tabs.width - width % tabs.width;
+                    expanded.push_str(&" ".repeat(spaces));
+                    width += spaces;
+                } else if tabs.all || c == ' ' {
+                    expanded.push(c);
+                    width += 1;
+                } else {
+                    expanded.push_str(&line[i..]);
+                    break;
+                }
+            }
+            expanded
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+// Reflow the prose in a fragment body to fit within `width` columns. This is deliberately
+// conservative: only lines which begin at the left margin are treated as prose. Blank lines separate
+// paragraphs and are kept as-is, and indented lines are assumed to be code and left untouched. Words
+// longer than the width are placed on their own line rather than broken. Each line comes with the
+// line of `body` it is, counting from 0; lines of a reflowed paragraph are made from several, so
+// have none.
+fn wrap_lines(body: &str, width: usize) -> Vec<(String, Option<usize>)> {
+    let mut lines: Vec<(String, Option<usize>)> = vec![];
+    let mut paragraph: Vec<&str> = vec![];
+    let fill = |lines: &mut Vec<(String, Option<usize>)>, paragraph: &[&str]| {
+        lines.extend(
+            fill_paragraph(paragraph, width)
+                .into_iter()
+                .map(|l| (l, None)),
+        );
+    };
+
+    for (i, line) in body.split('\n').enumerate() {
+        if line.trim().is_empty() || line.starts_with(char::is_whitespace) {
+            fill(&mut lines, &paragraph);
+            paragraph.clear();
+            lines.push((line.to_owned(), Some(i)));
+        } else {
+            paragraph.extend(line.split_whitespace());
+        }
+    }
+    fill(&mut lines, &paragraph);
+
+    lines
+}
+
+fn fill_paragraph(words: &[&str], width: usize) -> Vec<String> {
+    let mut lines = vec![];
+    let mut current = String::new();
+
+    for word in words {
+        let current_width = current.chars().count();
+        if current_width > 0 && current_width + 1 + word.chars().count() > width {
+            lines.push(std::mem::take(&mut current));
+        }
+        if !current.is_empty() {
+            current.push(' ');
+        }
+        current.push_str(word);
+    }
+
+    if !current.is_empty() {
+        lines.push(current);
+    }
+    lines
+}
+
+// Settings which change how `weave` behaves, beyond the symbols it looks for.
+#[derive(Debug, Default, PartialEq, Eq, Clone)]
+pub struct WeaveOptions {
+    // Leave references to missing fragments in the output exactly as written, instead of failing.
+    pub passthrough_missing: bool,
+    // Values for global metadata such as `@?@.date`, which don't belong to any fragment. Keys are
+    // lowercase property names.
+    pub globals: BTreeMap<String, String>,
+    pub syntax: Syntax,
+    pub final_newline: FinalNewline,
+    // How to trim fragment bodies as they are inserted, if at all. Bodies are normally inserted as
+    // they were extracted, and verbatim insertions are never trimmed.
+    pub trim: Option<TrimPolicy>,
+    // Marks the end of a truncated `@?id.snippet(n)`. Defaults to "...".
+    pub snippet_ellipsis: Option<String>,
+    // The metadata properties `@?id.prop` may name.
+    pub properties: PropertyRegistry,
+    // The comment style for `@@id!source`, such as `#` or `<!-- -->`. Defaults to `//`.
+    pub source_comment_style: Option<String>,
+    // How `@toc` formats each fragment it lists. Defaults to "- `{id}` ({loc})".
+    pub toc_template: Option<String>,
+    // Expand tabs in every inserted fragment, unless an insertion says otherwise with a render
+    // option. Verbatim insertions are left alone.
+    pub expand_tabs: Option<TabExpansion>,
+    // Flags for conditional insertions like `@@?flag:id`.
+    pub defines: BTreeSet<String>,
+    // Let `@*` patterns insert each of the fragments kept for an ID in turn, rather than all of them
+    // joined into one as other insertions are.
+    pub pattern_duplicates: bool,
+    // Wrap plain `@@id` insertions in code fences, as if they were written `@@@id`.
+    pub fence_all: bool,
+    // Languages for code fences by lowercase file extension, ahead of the built-in table. An empty
+    // language leaves the fence without one.
+    pub fence_languages: BTreeMap<String, String>,
+    // Names for `@?id.lang` by lowercase file extension, ahead of the built-in table.
+    pub language_names: BTreeMap<String, String>,
+    // Fail on missing fragments even when the insertion gives a default, as in `@@id ?? "TODO"`.
+    pub ignore_defaults: bool,
+    // Number every inserted line, as if each insertion had the `lines` render option.
+    pub line_numbers: bool,
+    // What `@?id.abspath` puts before each fragment's file.
+    pub abspath_root: AbsPathRoot,
+}
+
+// The root of the paths inserted by `@?id.abspath`.
+#[derive(Debug, Default, PartialEq, Eq, Clone)]
+pub enum AbsPathRoot {
+    // A `/`, as if the project were served from the root of a site.
+    #[default]
+    Slash,
+    // A URL or path to join the file to, as in `https://example.com/repo`.
+    Base(String),
+    // The file's real location, resolved from the working directory by `fs::canonicalize`.
+    Filesystem,
+}
+
+impl AbsPathRoot {
+    // The absolute path of `file` under this root. Other than on the filesystem, its separators are
+    // forward slashes, whatever the platform.
+    pub fn join(&self, file: &str) -> Result<String, WeaveError> {
+        match self {
+            AbsPathRoot::Slash => Ok(format!("/{}", posix_path(file))),
+            AbsPathRoot::Base(base) => Ok(format!(
+                "{}/{}",
+                base.trim_end_matches('/'),
+                posix_path(file).trim_start_matches('/')
+            )),
+            AbsPathRoot::Filesystem => std::fs::canonicalize(file)
+                .map(|path| path.to_string_lossy().into_owned())
+                .map_err(|_| WeaveError::BadMetadata(file.to_owned())),
+        }
+    }
+}
+
+// Whether woven output ends with a newline.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub enum FinalNewline {
+    // Only if the prose file does.
+    #[default]
+    Preserve,
+    Always,
+    Never,
+}
+
+// Line endings to force on woven output.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum LineEnding {
+    Lf,
+    Crlf,
+    // Whichever is usual on the platform this was built for.
+    Native,
+}
+
+impl LineEnding {
+    pub fn from_name(name: &str) -> Option<LineEnding> {
+        match name {
+            "lf" => Some(LineEnding::Lf),
+            "crlf" => Some(LineEnding::Crlf),
+            "native" => Some(LineEnding::Native),
+            _ => None,
+        }
+    }
+
+    // `text` with every line ending, whether LF or CRLF, replaced by this one.
+    pub fn apply(&self, text: &str) -> String {
+        let lf = text.replace("\r\n", "\n");
+        match self {
+            LineEnding::Crlf => lf.replace('\n', "\r\n"),
+            LineEnding::Native if cfg!(windows) => lf.replace('\n', "\r\n"),
+            _ => lf,
+        }
+    }
+}
+
+// The reference syntaxes `weave` understands.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub enum Syntax {
+    // Lines beginning with the insertion symbols, and metadata symbols anywhere, e.g. `@@id`.
+    #[default]
+    Symbols,
+    // Inline tags such as `{{verso:id}}` and `{{verso:id.file}}`, which can coexist with other
+    // templating engines that give `@` a meaning.
+    Mustache,
+}
+
+// Fragments by ID, as `weave` reads them. Each ID maps to every fragment kept for it, in the order
+// they were read.
+pub type Annotations = BTreeMap<String, Vec<Fragment>>;
+
+// The fragment that inserting `id` refers to. Several fragments kept for one ID are joined into
+// one, as when a fragment is reopened further down a file.
+pub fn find_fragment<'a>(annotations: &'a Annotations, id: &str) -> Option<Cow<'a, Fragment>> {
+    match annotations.get(id)?.as_slice() {
+        [] => None,
+        [f] => Some(Cow::Borrowed(f)),
+        [first, rest @ ..] => {
+            let mut joined = first.clone();
+            rest.iter().for_each(|f| joined.append(f));
+            Some(Cow::Owned(joined))
+        }
+    }
+}
+
+// Which fragment to keep when several share an ID.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub enum DuplicatePolicy {
+    // Keep them all. Insertions join them into one, in the order they were read, as `verso` does
+    // within a file.
+    #[default]
+    Merge,
+    Last,
+    First,
+    Error,
+}
+
+// Fragments indexed by ID as `recto` reads them, one at a time, keeping those which share an ID as
+// a `DuplicatePolicy` says.
+#[derive(Debug, Default)]
+pub struct FragmentIndex {
+    annotations: Annotations,
+    on_duplicate: DuplicatePolicy,
+    read: usize,
+}
+
+impl FragmentIndex {
+    pub fn new(on_duplicate: DuplicatePolicy) -> FragmentIndex {
+        FragmentIndex {
+            on_duplicate,
+            ..Default::default()
+        }
+    }
+
+    // Add the next fragment read, and say whether it was kept. Fails if it shares an ID with one
+    // already kept and the policy is `Error`.
+    pub fn add(&mut self, mut f: Fragment) -> Result<bool, String> {
+        self.read += 1;
+        // Fragments from older versions of verso have no index, so number them in the order they
+        // arrive. `@?id.next` and `@?id.prev` rely on this.
+        if f.index == 0 {
+            f.index = self.read;
+        }
+        let kept = self.annotations.entry(f.id.to_owned()).or_default();
+        match (kept.first(), self.on_duplicate) {
+            (None, _) | (Some(_), DuplicatePolicy::Merge) => {}
+            (Some(_), DuplicatePolicy::Last) => kept.clear(),
+            (Some(_), DuplicatePolicy::First) => return Ok(false),
+            (Some(existing), DuplicatePolicy::Error) => {
+                return Err(format!(
+                    "Error: fragment '{}' is defined in both {}:{} and {}:{}",
+                    f.id, existing.file, existing.line, f.file, f.line
+                ))
+            }
+        }
+        kept.push(f);
+        Ok(true)
+    }
+
+    pub fn into_annotations(self) -> Annotations {
+        self.annotations
+    }
+}
+
+// The fragments that `recto --check-cmd` checks: the one `find_fragment` gives for each ID, from
+// files with one of `extensions`, or from any file if there are none.
+pub fn fragments_to_check<'a>(
+    annotations: &'a Annotations,
+    extensions: &[String],
+) -> Vec<Cow<'a, Fragment>> {
+    annotations
+        .keys()
+        .filter_map(|id| find_fragment(annotations, id))
+        .filter(|f| {
+            let extension = Path::new(&f.file)
+                .extension()
+                .and_then(|e| e.to_str())
+                .unwrap_or_default();
+            extensions.is_empty() || extensions.iter().any(|e| e == extension)
+        })
+        .collect()
+}
+
+// Run `check_cmd`, split on whitespace, with `body` on stdin. Returns the command's stderr if it
+// fails, or None if it succeeds.
+pub fn run_check_cmd(check_cmd: &str, body: &str) -> Result<Option<String>, Box<dyn Error>> {
+    let mut words = check_cmd.split_whitespace();
+    let program = words.next().ok_or("The check command is empty")?;
+    let mut child = Command::new(program)
+        .args(words)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    // Feed the body from another thread, so that a chatty checker can't fill its stderr pipe
+    // while we are blocked writing.
+    let mut stdin = child
+        .stdin
+        .take()
+        .ok_or("Could not open the check command's stdin")?;
+    let body = body.to_owned();
+    let writer = thread::spawn(move || io::Write::write_all(&mut stdin, body.as_bytes()));
+    let output = child.wait_with_output()?;
+    // A checker may exit without reading all of its input, which is not an error here.
+    let _ = writer.join();
+
+    if output.status.success() {
+        Ok(None)
+    } else {
+        Ok(Some(String::from_utf8_lossy(&output.stderr).into_owned()))
+    }
+}
+
+pub fn weave(
+    filename: &str,
+    contents: &str,
+    annotations: &Annotations,
+    symbols: &SymbolKey,
+) -> Result<String, FileError<WeaveError>> {
+    weave_with_options(
+        filename,
+        contents,
+        annotations,
+        symbols,
+        &WeaveOptions::default(),
+    )
+}
+
+pub fn weave_with_options(
+    filename: &str,
+    contents: &str,
+    annotations: &Annotations,
+    symbols: &SymbolKey,
+    options: &WeaveOptions,
+) -> Result<String, FileError<WeaveError>> {
+    weave_with_report(filename, contents, annotations, symbols, options).map(|(woven, _)| woven)
+}
+
+// A fragment which weaving placed in the output, and where.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize)]
+pub struct Placement {
+    // The prose file being woven.
+    pub file: String,
+    pub id: String,
+    pub kind: ReferenceKind,
+    // The line of the woven output on which the fragment's text, or the line holding its metadata,
+    // begins.
+    pub line: usize,
+}
+
+// As `weave_with_options`, but also report which fragments were placed in the output and where.
+pub fn weave_with_report(
+    filename: &str,
+    contents: &str,
+    annotations: &Annotations,
+    symbols: &SymbolKey,
+    options: &WeaveOptions,
+) -> Result<(String, Vec<Placement>), FileError<WeaveError>> {
+    weave_with_source_map(filename, contents, annotations, symbols, options)
+        .map(|(woven, placements, _)| (woven, placements))
+}
+
+// Where each line of a woven document came from: its line number, and the file and line of the
+// fragment text on it, or `None` for prose.
+pub type SourceMap = Vec<(usize, Option<(String, usize)>)>;
+
+// As `weave_with_report`, but also map each line of the output to its source.
+pub fn weave_with_source_map(
+    filename: &str,
+    contents: &str,
+    annotations: &Annotations,
+    symbols: &SymbolKey,
+    options: &WeaveOptions,
+) -> Result<(String, Vec<Placement>, SourceMap), FileError<WeaveError>> {
+    let (substrings, placed) =
+        weave_lines(filename, contents, annotations, symbols, options, None)?;
+
+    // Each substring begins on the line after the last one ends.
+    let mut starts = vec![];
+    let mut next_line = 1;
+    for substring in &substrings {
+        starts.push(next_line);
+        next_line += substring.matches('\n').count() + 1;
+    }
+    // A line holding more than one insertion, as mustache tags allow, takes the first one's source.
+    let mut inserted: BTreeMap<usize, Vec<&Sources>> = BTreeMap::new();
+    for (substring, _, _, sources) in &placed {
+        inserted.entry(*substring).or_default().push(sources);
+    }
+    let mut source_map: SourceMap = vec![];
+    for (i, substring) in substrings.iter().enumerate() {
+        let placed_here = inserted.get(&i).map_or(&[][..], Vec::as_slice);
+        for line in 0..substring.split('\n').count() {
+            let source = placed_here
+                .iter()
+                .find_map(|sources| sources.get(line).cloned().flatten());
+            source_map.push((source_map.len() + 1, source));
+        }
+    }
+
+    let placements = placed
+        .into_iter()
+        .map(|(substring, kind, id, _)| Placement {
+            file: filename.to_owned(),
+            id,
+            kind,
+            line: starts[substring],
+        })
+        .collect();
+
+    let document = apply_final_newline(&substrings, contents, options.final_newline);
+    // Lines are read without their endings, so restore Windows ones, inserted fragments included.
+    let crlf = contents
+        .find('\n')
+        .is_some_and(|i| contents[..i].ends_with('\r'));
+    let document = if crlf {
+        LineEnding::Crlf.apply(&document)
+    } else {
+        document
+    };
+    // The last substring may be the empty line after a final newline.
+    source_map.truncate(document.lines().count());
+    Ok((document, placements, source_map))
+}
+
+// Weave the text of each `Str` and `CodeBlock` node in a Pandoc syntax tree, as written by
+// `pandoc -t json`, leaving the rest of the tree, including code blocks' attributes, as it was. A
+// `Str` is a single word, so an insertion which would put more than one line in one is an error.
+pub fn weave_pandoc(
+    node: &mut serde_json::Value,
+    filename: &str,
+    annotations: &Annotations,
+    symbols: &SymbolKey,
+    options: &WeaveOptions,
+) -> Result<(), FileError<WeaveError>> {
+    use serde_json::Value;
+
+    let weave_text = |text: &mut String| {
+        *text = weave_with_options(filename, text, annotations, symbols, options)?;
+        Ok(())
+    };
+    match node {
+        Value::Array(items) => {
+            for item in items {
+                weave_pandoc(item, filename, annotations, symbols, options)?;
+            }
+        }
+        Value::Object(fields) => {
+            let kind = fields.get("t").and_then(Value::as_str).map(String::from);
+            match (kind.as_deref(), fields.get_mut("c")) {
+                (Some("Str"), Some(Value::String(text))) => {
+                    let original = text.to_owned();
+                    weave_text(text)?;
+                    if text.contains('\n') {
+                        return Err(FileError {
+                            err_type: WeaveError::MultilineInsertion(original.clone()),
+                            filename: filename.to_owned(),
+                            line: 0,
+                            col: 0,
+                            message: Some(format!(
+                                "'{}' inserts more than one line into a single word; use a code \
+                                 block instead",
+                                original
+                            )),
+                        });
+                    }
+                }
+                // A code block's contents follow its attributes.
+                (Some("CodeBlock"), Some(Value::Array(contents))) => {
+                    if let Some(Value::String(text)) = contents.get_mut(1) {
+                        weave_text(text)?;
+                    }
+                }
+                _ => {
+                    for value in fields.values_mut() {
+                        weave_pandoc(value, filename, annotations, symbols, options)?;
+                    }
+                }
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+// Every error that weaving `contents` would meet, where weaving stops at the first. A line with an
+// error is left out, and weaving carries on from the next.
+pub fn weave_errors(
+    filename: &str,
+    contents: &str,
+    annotations: &Annotations,
+    symbols: &SymbolKey,
+    options: &WeaveOptions,
+) -> Vec<FileError<WeaveError>> {
+    let mut errors = vec![];
+    let woven = weave_lines(
+        filename,
+        contents,
+        annotations,
+        symbols,
+        options,
+        Some(&mut errors),
+    );
+    errors.extend(woven.err());
+    errors
+}
+
+// The woven lines so far, and the fragments placed in them.
+type Woven = (Vec<String>, Placed);
+
+// Weave each line of `contents` in the syntax `options` chooses. With `errors`, a line which fails
+// is recorded there rather than ending the weave.
+fn weave_lines(
+    filename: &str,
+    contents: &str,
+    annotations: &Annotations,
+    symbols: &SymbolKey,
+    options: &WeaveOptions,
+    mut errors: Option<&mut Vec<FileError<WeaveError>>>,
+) -> Result<Woven, FileError<WeaveError>> {
+    let weave_line = match options.syntax {
+        Syntax::Mustache => weave_mustache_line,
+        Syntax::Symbols => weave_symbols_line,
+    };
+    let mut woven: Woven = (vec![], vec![]);
+    for (line_no, line) in contents.lines().enumerate().map(|(l, c)| (l + 1, c)) {
+        let result = weave_line(
+            filename,
+            line_no,
+            line,
+            annotations,
+            symbols,
+            options,
+            &mut woven,
+        );
+        match (result, errors.as_deref_mut()) {
+            (Err(e), Some(errors)) => errors.push(e),
+            (result, _) => result?,
+        }
+    }
+    Ok(woven)
+}
+
+// One entry of a table of contents: the template with `{id}` and `{prop}` filled in for `f`.
+fn expand_toc_entry(
+    f: &Fragment,
+    filename: &str,
+    annotations: &Annotations,
+    symbols: &SymbolKey,
+    options: &WeaveOptions,
+) -> Result<String, WeaveError> {
+    let mut rest = options.toc_template.as_deref().unwrap_or(TOC_TEMPLATE);
+    let mut entry = String::new();
+    while let Some((before, after)) = rest.split_once('{') {
+        let (name, after) = match after.split_once('}') {
+            Some(split) => split,
+            None => break,
+        };
+        entry.push_str(before);
+        if name == "id" {
+            entry.push_str(&f.id);
+        } else {
+            let properties = &options.properties;
+            let value = properties.resolve(&f.id, name, annotations, filename, symbols, options)?;
+            entry.push_str(&value);
+        }
+        rest = after;
+    }
+    entry.push_str(rest);
+    Ok(entry)
+}
+
+// The fragments placed by weaving, as the index of the output substring holding each one. Inserted
+// text also has the source of each line of that substring, which is `None` for prose.
+type Placed = Vec<(usize, ReferenceKind, String, Sources)>;
+
+// The file and line that each line of some text came from, if it came from a fragment.
+type Sources = Vec<Option<(String, usize)>>;
+
+// Weave one line in the usual syntax, adding the result to `woven`.
+fn weave_symbols_line(
+    filename: &str,
+    line_no: usize,
+    line: &str,
+    annotations: &Annotations,
+    symbols: &SymbolKey,
+    options: &WeaveOptions,
+    woven: &mut Woven,
+) -> Result<(), FileError<WeaveError>> {
+    let (substrings, placed) = woven;
+    // Kept apart from `line`, which loses its condition and default below, so that a missing
+    // fragment can be passed through exactly as it was written.
+    let original = line;
+    // Inserted fragments take on the indentation of the line they replace, so that they stay
+    // within a list item or a code block.
+    let indent = &line[..line.len() - line.trim_start().len()];
+    if let Some(literal) = strip_escaped_directive(line.trim_start(), symbols) {
+        // The line is written out as it is, less the escape, without expanding any metadata.
+        substrings.push(format!("{}{}", indent, literal));
+    } else if let Some((symbol, mode)) = symbols.match_insertion(line.trim_start()) {
+        // A conditional insertion whose condition fails leaves no trace in the output.
+        let unconditional;
+        let line = match split_condition(line.trim_start(), symbol, symbols) {
+            Some((negated, flag, _)) if options.defines.contains(flag) == negated => return Ok(()),
+            Some((_, _, insertion)) => {
+                unconditional = insertion;
+                unconditional.as_str()
+            }
+            None => line,
+        };
+        let (line, default) = split_default(line);
+        let (directive, render_options) = if mode == InsertionMode::Verbatim {
+            (line.trim_start(), vec![])
+        } else {
+            match extract_render_options(line.trim_start(), symbol.chars().count()) {
+                Ok(parsed) => parsed,
+                Err(option) => {
+                    return Err(FileError {
+                        err_type: WeaveError::BadRenderOption(option.to_owned()),
+                        filename: filename.to_owned(),
+                        line: line_no,
+                        col: symbol.chars().count(),
+                        message: Some(format!("unknown or malformed render option '{}'", option)),
+                    });
+                }
+            }
+        };
+        let (directive, range) = extract_line_range(directive, symbol.chars().count());
+        let id = extract_id_with_symbols(directive, symbol.chars().count(), symbols);
+        match id {
+            Ok(id) => {
+                let fragment = find_fragment(annotations, &id);
+                match fragment.as_deref() {
+                    // TODO Add indexing information.
+                    Some(f) => {
+                        let sliced;
+                        let f = match range {
+                            Some(range) => {
+                                sliced = range.slice(f).ok_or_else(|| FileError {
+                                    err_type: WeaveError::RangeOutOfBounds(
+                                        id.to_owned(),
+                                        range.to_string(),
+                                    ),
+                                    filename: filename.to_owned(),
+                                    line: line_no,
+                                    col: symbol.chars().count() + id.chars().count(),
+                                    message: Some(format!(
+                                        "lines {} are out of bounds for fragment '{}', which \
+                                         has {} line(s)",
+                                        range,
+                                        id,
+                                        f.body.split('\n').count()
+                                    )),
+                                })?;
+                                &sliced
+                            }
+                            None => f,
+                        };
+                        let (rendered, shown) =
+                            render_insertion(f, mode, annotations, options, &render_options);
+                        let sources = fragment_sources(f, &shown);
+                        placed.push((substrings.len(), ReferenceKind::Insertion, id, sources));
+                        substrings.push(match mode {
+                            InsertionMode::Verbatim => rendered,
+                            _ => indent_lines(&rendered, indent),
+                        })
+                    }
+                    None if default.is_some() && !options.ignore_defaults => substrings
+                        .push(indent_lines(default.as_deref().unwrap_or_default(), indent)),
+                    None if options.passthrough_missing => substrings.push(original.to_owned()),
+                    None => {
+                        return Err(FileError {
+                            err_type: WeaveError::MissingFragment(id.to_owned()),
+                            filename: filename.to_owned(),
+                            line: line_no,
+                            col: symbol.chars().count(),
+                            message: Some(format!("no fragment found with identifier {}", id)),
+                        })
+                    }
+                }
+            }
+            Err(IdExtractError::NoIdFound) => {
+                return Err(FileError {
+                    err_type: WeaveError::MissingId,
+                    filename: filename.to_owned(),
+                    line: line_no,
+                    col: 0,
+                    message: Some(format!("no fragment identifier found in line: {}", line)),
+                })
+            }
+            Err(IdExtractError::ReservedCharacterUsed(c)) => {
+                return Err(FileError {
+                    err_type: WeaveError::IdExtractError,
+                    filename: filename.to_owned(),
+                    line: line_no,
+                    col: 0,
+                    message: Some(format!(
+                        "error parsing identifier in fragment open symbol: {}
+                         (used reserved character {})",
+                        line, c
+                    )),
+                })
+            }
+        }
+    } else if line.trim_start().starts_with(&symbols.pattern) {
+        let (pattern, pattern_options) =
+            split_pattern_options(line.trim_start()).map_err(|message| FileError {
+                err_type: WeaveError::PatternExtractError,
+                filename: filename.to_owned(),
+                line: line_no,
+                col: 0,
+                message: Some(message),
+            })?;
+        let re = extract_pattern(pattern, symbols.pattern.chars().count());
+        match re {
+            Ok(re) => {
+                let mut matched: Vec<Cow<Fragment>> = vec![];
+                for (id, all) in annotations.iter().filter(|(id, _)| re.is_match(id)) {
+                    if options.pattern_duplicates {
+                        matched.extend(all.iter().map(Cow::Borrowed));
+                    } else {
+                        matched.extend(find_fragment(annotations, id));
+                    }
+                }
+                if pattern_options.order == PatternOrder::Location {
+                    matched.sort_by(|a, b| (&a.file, a.line, a.col).cmp(&(&b.file, b.line, b.col)));
+                }
+                for (i, f) in matched.into_iter().enumerate() {
+                    let trimmed = trim_inserted(&f.body, options);
+                    let skipped = skipped_lines(&f.body, options);
+                    let mut shown: Vec<Option<usize>> = (skipped..)
+                        .take(trimmed.split('\n').count())
+                        .map(Some)
+                        .collect();
+                    // The separator goes right before the body. Its lines end as the body's
+                    // first begins, so a final newline in it doesn't add another.
+                    let mut inserted = trimmed;
+                    if let Some(separator) = pattern_options.separator.as_ref().filter(|_| i > 0) {
+                        let separator = separator.strip_suffix('\n').unwrap_or(separator);
+                        inserted = format!("{}\n{}", separator, inserted);
+                        let lines = separator.split('\n').count();
+                        shown.splice(0..0, vec![None; lines]);
+                    }
+                    let sources = fragment_sources(&f, &shown);
+                    let id = f.id.to_owned();
+                    placed.push((substrings.len(), ReferenceKind::Pattern, id, sources));
+                    substrings.push(indent_lines(&inserted, indent));
+                }
+            }
+            Err(PatternExtractError::NoPatternFound) => {
+                return Err(FileError {
+                    err_type: WeaveError::PatternExtractError,
+                    filename: filename.to_owned(),
+                    line: line_no,
+                    col: 0,
+                    message: Some(format!("no fragment pattern found in line: {}", line)),
+                })
+            }
+            Err(PatternExtractError::RegexConstruction(e)) => {
+                return Err(FileError {
+                    err_type: WeaveError::PatternExtractError,
+                    filename: filename.to_owned(),
+                    line: line_no,
+                    col: 0,
+                    message: Some(format!(
+                        "error parsing pattern at insertion symbol: {}
+                         (regex construction failed with {})",
+                        line, e
+                    )),
+                })
+            }
+        }
+    } else if line.trim_start().starts_with(&symbols.toc) {
+        let re = extract_pattern(line.trim_start(), symbols.toc.chars().count()).map_err(|e| {
+            let reason = match e {
+                PatternExtractError::NoPatternFound => String::from("no pattern found"),
+                PatternExtractError::RegexConstruction(e) => e.to_string(),
+            };
+            FileError {
+                err_type: WeaveError::PatternExtractError,
+                filename: filename.to_owned(),
+                line: line_no,
+                col: 0,
+                message: Some(format!("bad table of contents pattern: {}", reason)),
+            }
+        })?;
+        // The table follows the order in which the fragments are numbered.
+        let mut listed: Vec<Cow<Fragment>> = annotations
+            .keys()
+            .filter(|id| re.is_match(id))
+            .filter_map(|id| find_fragment(annotations, id))
+            .collect();
+        listed.sort_by_key(|f| f.index);
+        for f in listed {
+            let entry = expand_toc_entry(&f, filename, annotations, symbols, options);
+            let entry = entry.map_err(|err_type| FileError {
+                message: Some(format!("in the table of contents entry for '{}'", f.id)),
+                err_type,
+                filename: filename.to_owned(),
+                line: line_no,
+                col: 0,
+            })?;
+            placed.push((
+                substrings.len(),
+                ReferenceKind::Pattern,
+                f.id.to_owned(),
+                vec![],
+            ));
+            substrings.push(entry);
+        }
+    } else if line.contains(&symbols.metadata) {
+        let expanded =
+            expand_metadata_refs(line, filename, line_no, annotations, symbols, options)?;
+        for reference in find_references(filename, line, symbols) {
+            if annotations.contains_key(&reference.target) {
+                placed.push((substrings.len(), reference.kind, reference.target, vec![]));
+            }
+        }
+        substrings.push(expanded);
+    } else {
+        substrings.push(line.to_owned());
+    }
+
+    Ok(())
+}
+
+// Put `indent` before each line of `body`, except blank ones, which would be left with trailing
+// whitespace.
+fn indent_lines(body: &str, indent: &str) -> String {
+    if indent.is_empty() {
+        return body.to_owned();
+    }
+    body.split('\n')
+        .map(|line| {
+            if line.trim().is_empty() {
+                line.to_owned()
+            } else {
+                format!("{}{}", indent, line)
+            }
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+// Join the woven lines, with a final newline only if `policy` calls for one. `contents` is the
+// unwoven text. Whether it ended with a newline is all that matters: a fragment inserted at the end
+// which brings its own line ending, as an untrimmed body does, doesn't add another.
+fn apply_final_newline(substrings: &[String], contents: &str, policy: FinalNewline) -> String {
+    let mut document = substrings.join("\n");
+    if substrings.last().is_some_and(|last| last.ends_with('\n')) {
+        document.pop();
+    }
+    let wanted = match policy {
+        FinalNewline::Preserve => contents.ends_with('\n'),
+        FinalNewline::Always => true,
+        FinalNewline::Never => false,
+    };
+    if wanted {
+        document.push('\n');
+    }
+    document
+}
+
+// Format seconds since the Unix epoch as a UTC date, YYYY-MM-DD.
+pub fn format_date(epoch_secs: u64) -> String {
+    // Convert days since the epoch to a civil date; see Howard Hinnant's `civil_from_days`.
+    let days = (epoch_secs / 86400) as i64 + 719468;
+    let era = days / 146097;
+    let doe = days - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+// A header or footer for woven files with `{file}` and `{date}` filled in, without any trailing
+// newlines.
+pub fn expand_banner(banner: &str, filename: &str, date: &str) -> String {
+    banner
+        .replace("{file}", filename)
+        .replace("{date}", date)
+        .trim_end_matches('\n')
+        .to_owned()
+}
+
+// Put a header and footer, as from `expand_banner`, around a woven document, each on lines of their
+// own. The header goes after `front_matter`, which must stay at the very start of the file, and the
+// document's final newline, if it had one, stays at the end. Returns the result and the number of
+// lines before the woven text.
+pub fn add_banners(
+    front_matter: &str,
+    woven: &str,
+    header: Option<&str>,
+    footer: Option<&str>,
+) -> (String, usize) {
+    let mut document = front_matter.to_owned();
+    if let Some(header) = header {
+        document.push_str(header);
+        document.push('\n');
+    }
+    let before = document.matches('\n').count();
+    document.push_str(woven);
+    if let Some(footer) = footer {
+        let ends_with_newline = woven.ends_with('\n');
+        if !woven.is_empty() && !ends_with_newline {
+            document.push('\n');
+        }
+        document.push_str(footer);
+        if ends_with_newline {
+            document.push('\n');
+        }
+    }
+    (document, before)
+}
+
+// Shorten every run of blank lines in `text` to at most `max` lines. Blank lines inside Markdown
+// code fences are left alone, since they may be significant there.
+pub fn squeeze_blank_lines(text: &str, max: usize) -> String {
+    let mut lines: Vec<&str> = vec![];
+    // The opening fence of the code block we're in, if any.
+    let mut fence: Option<&str> = None;
+    let mut blanks = 0;
+
+    for line in text.split('\n') {
+        let trimmed = line.trim();
+        let fence_len = |c: char| trimmed.chars().take_while(|&t| t == c).count();
+        let marker = ['`', '~']
+            .iter()
+            .map(|&c| &trimmed[..fence_len(c)])
+            .find(|m| m.len() >= CODE_FENCE_LEN);
+        match (fence, marker) {
+            (None, Some(marker)) => fence = Some(marker),
+            // A fence is closed by a bare run of the same character, at least as long.
+            (Some(open), Some(marker))
+                if marker.len() == trimmed.len()
+                    && marker.starts_with(&open[..1])
+                    && marker.len() >= open.len() =>
+            {
+                fence = None
+            }
+            _ => {}
+        }
+
+        if trimmed.is_empty() && fence.is_none() {
+            blanks += 1;
+            if blanks > max {
+                continue;
+            }
+        } else {
+            blanks = 0;
+        }
+        lines.push(line);
+    }
+
+    lines.join("\n")
+}
+
+// Weave one line using the mustache syntax, adding the result to `woven`. Tags may appear anywhere
+// in a line: `{{verso:id}}` is replaced
+// by the fragment's body (render options are accepted, as in `{{verso:id!wrap=80}}`), and
+// `{{verso:id.prop}}` by a metadata property.
+fn weave_mustache_line(
+    filename: &str,
+    line_no: usize,
+    line: &str,
+    annotations: &Annotations,
+    symbols: &SymbolKey,
+    options: &WeaveOptions,
+    woven: &mut Woven,
+) -> Result<(), FileError<WeaveError>> {
+    let (lines, placed) = woven;
+    let mut woven = String::new();
+    let mut rest_col = 0;
+
+    while let Some(open) = line[rest_col..].find(MUSTACHE_OPEN).map(|i| i + rest_col) {
+        let tag_col = open + MUSTACHE_OPEN.len();
+        let close = line[tag_col..]
+            .find(MUSTACHE_CLOSE)
+            .map(|i| i + tag_col)
+            .ok_or_else(|| FileError {
+                err_type: WeaveError::MetadataParseError,
+                filename: filename.to_owned(),
+                line: line_no,
+                col: line[..open].chars().count(),
+                message: Some(format!("unterminated tag in line: {}", line)),
+            })?;
+        let tag = &line[tag_col..close];
+        let original = &line[open..close + MUSTACHE_CLOSE.len()];
+        let col = line[..tag_col].chars().count();
+
+        let (kind, expansion) = if tag.contains(symbols.metadata_separator) {
+            let expansion =
+                expand_metadata(tag, filename, line_no, col, annotations, symbols, options);
+            (ReferenceKind::Metadata, expansion.map(|e| (e, vec![])))
+        } else {
+            let expansion = expand_mustache_insertion(
+                tag,
+                filename,
+                line_no,
+                col,
+                annotations,
+                symbols,
+                options,
+            );
+            (ReferenceKind::Insertion, expansion)
+        };
+        let expansion = match expansion {
+            Err(FileError {
+                err_type: WeaveError::MissingFragment(_),
+                ..
+            }) if options.passthrough_missing => original.to_owned(),
+            result => {
+                let (expansion, sources) = result?;
+                let separators = [symbols.metadata_separator, RENDER_OPTION_SEPARATOR];
+                let id = tag.split(&separators[..]).next().unwrap_or_default().trim();
+                if annotations.contains_key(id) {
+                    // The inserted text starts on the woven line's last line so far.
+                    let before = woven.matches('\n').count();
+                    let sources = vec![None; before].into_iter().chain(sources).collect();
+                    placed.push((lines.len(), kind, id.to_owned(), sources));
+                }
+                expansion
+            }
+        };
+
+        woven.push_str(&line[rest_col..open]);
+        woven.push_str(&expansion);
+        rest_col = close + MUSTACHE_CLOSE.len();
+    }
+
+    woven.push_str(&line[rest_col..]);
+    lines.push(woven);
+
+    Ok(())
+}
+
+fn expand_mustache_insertion(
+    tag: &str,
+    filename: &str,
+    line: usize,
+    col: usize,
+    annotations: &Annotations,
+    symbols: &SymbolKey,
+    options: &WeaveOptions,
+) -> Result<(String, Sources), FileError<WeaveError>> {
+    let error = |err_type, message| FileError {
+        err_type,
+        filename: filename.to_owned(),
+        line,
+        col,
+        message: Some(message),
+    };
+
+    let (directive, render_options) = extract_render_options(tag, 0).map_err(|option| {
+        error(
+            WeaveError::BadRenderOption(option.to_owned()),
+            format!("unknown or malformed render option '{}'", option),
+        )
+    })?;
+    let (directive, range) = extract_line_range(directive, 0);
+    match extract_id_with_symbols(directive, 0, symbols) {
+        Ok(id) => match find_fragment(annotations, &id).as_deref() {
+            Some(f) => {
+                let sliced = match range {
+                    Some(range) => Some(range.slice(f).ok_or_else(|| {
+                        error(
+                            WeaveError::RangeOutOfBounds(id.to_owned(), range.to_string()),
+                            format!(
+                                "lines {} are out of bounds for fragment '{}', which has {} \
+                                 line(s)",
+                                range,
+                                id,
+                                f.body.split('\n').count()
+                            ),
+                        )
+                    })?),
+                    None => None,
+                };
+                let f = sliced.as_ref().unwrap_or(f);
+                let (rendered, shown) = render_insertion(
+                    f,
+                    InsertionMode::Plain,
+                    annotations,
+                    options,
+                    &render_options,
+                );
+                Ok((rendered, fragment_sources(f, &shown)))
+            }
+            None => Err(error(
+                WeaveError::MissingFragment(id.to_owned()),
+                format!("no fragment found with identifier {}", id),
+            )),
+        },
+        Err(IdExtractError::NoIdFound) => Err(error(
+            WeaveError::MissingId,
+            format!("no fragment identifier found in tag: {}", tag),
+        )),
+        Err(IdExtractError::ReservedCharacterUsed(c)) => Err(error(
+            WeaveError::IdExtractError,
+            format!(
+                "error parsing identifier in tag: {} (used reserved character {})",
+                tag, c
+            ),
+        )),
+    }
+}
+
+fn expand_metadata_refs(
+    line: &str,
+    filename: &str,
+    line_no: usize,
+    annotations: &Annotations,
+    symbols: &SymbolKey,
+    options: &WeaveOptions,
+) -> Result<String, FileError<WeaveError>> {
+    let mut expanded = String::new();
+    let ref_len = |s: &str| metadata_ref_len(s, symbols);
+
+    for token in Scanner::new(line, &[symbols.metadata.as_str()], &ref_len) {
+        match token.kind {
+            // An escaped reference is kept as written, without its escape.
+            TokenKind::Symbol { .. } if expanded.ends_with(ESCAPE_CHAR) => {
+                expanded.pop();
+                expanded.push_str(token.text);
+            }
+            TokenKind::Symbol { symbol, word } => {
+                let col = token.col + symbol.chars().count();
+                let expansion =
+                    expand_metadata(word, filename, line_no, col, annotations, symbols, options);
+                match expansion {
+                    Err(FileError {
+                        err_type: WeaveError::MissingFragment(_),
+                        ..
+                    }) if options.passthrough_missing => expanded.push_str(token.text),
+                    result => expanded.push_str(&result?),
+                }
+            }
+            TokenKind::Text => expanded.push_str(token.text),
+        }
+    }
+
+    Ok(expanded)
+}
+
+// The rest of a line which starts with an escaped directive symbol, as in `\@@id` or `\@* x`.
+fn strip_escaped_directive<'a>(line: &'a str, symbols: &SymbolKey) -> Option<&'a str> {
+    let rest = line.strip_prefix(ESCAPE_CHAR)?;
+    let directive = symbols.match_insertion(rest).is_some()
+        || rest.starts_with(&symbols.pattern)
+        || rest.starts_with(&symbols.toc);
+    Some(rest).filter(|_| directive)
+}
+
+// The length of the `id.prop` metadata reference at the start of `s`, which ends at the first
+// character that can't belong to it.
+fn metadata_ref_len(s: &str, symbols: &SymbolKey) -> usize {
+    let separator = symbols.metadata_separator;
+    // A namespace, which may hold the property separator, comes before the ID when it is followed
+    // by one.
+    let ns_len = s
+        .find(NAMESPACE_SEPARATOR)
+        .filter(|&i| {
+            s[..i]
+                .chars()
+                .all(|c| symbols.is_id_char(c) || c == separator)
+        })
+        .map(|i| i + NAMESPACE_SEPARATOR.len())
+        .filter(|&i| s[i..].starts_with(|c: char| symbols.is_id_char(c)));
+    if let Some(ns_len) = ns_len {
+        return ns_len + metadata_ref_len(&s[ns_len..], symbols);
+    }
+
+    // Global metadata, as in `@.date`, is looked up under an ID which no fragment can have.
+    let global = s
+        .strip_prefix(GLOBAL_METADATA_ID)
+        .is_some_and(|rest| rest.starts_with(separator));
+    let id_len = if global {
+        GLOBAL_METADATA_ID.len()
+    } else {
+        s.find(|c: char| !symbols.is_id_char(c)).unwrap_or(s.len())
+    };
+    match s[id_len..].strip_prefix(separator) {
+        Some(prop) => {
+            let prop_len = prop
+                .find(|c: char| !c.is_safe_for_refs())
+                .unwrap_or(prop.len());
+            // A parenthesized argument may follow the property, or else another fragment's ID, as
+            // in `a.relpath.b`.
+            let arg_len = prop[prop_len..]
+                .strip_prefix('(')
+                .and_then(|arg| arg.find(')'))
+                .map_or(0, |close| close + 2);
+            let other_len = prop[prop_len..]
+                .strip_prefix(separator)
+                .filter(|_| arg_len == 0)
+                .map(|other| {
+                    other
+                        .find(|c: char| !symbols.is_id_char(c))
+                        .unwrap_or(other.len())
+                })
+                .filter(|&len| len > 0)
+                .map_or(0, |len| separator.len_utf8() + len);
+            id_len + separator.len_utf8() + prop_len + arg_len + other_len
+        }
+        None => id_len,
+    }
+}
+
+// Resolve an `id.prop` metadata reference, without its leading symbol, which starts at `col`.
+fn expand_metadata(
+    word: &str,
+    filename: &str,
+    line: usize,
+    col: usize,
+    annotations: &Annotations,
+    symbols: &SymbolKey,
+    options: &WeaveOptions,
+) -> Result<String, FileError<WeaveError>> {
+    let (namespace, unqualified) = split_namespace(word);
+    // The ID, and so any errors in it or its property, come after the namespace.
+    let id_col = col
+        + namespace.map_or(0, |ns| {
+            ns.chars().count() + NAMESPACE_SEPARATOR.chars().count()
+        });
+    let pieces: Vec<&str> = unqualified.split(symbols.metadata_separator).collect();
+    // `@?a.relpath.b` is the path from fragment a's file to fragment b's. No other property takes a
+    // second fragment.
+    if let [from_id, prop, to_id] = pieces[..] {
+        let error = |err_type, col, message| FileError {
+            err_type,
+            filename: filename.to_owned(),
+            line,
+            col,
+            message: Some(message),
+        };
+        if namespace.is_some() || !prop.eq_ignore_ascii_case(REL_PATH_REF) || from_id.is_empty() {
+            return Err(error(
+                WeaveError::BadMetadata(word.to_owned()),
+                id_col,
+                format!(
+                    "malformed property lookup '{}': only '{}' takes a second fragment, as in \
+                     'a.{}.b'",
+                    word, REL_PATH_REF, REL_PATH_REF
+                ),
+            ));
+        }
+        let lookup = |id: &str, col| {
+            find_fragment(annotations, id).ok_or_else(|| {
+                error(
+                    WeaveError::MissingFragment(id.to_owned()),
+                    col,
+                    format!("unknown fragment '{}'", id),
+                )
+            })
+        };
+        let from = lookup(from_id, id_col)?;
+        let to_col = id_col + from_id.chars().count() + prop.chars().count() + 2;
+        let to = lookup(to_id, to_col)?;
+        let rel_path = find_relative_path(Path::new(&from.file), Path::new(&to.file));
+        return Ok(posix_path(&rel_path.to_string_lossy()));
+    }
+    if pieces.len() == 2 && !pieces[0].contains(NAMESPACE_SEPARATOR) {
+        let frag_id = pieces[0];
+        let prop = pieces[1];
+        // A qualified reference resolves against the fragment with that ID from that file, rather
+        // than every fragment which shares the ID.
+        let scoped = match namespace {
+            Some(ns) => Some(
+                annotations
+                    .get(frag_id)
+                    .into_iter()
+                    .flatten()
+                    .find(|f| f.file == ns)
+                    .ok_or_else(|| FileError {
+                        err_type: WeaveError::MissingFragment(word.to_owned()),
+                        filename: filename.to_owned(),
+                        line,
+                        col: id_col,
+                        message: Some(format!("no fragment '{}' in '{}'", frag_id, ns)),
+                    })?,
+            ),
+            None => None,
+        };
+        let properties = &options.properties;
+        let resolved = if let Some(f) = scoped {
+            properties.resolve_fragment(f, prop, annotations, filename, symbols, options)
+        } else if frag_id == GLOBAL_METADATA_ID {
+            options
+                .globals
+                .get(&prop.to_ascii_lowercase())
+                .cloned()
+                .ok_or_else(|| WeaveError::UnknownProperty(prop.to_owned()))
+        } else {
+            properties.resolve(frag_id, prop, annotations, filename, symbols, options)
+        };
+        resolved.map_err(|err_type| match &err_type {
+            WeaveError::UnknownProperty(_) => FileError {
+                err_type,
+                filename: filename.to_owned(),
+                line,
+                col: id_col + frag_id.chars().count() + 1,
+                message: Some(format!("unknown metadata type '{}'", prop)),
+            },
+            WeaveError::BadMetadata(arg) => FileError {
+                filename: filename.to_owned(),
+                line,
+                col: id_col + frag_id.chars().count() + 1,
+                message: Some(format!("bad argument '{}' for property '{}'", arg, prop)),
+                err_type,
+            },
+            _ => FileError {
+                err_type,
+                filename: filename.to_owned(),
+                line,
+                col: id_col,
+                message: Some(format!("unknown fragment '{}'", frag_id)),
+            },
+        })
+    } else {
+        // TODO Make these errors more granular.
+        Err(FileError {
+            err_type: WeaveError::BadMetadata(word.to_owned()),
+            filename: filename.to_owned(),
+            line,
+            col,
+            message: Some(format!("malformed property lookup '{}'", word)),
+        })
+    }
+}
+
+// Splits the namespace off of a metadata reference like `src/main.rs::id.prop`, if it has one. The
+// namespace separator takes precedence: the namespace runs up to the first `::`, so it may contain
+// `.`, and what follows is read as an unqualified `id.prop`.
+fn split_namespace(word: &str) -> (Option<&str>, &str) {
+    match word.split_once(NAMESPACE_SEPARATOR) {
+        Some((namespace, rest)) => (Some(namespace), rest),
+        None => (None, word),
+    }
+}
+
+// Splits a property like `snippet(3)` into its name and parenthesized argument, if it has one.
+fn split_property_arg(prop: &str) -> (&str, Option<&str>) {
+    match prop.find('(') {
+        Some(open) => match prop[open + 1..].strip_suffix(')') {
+            Some(arg) => (&prop[..open], Some(arg)),
+            None => (prop, None),
+        },
+        None => (prop, None),
+    }
+}
+
+// The first `lines` lines of a fragment's body, followed by `ellipsis` if any were left out.
+fn resolve_snippet(f: &Fragment, lines: &str, ellipsis: &str) -> Result<String, WeaveError> {
+    let lines: usize = lines
+        .trim()
+        .parse()
+        .map_err(|_| WeaveError::BadMetadata(lines.to_owned()))?;
+
+    let mut snippet = f.body.lines().take(lines).collect::<Vec<&str>>().join("\n");
+    if f.body.lines().count() > lines {
+        snippet.push_str(ellipsis);
+    }
+    Ok(snippet)
+}
+
+// The ID of the fragment numbered `index`, as a neighbour of `f`. There is none before the first
+// fragment or after the last, nor for fragments without an index, and these give an empty string.
+fn neighbour_id(annotations: &Annotations, f: &Fragment, index: usize) -> String {
+    annotations
+        .values()
+        .flatten()
+        .find(|g| f.index != 0 && index != 0 && g.index == index)
+        .map(|g| g.id.to_owned())
+        .unwrap_or_default()
+}
+
+// Everything a property resolver can see besides the fragment itself.
+pub struct PropertyContext<'a> {
+    pub annotations: &'a Annotations,
+    // The prose file being woven.
+    pub filename: &'a str,
+    pub symbols: &'a SymbolKey,
+    pub options: &'a WeaveOptions,
+    // The parenthesized argument of the property, as in `@?id.snippet(3)`.
+    pub arg: Option<&'a str>,
+}
+
+pub type PropertyResolver =
+    Arc<dyn Fn(&Fragment, &PropertyContext) -> Result<String, WeaveError> + Send + Sync>;
+
+// Maps metadata property names to the functions which resolve them. The default registry holds the
+// built-in properties; library users may register their own, or replace the built-ins, before
+// weaving with it in `WeaveOptions`.
+#[derive(Clone)]
+pub struct PropertyRegistry {
+    resolvers: BTreeMap<String, PropertyResolver>,
+    // The properties which take a parenthesized argument. Any other is an error with one.
+    with_arg: BTreeSet<String>,
+}
+
+impl PropertyRegistry {
+    // A registry without any properties, not even the built-in ones.
+    pub fn empty() -> PropertyRegistry {
+        PropertyRegistry {
+            resolvers: BTreeMap::new(),
+            with_arg: BTreeSet::new(),
+        }
+    }
+
+    // Registers `resolver` for `@?id.name`, which takes no argument. Property names are
+    // case-insensitive.
+    pub fn register<F>(&mut self, name: &str, resolver: F)
+    where
+        F: Fn(&Fragment, &PropertyContext) -> Result<String, WeaveError> + Send + Sync + 'static,
+    {
+        self.with_arg.remove(&name.to_ascii_lowercase());
+        self.resolvers
+            .insert(name.to_ascii_lowercase(), Arc::new(resolver));
+    }
+
+    // Registers `resolver` for `@?id.name(arg)`, which finds the argument in its context.
+    pub fn register_with_arg<F>(&mut self, name: &str, resolver: F)
+    where
+        F: Fn(&Fragment, &PropertyContext) -> Result<String, WeaveError> + Send + Sync + 'static,
+    {
+        self.register(name, resolver);
+        self.with_arg.insert(name.to_ascii_lowercase());
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.resolvers.keys().map(String::as_str)
+    }
+
+    // Resolves `prop`, which may carry an argument, for the fragment `id`.
+    pub fn resolve(
+        &self,
+        id: &str,
+        prop: &str,
+        annotations: &Annotations,
+        filename: &str,
+        symbols: &SymbolKey,
+        options: &WeaveOptions,
+    ) -> Result<String, WeaveError> {
+        let f = find_fragment(annotations, id)
+            .ok_or_else(|| WeaveError::MissingFragment(id.to_owned()))?;
+        self.resolve_fragment(&f, prop, annotations, filename, symbols, options)
+    }
+
+    // Resolves `prop` for `f` itself, such as the fragment a namespaced reference picks out from
+    // those which share its ID.
+    pub fn resolve_fragment(
+        &self,
+        f: &Fragment,
+        prop: &str,
+        annotations: &Annotations,
+        filename: &str,
+        symbols: &SymbolKey,
+        options: &WeaveOptions,
+    ) -> Result<String, WeaveError> {
+        let (name, arg) = split_property_arg(prop);
+        let resolver = self
+            .resolvers
+            .get(&name.to_ascii_lowercase())
+            .ok_or_else(|| WeaveError::UnknownProperty(prop.to_owned()))?;
+        if let Some(arg) = arg.filter(|_| !self.with_arg.contains(&name.to_ascii_lowercase())) {
+            return Err(WeaveError::BadMetadata(arg.to_owned()));
+        }
+        let context = PropertyContext {
+            annotations,
+            filename,
+            symbols,
+            options,
+            arg,
+        };
+        resolver(f, &context)
+    }
+}
+
+impl Default for PropertyRegistry {
+    fn default() -> PropertyRegistry {
+        let mut registry = PropertyRegistry::empty();
+        registry.register(FILENAME_REF, |f, _| Ok(f.file.to_owned()));
+        registry.register(LINE_NO_REF, |f, _| Ok(f.line.to_string()));
+        registry.register(END_LINE_REF, |f, _| {
+            // Without a recorded end, assume the body wasn't trimmed.
+            let end_line = match f.end_line {
+                0 => f.line + f.body.split('\n').count() - 1,
+                end_line => end_line,
+            };
+            Ok(end_line.to_string())
+        });
+        registry.register(COL_NO_REF, |f, _| Ok(f.col.to_string()));
+        registry.register(LINES_REF, |f, _| Ok(f.body.lines().count().to_string()));
+        registry.register(CHARS_REF, |f, _| Ok(f.body.chars().count().to_string()));
+        registry.register(LANG_REF, |f, cx| Ok(language_name(&f.file, cx.options)));
+        registry.register(INDEX_REF, |f, _| Ok(f.index.to_string()));
+        registry.register(NEXT_REF, |f, cx| {
+            Ok(neighbour_id(cx.annotations, f, f.index + 1))
+        });
+        registry.register(PREV_REF, |f, cx| {
+            Ok(neighbour_id(cx.annotations, f, f.index.saturating_sub(1)))
+        });
+        registry.register(LOC_REF, |f, _| {
+            Ok(format!("{} ({}:{})", f.file, f.line, f.col))
+        });
+        registry.register(ABS_PATH_REF, |f, cx| cx.options.abspath_root.join(&f.file));
+        registry.register(REL_PATH_REF, |f, cx| {
+            let from_path = std::path::Path::new(cx.filename);
+            let to_path = std::path::Path::new(&f.file);
+            let rel_path = find_relative_path(from_path, to_path);
+            Ok(posix_path(&rel_path.to_string_lossy()))
+        });
+        registry.register_with_arg(SNIPPET_REF, |f, cx| {
+            let ellipsis = cx.options.snippet_ellipsis.as_deref();
+            resolve_snippet(
+                f,
+                cx.arg.unwrap_or_default(),
+                ellipsis.unwrap_or(SNIPPET_ELLIPSIS),
+            )
+        });
+        registry
+    }
+}
+
+// Resolvers can't be compared or printed, so registries are identified by their property names.
+impl fmt::Debug for PropertyRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_set().entries(self.names()).finish()
+    }
+}
+
+impl PartialEq for PropertyRegistry {
+    fn eq(&self, other: &PropertyRegistry) -> bool {
+        self.names().eq(other.names())
+    }
+}
+
+impl Eq for PropertyRegistry {}
+
+// Look up a single metadata property of a fragment, as `@?id.prop` would in a prose file named
+// `filename` woven with `options`. This is the same resolution `weave` performs, minus the parsing
+// and error locations.
+pub fn resolve_metadata(
+    id: &str,
+    prop: &str,
+    annotations: &Annotations,
+    filename: &str,
+    symbols: &SymbolKey,
+    options: &WeaveOptions,
+) -> Result<String, WeaveError> {
+    options
+        .properties
+        .resolve(id, prop, annotations, filename, symbols, options)
+}
+
+const FRONT_MATTER_DELIMITER: &str = "---";
+
+// Split a leading `---`-delimited front matter block off of a prose file. On success, returns the
+// text between the delimiters and the remainder of the file following the closing delimiter.
+pub fn split_front_matter(contents: &str) -> Option<(&str, &str)> {
+    let mut lines = contents.split_inclusive('\n');
+    let first = lines.next()?;
+    if first.trim_end() != FRONT_MATTER_DELIMITER {
+        return None;
+    }
+
+    let start = first.len();
+    let mut offset = start;
+    for line in lines {
+        if line.trim_end() == FRONT_MATTER_DELIMITER {
+            return Some((&contents[start..offset], &contents[offset + line.len()..]));
+        }
+        offset += line.len();
+    }
+
+    None
+}
+
+// Front matter is shared with other tools, so verso's settings are namespaced under this key.
+#[cfg(feature = "frontmatter")]
+const FRONT_MATTER_KEY: &str = "verso";
+
+// Read symbol overrides from the `verso` table in a block of YAML front matter and apply them on top
+// of the given symbols. Front matter without a `verso` table leaves the symbols unchanged.
+#[cfg(feature = "frontmatter")]
+pub fn symbols_from_front_matter(
+    front_matter: &str,
+    symbols: &SymbolKey,
+) -> Result<SymbolKey, Box<dyn Error>> {
+    let document: serde_yaml::Value = serde_yaml::from_str(front_matter)?;
+    match document.get(FRONT_MATTER_KEY) {
+        Some(settings) => {
+            let overrides: SymbolOverrides = serde_yaml::from_value(settings.clone())?;
+            Ok(symbols.with_overrides(&overrides)?)
+        }
+        None => Ok(symbols.clone()),
+    }
+}
+
+// The key in the `verso` table of front matter which sets the root for `@?id.abspath`.
+#[cfg(feature = "frontmatter")]
+const FRONT_MATTER_ROOT_KEY: &str = "root";
+
+// Read the root for `@?id.abspath` from the `verso` table in a block of YAML front matter, as
+// `recto --url-base` gives it for every file. Front matter without one gives `None`.
+#[cfg(feature = "frontmatter")]
+pub fn abspath_root_from_front_matter(
+    front_matter: &str,
+) -> Result<Option<AbsPathRoot>, serde_yaml::Error> {
+    let document: serde_yaml::Value = serde_yaml::from_str(front_matter)?;
+    match document
+        .get(FRONT_MATTER_KEY)
+        .and_then(|settings| settings.get(FRONT_MATTER_ROOT_KEY))
+    {
+        Some(root) => Ok(Some(AbsPathRoot::Base(serde_yaml::from_value(
+            root.clone(),
+        )?))),
+        None => Ok(None),
+    }
+}
+
+// Reduce an ID to its "shape" by trimming separators from both ends and replacing each run of them
+// with a single `-`, whichever separators it holds. A separator is any character `symbols` allows
+// in IDs besides letters and digits. IDs like `foo`, `foo-`, and `foo//` all normalize to `foo`,
+// and `foo/_bar` and `foo_/bar` both to `foo-bar`.
+pub fn normalize_id(id: &str, symbols: &SymbolKey) -> String {
+    let is_separator = |c: char| !c.is_alphanumeric() && symbols.is_id_char(c);
+    let mut normalized = String::with_capacity(id.len());
+    let mut in_separator_run = false;
+    for c in id.trim_matches(is_separator).chars() {
+        if is_separator(c) {
+            if !in_separator_run {
+                normalized.push('-');
+            }
+            in_separator_run = true;
+        } else {
+            normalized.push(c);
+            in_separator_run = false;
+        }
+    }
+    normalized
+}
+
+// Find groups of fragments whose IDs are distinct but normalize to the same value. These are easy to
+// create by accident and almost never intended, so callers should warn about them.
+pub fn find_similar_ids<'a>(
+    fragments: &'a [Fragment],
+    symbols: &SymbolKey,
+) -> Vec<Vec<&'a Fragment>> {
+    let mut groups: BTreeMap<String, Vec<&Fragment>> = BTreeMap::new();
+    for fragment in fragments {
+        groups
+            .entry(normalize_id(&fragment.id, symbols))
+            .or_default()
+            .push(fragment);
+    }
+
+    groups
+        .into_values()
+        .filter(|group| group.iter().any(|f| f.id != group[0].id))
+        .collect()
+}
+
+// Put fragments in the order they appear in their sources: by file, then line, then column. Nested
+// fragments are extracted when they close, before their parents; this puts the parents first. The
+// sort is stable, so fragments at the same place keep their order.
+pub fn sort_by_location(fragments: &mut [Fragment]) {
+    fragments.sort_by(|a, b| (&a.file, a.marker_line, a.col).cmp(&(&b.file, b.marker_line, b.col)));
+}
+
+// Number `fragments` from `first`, in the order given. Numbering them as `extract_fragments` returns
+// them, in the order they close, keeps the numbers the same however they are later sorted.
+pub fn number_fragments(fragments: &mut [Fragment], first: usize) {
+    for (i, f) in fragments.iter_mut().enumerate() {
+        f.index = first + i;
+    }
+}
+
+// The bodies of the fragments with each of `ids`, in that order, joined by `delimiter`, as printed
+// by `verso --fragment`. Where several fragments share an ID, the first is used. Fails with the
+// first ID which no fragment has.
+pub fn select_bodies<'a>(
+    fragments: &[Fragment],
+    ids: &'a [String],
+    delimiter: &str,
+) -> Result<String, &'a str> {
+    let mut bodies = vec![];
+    for id in ids {
+        match fragments.iter().find(|f| &f.id == id) {
+            Some(f) => bodies.push(f.body.as_str()),
+            None => return Err(id),
+        }
+    }
+    Ok(bodies.join(delimiter))
+}
+
+// Find groups of fragments which share an ID, in the order they were given. `recto` keeps only one
+// fragment per ID, so all but one of each group would be lost.
+pub fn find_duplicate_ids(fragments: &[Fragment]) -> Vec<Vec<&Fragment>> {
+    let mut groups: BTreeMap<&str, Vec<&Fragment>> = BTreeMap::new();
+    for fragment in fragments {
+        groups.entry(&fragment.id).or_default().push(fragment);
+    }
+
+    groups
+        .into_values()
+        .filter(|group| group.len() > 1)
+        .collect()
+}
+
+// Where a fragment was defined, as `file:line`, with the line of its open symbol.
+pub fn fragment_location(f: &Fragment) -> String {
+    format!("{}:{}", f.file, f.open_line())
+}
+
+// The error for a group of fragments which share an ID, as found by `find_duplicate_ids`, naming
+// where each was defined. They would be joined in an order which depends on the order of the files.
+pub fn duplicate_id_message(group: &[&Fragment]) -> String {
+    let locations: Vec<String> = group.iter().map(|f| fragment_location(f)).collect();
+    format!(
+        "fragment '{}' is defined more than once, at {}",
+        group[0].id,
+        locations.join(", ")
+    )
+}
+
+// Weave `prose` with the fragments from each of `sources`, given as `(filename, contents)` pairs,
+// without touching the filesystem. Fragments are numbered as `verso` would, in the order of the
+// sources, and as in `verso` an ID defined in more than one source is an error.
+pub fn process(
+    sources: &[(&str, &str)],
+    prose_name: &str,
+    prose: &str,
+    symbols: &SymbolKey,
+) -> Result<String, Box<dyn Error>> {
+    let mut fragments = Vec::new();
+    for (filename, contents) in sources {
+        let mut extracted = extract_fragments(contents, filename, symbols)?;
+        number_fragments(&mut extracted, fragments.len() + 1);
+        fragments.append(&mut extracted);
+    }
+
+    if let Some(group) = find_duplicate_ids(&fragments).first() {
+        return Err(duplicate_id_message(group).into());
+    }
+
+    let annotations: Annotations = fragments
+        .into_iter()
+        .map(|f| (f.id.to_owned(), vec![f]))
+        .collect();
+    Ok(weave(prose_name, prose, &annotations, symbols)?)
+}
+
+// The differences between two sets of fragments, such as verso's output at two commits. Each list
+// holds fragment IDs, in order.
+#[derive(Debug, Default, PartialEq, Eq, Clone)]
+pub struct FragmentDiff {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    // The body is different.
+    pub changed: Vec<String>,
+    // The body is the same, but it now comes from a different file.
+    pub moved: Vec<String>,
+}
+
+impl FragmentDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.removed.is_empty()
+            && self.changed.is_empty()
+            && self.moved.is_empty()
+    }
+}
+
+pub fn diff_fragments(old: &Annotations, new: &Annotations) -> FragmentDiff {
+    let mut diff = FragmentDiff::default();
+    for id in old.keys() {
+        let old_fragment = find_fragment(old, id).unwrap_or_default();
+        match find_fragment(new, id) {
+            None => diff.removed.push(id.to_owned()),
+            Some(f) if f.body != old_fragment.body => diff.changed.push(id.to_owned()),
+            Some(f) if f.file != old_fragment.file => diff.moved.push(id.to_owned()),
+            Some(_) => {}
+        }
+    }
+    diff.added = new
+        .keys()
+        .filter(|id| !old.contains_key(*id))
+        .cloned()
+        .collect();
+    diff
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReferenceKind {
+    Insertion,
+    Pattern,
+    Metadata,
+}
+
+// A place in a prose file which refers to one or more fragments. For insertions and metadata the
+// target is a fragment ID; for patterns it is the regular expression as written.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Reference {
+    pub kind: ReferenceKind,
+    pub target: String,
+    pub file: String,
+    pub line: usize,
+    // Whether an insertion renders `!withheader`, and so needs the fragments enclosing its target.
+    pub with_header: bool,
+}
+
+// Find all of the fragment references in a prose file without weaving it. Malformed references are
+// skipped, since weaving the file will report them with more context.
+pub fn find_references(filename: &str, contents: &str, symbols: &SymbolKey) -> Vec<Reference> {
+    find_references_with_options(filename, contents, symbols, &WeaveOptions::default())
+}
+
+// As `find_references`, but in the reference syntax that `options` gives.
+pub fn find_references_with_options(
+    filename: &str,
+    contents: &str,
+    symbols: &SymbolKey,
+    options: &WeaveOptions,
+) -> Vec<Reference> {
+    if options.syntax == Syntax::Mustache {
+        return find_mustache_references(filename, contents, symbols);
+    }
+    let mut references = vec![];
+    let mut push = |kind, target: String, line, with_header| {
+        references.push(Reference {
+            kind,
+            target,
+            file: filename.to_owned(),
+            line,
+            with_header,
+        })
+    };
+
+    for (line_no, line) in contents.lines().enumerate().map(|(l, c)| (l + 1, c)) {
+        let trimmed = line.trim_start();
+        if strip_escaped_directive(trimmed, symbols).is_some() {
+            continue;
+        } else if let Some((symbol, _)) = symbols.match_insertion(trimmed) {
+            // Conditional insertions are references whether or not their condition holds.
+            let unconditional =
+                split_condition(trimmed, symbol, symbols).map(|(_, _, insertion)| insertion);
+            let trimmed = unconditional.as_deref().unwrap_or(trimmed);
+            let (directive, render_options) =
+                extract_render_options(trimmed, symbol.chars().count())
+                    .unwrap_or((trimmed, vec![]));
+            let (directive, _) = extract_line_range(directive, symbol.chars().count());
+            let col = symbol.chars().count();
+            if let Ok(id) = extract_id_with_symbols(directive, col, symbols) {
+                let with_header = render_options.contains(&RenderOption::WithHeader);
+                push(ReferenceKind::Insertion, id, line_no, with_header);
+            }
+        } else if trimmed.starts_with(&symbols.pattern) {
+            let (trimmed, _) =
+                split_pattern_options(trimmed).unwrap_or((trimmed, PatternOptions::default()));
+            if extract_pattern(trimmed, symbols.pattern.chars().count()).is_ok() {
+                let pattern = trimmed[symbols.pattern.len()..].trim();
+                push(ReferenceKind::Pattern, pattern.to_owned(), line_no, false);
+            }
+        } else if trimmed.starts_with(&symbols.toc) {
+            // A table of contents refers to its fragments just as a pattern does.
+            if extract_pattern(trimmed, symbols.toc.chars().count()).is_ok() {
+                let pattern = trimmed[symbols.toc.len()..].trim();
+                push(ReferenceKind::Pattern, pattern.to_owned(), line_no, false);
+            }
+        } else {
+            let ref_len = |s: &str| metadata_ref_len(s, symbols);
+            let scanner = Scanner::new(line, &[symbols.metadata.as_str()], &ref_len);
+            for token in scanner.filter(|t| !line[..t.start].ends_with(ESCAPE_CHAR)) {
+                let word = token.word_after(&symbols.metadata).unwrap_or_default();
+                for id in metadata_reference_ids(word, symbols) {
+                    push(ReferenceKind::Metadata, id.to_owned(), line_no, false);
+                }
+            }
+        }
+    }
+
+    references
+}
+
+// The fragments which a metadata reference such as `id.prop` uses. The third piece of `a.relpath.b`
+// is another fragment.
+fn metadata_reference_ids<'a>(word: &'a str, symbols: &SymbolKey) -> Vec<&'a str> {
+    let pieces: Vec<&str> = split_namespace(word)
+        .1
+        .split(symbols.metadata_separator)
+        .collect();
+    [pieces.first(), pieces.get(2)]
+        .iter()
+        .flatten()
+        .map(|id| **id)
+        .filter(|id| !id.is_empty() && *id != GLOBAL_METADATA_ID)
+        .collect()
+}
+
+// Find the references in mustache tags, such as `{{verso:id}}` and `{{verso:id.file}}`. Unterminated
+// tags are skipped, as malformed references are.
+fn find_mustache_references(filename: &str, contents: &str, symbols: &SymbolKey) -> Vec<Reference> {
+    let mut references = vec![];
+    for (line_no, line) in contents.lines().enumerate().map(|(l, c)| (l + 1, c)) {
+        let mut rest = line;
+        while let Some((_, after)) = rest.split_once(MUSTACHE_OPEN) {
+            let (tag, after) = match after.split_once(MUSTACHE_CLOSE) {
+                Some(split) => split,
+                None => break,
+            };
+            rest = after;
+            let reference = |kind, target: &str, with_header| Reference {
+                kind,
+                target: target.to_owned(),
+                file: filename.to_owned(),
+                line: line_no,
+                with_header,
+            };
+            if tag.contains(symbols.metadata_separator) {
+                for id in metadata_reference_ids(tag.trim(), symbols) {
+                    references.push(reference(ReferenceKind::Metadata, id, false));
+                }
+            } else {
+                let (directive, render_options) =
+                    extract_render_options(tag, 0).unwrap_or((tag, vec![]));
+                let (directive, _) = extract_line_range(directive, 0);
+                if let Ok(id) = extract_id_with_symbols(directive, 0, symbols) {
+                    let with_header = render_options.contains(&RenderOption::WithHeader);
+                    references.push(reference(ReferenceKind::Insertion, &id, with_header));
+                }
+            }
+        }
+    }
+    references
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct AuditReport<'a> {
+    // Fragments which no reference in the prose uses.
+    pub unreferenced: Vec<&'a Fragment>,
+    // References which do not resolve to any fragment.
+    pub undefined: Vec<Reference>,
+}
+
+impl AuditReport<'_> {
+    pub fn is_clean(&self) -> bool {
+        self.unreferenced.is_empty() && self.undefined.is_empty()
+    }
+}
+
+// The two lists of an audit, as `verso --audit` and `recto --audit` print them.
+impl fmt::Display for AuditReport<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Fragments defined but never referenced:")?;
+        for frag in &self.unreferenced {
+            write!(f, "\n    {} ({}:{})", frag.id, frag.file, frag.line)?;
+        }
+        write!(f, "\nReferences to fragments that don't exist:")?;
+        for r in &self.undefined {
+            write!(f, "\n    {} ({}:{})", r.target, r.file, r.line)?;
+        }
+        Ok(())
+    }
+}
+
+// Find the metadata references which name a fragment that doesn't exist. Weaving stops at the first
+// of these; this finds all of them in one pass.
+pub fn find_undefined_metadata<'a>(
+    annotations: &Annotations,
+    references: &'a [Reference],
+) -> Vec<&'a Reference> {
+    references
+        .iter()
+        .filter(|r| r.kind == ReferenceKind::Metadata && !annotations.contains_key(&r.target))
+        .collect()
+}
+
+// Find the references to deprecated fragments, paired with the fragment each one uses. A pattern
+// which matches several deprecated fragments is listed once for each of their IDs, and where
+// several deprecated fragments share an ID, only the first is given. Invalid patterns are skipped.
+pub fn find_deprecated_references<'a>(
+    annotations: &'a Annotations,
+    references: &'a [Reference],
+) -> Vec<(&'a Reference, &'a Fragment)> {
+    let mut deprecated = vec![];
+    for reference in references {
+        match reference.kind {
+            ReferenceKind::Insertion | ReferenceKind::Metadata => {
+                for f in annotations.get(&reference.target).into_iter().flatten() {
+                    deprecated.push((reference, f));
+                }
+            }
+            ReferenceKind::Pattern => {
+                if let Ok(re) = Regex::new(&reference.target) {
+                    for f in annotations
+                        .values()
+                        .flatten()
+                        .filter(|f| re.is_match(&f.id))
+                    {
+                        deprecated.push((reference, f));
+                    }
+                }
+            }
+        }
+    }
+    deprecated.retain(|(_, f)| f.deprecated.is_some());
+    // Each reference's fragments are listed together, and grouped by ID.
+    deprecated.dedup_by(|(r, f), (prev_r, prev_f)| std::ptr::eq(*r, *prev_r) && f.id == prev_f.id);
+    deprecated
+}
+
+// Compare the fragments defined in the sources against the references made by the prose.
+pub fn audit<'a>(annotations: &'a Annotations, references: &[Reference]) -> AuditReport<'a> {
+    let mut used: std::collections::BTreeSet<&str> = std::collections::BTreeSet::new();
+    let mut undefined = vec![];
+
+    for reference in references {
+        match reference.kind {
+            ReferenceKind::Insertion | ReferenceKind::Metadata => {
+                match annotations.get_key_value(&reference.target) {
+                    Some((id, _)) => {
+                        used.insert(id);
+                    }
+                    None => undefined.push(reference.clone()),
+                }
+            }
+            ReferenceKind::Pattern => {
+                // Patterns were validated when the references were collected.
+                let re = Regex::new(&reference.target).expect("Expected a valid pattern");
+                let mut matched = false;
+                for id in annotations.keys().filter(|id| re.is_match(id)) {
+                    used.insert(id);
+                    matched = true;
+                }
+                if !matched {
+                    undefined.push(reference.clone());
+                }
+            }
+        }
+    }
+
+    AuditReport {
+        unreferenced: annotations
+            .iter()
+            .filter(|(id, _)| !used.contains(id.as_str()))
+            .flat_map(|(_, all)| all)
+            .collect(),
+        undefined,
+    }
+}
+
+// Write fragments as `verso` prints them: a JSON array, or with `jsonl`, JSON Lines with one
+// fragment on each.
+pub fn write_fragments<T: Serialize>(
+    mut out: impl io::Write,
+    fragments: &[T],
+    jsonl: bool,
+) -> io::Result<()> {
+    if jsonl {
+        for f in fragments {
+            serde_json::to_writer(&mut out, f)?;
+            writeln!(out)?;
+        }
+    } else {
+        serde_json::to_writer(&mut out, fragments)?;
+    }
+    out.flush()
+}
+
+// As `write_fragments`, to the file at `path`, as `verso -o` does. The file is written in full
+// before it replaces the old one, so that a reader such as `recto --watch` never sees it
+// half-written.
+pub fn write_fragments_file<T: Serialize>(
+    path: &str,
+    fragments: &[T],
+    jsonl: bool,
+) -> io::Result<()> {
+    let partial = format!("{}.partial", path);
+    write_fragments(
+        io::BufWriter::new(File::create(&partial)?),
+        fragments,
+        jsonl,
+    )?;
+    std::fs::rename(partial, path)
+}
+
+// Fragments as they are read, one at a time.
+pub type FragmentReader<'a> = Box<dyn Iterator<Item = Result<Fragment, Box<dyn Error>>> + 'a>;
+
+// Read fragments as `verso` writes them: a JSON array, read in full, or with `jsonl`, JSON Lines,
+// read as they are needed.
+pub fn read_fragments<'a>(
+    reader: impl BufRead + 'a,
+    jsonl: bool,
+) -> Result<FragmentReader<'a>, Box<dyn Error>> {
+    if jsonl {
+        return Ok(Box::new(read_jsonl(reader)));
+    }
+    let fragments: Vec<Fragment> = serde_json::from_reader(reader)?;
+    Ok(Box::new(fragments.into_iter().map(Ok)))
+}
+
+// As `read_fragments`, from the file at `path`, as `recto --fragments` reads it.
+pub fn read_fragments_file(
+    path: &str,
+    jsonl: bool,
+) -> Result<FragmentReader<'static>, Box<dyn Error>> {
+    let file = File::open(path).map_err(|e| format!("Error: ({}) {}", path, e))?;
+    read_fragments(io::BufReader::new(file), jsonl)
+        .map_err(|e| format!("Error: ({}) {}", path, e).into())
+}
+
+// The fragments from several readers, one after another, as `recto` reads repeated `--fragments`
+// files. Each file was numbered on its own, so the indices would clash; if there is more than one
+// reader, the fragments are numbered afresh in the order they are read.
+pub fn combine_fragment_readers<'a>(readers: Vec<FragmentReader<'a>>) -> FragmentReader<'a> {
+    let renumber = readers.len() > 1;
+    Box::new(readers.into_iter().flatten().map(move |f| {
+        f.map(|f| {
+            if renumber {
+                Fragment { index: 0, ..f }
+            } else {
+                f
+            }
+        })
+    }))
+}
+
+// Read fragments written one per line by `verso --jsonl`, as they are needed. Blank lines are
+// skipped.
+pub fn read_jsonl(reader: impl BufRead) -> impl Iterator<Item = Result<Fragment, Box<dyn Error>>> {
+    reader
+        .lines()
+        .filter(|line| line.as_ref().map_or(true, |l| !l.trim().is_empty()))
+        .map(|line| Ok(serde_json::from_str(&line?)?))
+}
+
+// The files under `dir`, in a stable order, as `verso` reads a directory. Hidden files are skipped,
+// as are those matched by `.gitignore` or `.ignore` files unless `no_ignore` is set.
+pub fn walk_dir(dir: &str, no_ignore: bool) -> Result<Vec<String>, ignore::Error> {
+    let mut files = vec![];
+    let walk = WalkBuilder::new(dir)
+        .standard_filters(!no_ignore)
+        .hidden(true)
+        .require_git(false)
+        .sort_by_file_name(|a, b| a.cmp(b))
+        .build();
+    for entry in walk {
+        let entry = entry?;
+        if entry.file_type().is_some_and(|t| t.is_file()) {
+            files.push(entry.path().to_string_lossy().into_owned());
+        }
+    }
+    Ok(files)
+}
+
+// Read the fragments from every `*.json` file in `dir`, as written by `verso`. Files are read in
+// order of their names, so that the result doesn't depend on the order the OS lists them in.
+pub fn read_fragments_dir(dir: &Path) -> Result<Vec<Fragment>, Box<dyn Error>> {
+    let mut paths = vec![];
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_file() && path.extension().is_some_and(|e| e == "json") {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+
+    let mut fragments = vec![];
+    for path in paths {
+        let contents = std::fs::read_to_string(&path)?;
+        let mut file_fragments: Vec<Fragment> = serde_json::from_str(&contents).map_err(|e| {
+            format!(
+                "Error: could not read fragments from {}: {}",
+                path.display(),
+                e
+            )
+        })?;
+        fragments.append(&mut file_fragments);
+    }
+    Ok(fragments)
+}
+
+// The files `recto --watch` watches: the prose files it weaves, and the fragment files and
+// directories it reads. Paths are made canonical, to compare with those in file system events.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct WatchTargets {
+    // Each prose file as it was named, and its canonical path.
+    pub prose: Vec<(String, PathBuf)>,
+    pub sources: Vec<PathBuf>,
+}
+
+impl WatchTargets {
+    pub fn new(prose: &[String], sources: &[String]) -> io::Result<WatchTargets> {
+        Ok(WatchTargets {
+            prose: prose
+                .iter()
+                .map(|f| Ok((f.to_owned(), std::fs::canonicalize(f)?)))
+                .collect::<io::Result<_>>()?,
+            sources: sources
+                .iter()
+                .map(std::fs::canonicalize)
+                .collect::<io::Result<_>>()?,
+        })
+    }
+
+    // The paths to watch, and whether to watch each recursively. Editors often save a file by
+    // replacing it, which ends a watch on the file itself, so the directories which hold the files
+    // are watched instead. Source directories are watched whole.
+    pub fn watches(&self) -> Vec<(PathBuf, bool)> {
+        let mut dirs: BTreeSet<&Path> = self.prose.iter().filter_map(|(_, p)| p.parent()).collect();
+        let mut watches = vec![];
+        for source in &self.sources {
+            if source.is_dir() {
+                watches.push((source.to_owned(), true));
+            } else {
+                dirs.extend(source.parent());
+            }
+        }
+        watches.extend(dirs.into_iter().map(|dir| (dir.to_owned(), false)));
+        watches
+    }
+
+    // Whether any of the `changed` paths is a fragment source, so that the fragments must be read
+    // again.
+    pub fn reloads(&self, changed: &[PathBuf]) -> bool {
+        changed
+            .iter()
+            .any(|p| self.sources.iter().any(|source| p.starts_with(source)))
+    }
+
+    // The prose files to weave again after the `changed` paths: those which changed, or all of them
+    // if the fragments were `reloaded`. If any is, `all` asks for every file, as a report does.
+    pub fn to_weave(&self, changed: &[PathBuf], reloaded: bool, all: bool) -> Vec<String> {
+        let any = reloaded || self.prose.iter().any(|(_, path)| changed.contains(path));
+        self.prose
+            .iter()
+            .filter(|(_, path)| any && (reloaded || all || changed.contains(path)))
+            .map(|(filename, _)| filename.to_owned())
+            .collect()
+    }
+}
+
+// A collection of fragments which can be queried by ID or pattern, without necessarily holding all
+// of them in memory.
+pub trait FragmentStore {
+    // Every fragment with the ID `id`, in the order `verso` emitted them.
+    fn get(&self, id: &str) -> Result<Vec<Fragment>, Box<dyn Error>>;
+    // Every fragment whose ID matches `pattern`, in order of their IDs.
+    fn matching(&self, pattern: &Regex) -> Result<Vec<Fragment>, Box<dyn Error>>;
+    // The fragment numbered `index` by `verso`, if there is one.
+    fn by_index(&self, index: usize) -> Result<Option<Fragment>, Box<dyn Error>>;
+}
+
+// The usual store: the JSON written by `verso`, read into memory in full.
+impl FragmentStore for Annotations {
+    fn get(&self, id: &str) -> Result<Vec<Fragment>, Box<dyn Error>> {
+        Ok(BTreeMap::get(self, id).cloned().unwrap_or_default())
+    }
+
+    fn matching(&self, pattern: &Regex) -> Result<Vec<Fragment>, Box<dyn Error>> {
+        Ok(self
+            .iter()
+            .filter(|(id, _)| pattern.is_match(id))
+            .flat_map(|(_, fragments)| fragments.iter().cloned())
+            .collect())
+    }
+
+    fn by_index(&self, index: usize) -> Result<Option<Fragment>, Box<dyn Error>> {
+        Ok(self.values().flatten().find(|f| f.index == index).cloned())
+    }
+}
+
+const STORE_INDEX_FILE: &str = "index.json";
+const STORE_FRAGMENTS_FILE: &str = "fragments.jsonl";
+
+// Where a fragment is kept in an `IndexedStore`'s fragments file.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+struct StoreEntry {
+    index: usize,
+    offset: u64,
+    len: usize,
+}
+
+// A store on disk, for projects with too many fragments to read all of them on every run. Only the
+// index, mapping IDs to positions in the fragments file, is read when it is opened; fragments are
+// read as they are asked for, from a fragments file which is opened once.
+#[derive(Debug)]
+pub struct IndexedStore {
+    fragments: RefCell<File>,
+    // Every fragment with each ID, in the order they were written.
+    entries: BTreeMap<String, Vec<StoreEntry>>,
+    by_index: BTreeMap<usize, StoreEntry>,
+}
+
+impl IndexedStore {
+    // Write `fragments` to a new store in `dir`. Every fragment is kept, even where IDs collide, so
+    // that readers can resolve the collisions as they would for `verso`'s JSON.
+    pub fn write(dir: &Path, fragments: &[Fragment]) -> Result<(), Box<dyn Error>> {
+        create_output_dir(dir)?;
+        let mut contents = String::new();
+        let mut entries: BTreeMap<&str, Vec<StoreEntry>> = BTreeMap::new();
+        for f in fragments {
+            let record = serde_json::to_string(f)?;
+            entries.entry(&f.id).or_default().push(StoreEntry {
+                index: f.index,
+                offset: contents.len() as u64,
+                len: record.len(),
+            });
+            contents.push_str(&record);
+            contents.push('\n');
+        }
+        std::fs::write(dir.join(STORE_FRAGMENTS_FILE), contents)?;
+        std::fs::write(dir.join(STORE_INDEX_FILE), serde_json::to_string(&entries)?)?;
+        Ok(())
+    }
+
+    pub fn open(dir: &Path) -> Result<IndexedStore, Box<dyn Error>> {
+        let error = |e: io::Error| {
+            format!(
+                "Error: could not read the fragment store in {}: {}",
+                dir.display(),
+                e
+            )
+        };
+        let index = std::fs::read_to_string(dir.join(STORE_INDEX_FILE)).map_err(error)?;
+        let entries: BTreeMap<String, Vec<StoreEntry>> = serde_json::from_str(&index)?;
+        let by_index = entries
+            .values()
+            .flatten()
+            .filter(|e| e.index > 0)
+            .map(|e| (e.index, e.clone()))
+            .collect();
+        Ok(IndexedStore {
+            fragments: RefCell::new(File::open(dir.join(STORE_FRAGMENTS_FILE)).map_err(error)?),
+            entries,
+            by_index,
+        })
+    }
+
+    // Read the fragments at `entries`, in order of where they are in the file, so that reading many
+    // of them moves through it once.
+    fn read<'a>(
+        &self,
+        entries: impl Iterator<Item = &'a StoreEntry>,
+    ) -> Result<Vec<Fragment>, Box<dyn Error>> {
+        use std::io::{Read, Seek, SeekFrom};
+
+        let mut entries: Vec<(usize, &StoreEntry)> = entries.enumerate().collect();
+        entries.sort_by_key(|(_, e)| e.offset);
+        let mut file = self.fragments.borrow_mut();
+        let mut read = Vec::with_capacity(entries.len());
+        for (position, entry) in entries {
+            file.seek(SeekFrom::Start(entry.offset))?;
+            let mut record = vec![0; entry.len];
+            file.read_exact(&mut record)?;
+            read.push((position, serde_json::from_slice(&record)?));
+        }
+        read.sort_by_key(|(position, _)| *position);
+        Ok(read.into_iter().map(|(_, f)| f).collect())
+    }
+}
+
+impl FragmentStore for IndexedStore {
+    fn get(&self, id: &str) -> Result<Vec<Fragment>, Box<dyn Error>> {
+        self.read(self.entries.get(id).into_iter().flatten())
+    }
+
+    // IDs are held in order, so a pattern anchored to a literal prefix, such as `^listing/`, only
+    // tries the IDs which start with it.
+    fn matching(&self, pattern: &Regex) -> Result<Vec<Fragment>, Box<dyn Error>> {
+        let prefix = literal_prefix(pattern.as_str());
+        let ids = self
+            .entries
+            .range::<str, _>((Bound::Included(prefix.as_str()), Bound::Unbounded))
+            .take_while(|(id, _)| id.starts_with(&prefix))
+            .filter(|(id, _)| pattern.is_match(id));
+        self.read(ids.flat_map(|(_, entries)| entries))
+    }
+
+    fn by_index(&self, index: usize) -> Result<Option<Fragment>, Box<dyn Error>> {
+        Ok(self.read(self.by_index.get(&index).into_iter())?.pop())
+    }
+}
+
+// The text which every match of a regular expression starts with, when it is anchored to the start
+// and begins with plain characters. Empty when that can't be told without parsing it in full.
+fn literal_prefix(pattern: &str) -> String {
+    let rest = match pattern.strip_prefix('^') {
+        Some(rest) if !pattern.contains('|') => rest,
+        _ => return String::new(),
+    };
+    let plain = |c: &char| c.is_alphanumeric() || "/_-:#@ ".contains(*c);
+    let mut prefix: String = rest.chars().take_while(plain).collect();
+    // A quantifier such as `*` may repeat the last character no times at all.
+    if rest[prefix.len()..].starts_with(|c| "*?{".contains(c)) {
+        prefix.pop();
+    }
+    prefix
+}
+
+// Load just the fragments that `references` use from a store, along with their neighbours by
+// index, which `@?id.next` and `@?id.prev` refer to. Every fragment with each of those IDs is
+// loaded, so that duplicates can be resolved just as they are for fragments read in full. For an
+// insertion `!withheader`, the fragments before its target in the same file are loaded too, since
+// any of them may enclose it.
+pub fn load_referenced(
+    store: &dyn FragmentStore,
+    references: &[Reference],
+) -> Result<Annotations, Box<dyn Error>> {
+    let mut loaded = Annotations::new();
+    // Each ID's fragments are loaded together, so an ID which is already loaded is complete.
+    let load = |loaded: &mut Annotations, fragments: Vec<Fragment>| {
+        let fresh: Vec<Fragment> = fragments
+            .into_iter()
+            .filter(|f| !loaded.contains_key(&f.id))
+            .collect();
+        for f in fresh {
+            loaded.entry(f.id.to_owned()).or_default().push(f);
+        }
+    };
+    for reference in references {
+        let fragments = match reference.kind {
+            ReferenceKind::Insertion | ReferenceKind::Metadata => store.get(&reference.target)?,
+            ReferenceKind::Pattern => store.matching(&Regex::new(&reference.target)?)?,
+        };
+        load(&mut loaded, fragments);
+    }
+
+    let indices: Vec<usize> = loaded.values().flatten().map(|f| f.index).collect();
+    for index in indices.into_iter().filter(|&i| i > 0) {
+        for neighbour in [index - 1, index + 1] {
+            if let Some(f) = store.by_index(neighbour)? {
+                if !loaded.contains_key(&f.id) {
+                    load(&mut loaded, store.get(&f.id)?);
+                }
+            }
+        }
+    }
+
+    // A file's fragments are numbered together, so they are found by walking the numbers outward
+    // from the target until another file's fragments are reached. This comes after the neighbours
+    // are loaded, since those of the enclosing fragments aren't referred to.
+    let targets: Vec<Fragment> = references
+        .iter()
+        .filter(|r| r.with_header)
+        .flat_map(|r| loaded.get(&r.target).cloned().unwrap_or_default())
+        .filter(|f| f.index > 0)
+        .collect();
+    for target in targets {
+        let sides: [Box<dyn Iterator<Item = usize>>; 2] = [
+            Box::new((1..target.index).rev()),
+            Box::new(target.index + 1..),
+        ];
+        for side in sides {
+            for index in side {
+                match store.by_index(index)? {
+                    Some(f) if f.file == target.file => {
+                        if f.line < target.line && !loaded.contains_key(&f.id) {
+                            load(&mut loaded, store.get(&f.id)?);
+                        }
+                    }
+                    _ => break,
+                }
+            }
+        }
+    }
+    Ok(loaded)
+}
+
+// `path` with the platform's separators replaced by forward slashes. Only Windows separates paths
+// with backslashes; elsewhere a backslash is part of a file name, and is kept.
+pub fn posix_path(path: &str) -> String {
+    path.chars()
+        .map(|c| if std::path::is_separator(c) { '/' } else { c })
+        .collect()
+}
+
+// The root a path hangs from: a Windows drive letter, `/` for other absolute paths, or `None` for a
+// relative path. This is worked out from the text, so that Windows paths are understood everywhere.
+fn path_root(path: &std::path::Path) -> Option<String> {
+    let path = path.to_string_lossy();
+    let mut chars = path.chars();
+    match (chars.next(), chars.next()) {
+        (Some(drive), Some(':')) if drive.is_ascii_alphabetic() => {
+            Some(drive.to_ascii_uppercase().to_string())
+        }
+        (Some('/'), _) | (Some('\\'), _) => Some(String::from("/")),
+        _ => None,
+    }
+}
+
+fn find_relative_path(a: &std::path::Path, b: &std::path::Path) -> std::path::PathBuf {
+    // There is no relative path between different drives, or from an absolute path to a relative
+    // one. Use B's absolute path instead, taking relative paths from the root as `abspath` does.
+    match (path_root(a), path_root(b)) {
+        (a_root, b_root) if a_root == b_root => {}
+        (_, Some(_)) => return b.to_path_buf(),
+        (_, None) => return std::path::Path::new("/").join(b),
+    }
+
+    // Like a link in a web page, the path is relative to the directory holding A, not to A itself.
+    let directory: Vec<Component> = a
+        .parent()
+        .map(|dir| {
+            dir.components()
+                .filter(|c| *c != Component::CurDir)
+                .collect()
+        })
+        .unwrap_or_default();
+    let target: Vec<Component> = b.components().filter(|c| *c != Component::CurDir).collect();
+    let shared = directory
+        .iter()
+        .zip(&target)
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let mut path = std::path::PathBuf::new();
+    for _ in shared..directory.len() {
+        path.push("..");
+    }
+    for component in &target[shared..] {
+        path.push(component);
+    }
+    if path.as_os_str().is_empty() {
+        path.push(".");
+    }
+    path
+}
+
+// Problems with where `recto` would write its output.
+#[derive(Debug)]
+pub enum OutputError {
+    // Something other than a directory is in the way of the output directory.
+    NotADirectory(PathBuf),
+    // The prose path is absolute or climbs out of the output directory with `..`.
+    OutsideOutDir(String),
+    Io(std::io::Error),
+}
+
+impl Error for OutputError {}
+
+impl fmt::Display for OutputError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OutputError::NotADirectory(path) => write!(
+                f,
+                "Error: output path {} exists and is not a directory",
+                path.display()
+            ),
+            OutputError::OutsideOutDir(path) => write!(
+                f,
+                "Error: prose file {} would be written outside the output directory",
+                path
+            ),
+            OutputError::Io(err) => write!(f, "Error: {}", err),
+        }
+    }
+}
+
+// Where the woven version of the prose file `filename` goes: the same relative path beneath
+// `out_dir`. Absolute paths and paths containing `..` could overwrite anything, so are refused.
+pub fn output_path(out_dir: &Path, filename: &str) -> Result<PathBuf, OutputError> {
+    let escapes = Path::new(filename)
+        .components()
+        .any(|c| !matches!(c, Component::Normal(_) | Component::CurDir));
+    if escapes {
+        return Err(OutputError::OutsideOutDir(filename.to_owned()));
+    }
+    Ok(out_dir.join(filename))
+}
+
+// Create `dir` and any missing parents, as `fs::create_dir_all` does, but say which path is in the
+// way if one of them already exists as a file.
+pub fn create_output_dir(dir: &Path) -> Result<(), OutputError> {
+    if let Some(blocked) = dir.ancestors().find(|p| p.exists() && !p.is_dir()) {
+        return Err(OutputError::NotADirectory(blocked.to_owned()));
+    }
+    std::fs::create_dir_all(dir).map_err(OutputError::Io)
+}
+
+// @<tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A fragment from `file`, with everything besides its ID and body left as the default.
+    fn fragment(id: &str, file: &str, body: &str) -> Fragment {
+        Fragment {
+            id: id.to_owned(),
+            file: file.to_owned(),
+            body: body.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    // Fragments by ID, as `weave` takes them. Fragments which share an ID are all kept.
+    fn annotations_of<I>(fragments: I) -> Annotations
+    where
+        I: IntoIterator<Item = Fragment>,
+    {
+        let mut annotations = Annotations::new();
+        for f in fragments {
+            annotations.entry(f.id.to_owned()).or_default().push(f);
+        }
+        annotations
+    }
+
+    #[test]
+    fn test_scanner() {
+        let tokens: Vec<(TokenKind, &str, usize)> =
+            Scanner::new("é @<a@<b ü >@", &["@<", ">@"], &id_len)
+                .map(|t| (t.kind, t.text, t.col))
+                .collect();
+        assert_eq!(
+            tokens,
+            vec![
+                (TokenKind::Text, "é ", 0),
+                (
+                    TokenKind::Symbol {
+                        symbol: "@<",
+                        word: "a"
+                    },
+                    "@<a",
+                    2
+                ),
+                (
+                    TokenKind::Symbol {
+                        symbol: "@<",
+                        word: "b"
+                    },
+                    "@<b",
+                    5
+                ),
+                (TokenKind::Text, " ü ", 8),
+                (
+                    TokenKind::Symbol {
+                        symbol: ">@",
+                        word: ""
+                    },
+                    ">@",
+                    11
+                ),
+            ]
+        );
+
+        // The longest symbol wins, and metadata references end where their property does.
+        let words: Vec<Option<&str>> =
+            Scanner::new("@@@?x.loc, @?@.date.", &["@?", "@@@?"], &|s| {
+                metadata_ref_len(s, &SymbolKey::default())
+            })
+            .map(|t| t.word_after("@?"))
+            .collect();
+        assert_eq!(words, vec![None, None, Some("@.date"), None]);
+    }
+
+    #[test]
+    fn test_weave_metadata_multibyte() {
+        let frag = Fragment {
+            id: String::from("1"),
+            file: String::from("example.code"),
+            line: 3,
+            ..Default::default()
+        };
+        let annotations = annotations_of(vec![frag]);
+
+        let result = weave(
+            "test",
+            "ünïcode @?1.line",
+            &annotations,
+            &SymbolKey::default(),
+        )
+        .expect("Expected weave to return Ok");
+        assert_eq!(result, "ünïcode 3");
+        // A reference may end a line, or be followed directly by a multibyte character.
+        let result = weave(
+            "test",
+            "ü @?1.line\n«@?1.line»",
+            &annotations,
+            &SymbolKey::default(),
+        );
+        assert_eq!(result.unwrap(), "ü 3\n«3»");
+
+        match weave(
+            "test",
+            "ünïcode @?1.foo",
+            &annotations,
+            &SymbolKey::default(),
+        ) {
+            Err(FileError {
+                err_type: WeaveError::UnknownProperty(_),
+                col: 12,
+                ..
+            }) => {}
+            result => panic!("Expected an unknown property error, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_extract_id_missing() {
+        let id = extract_id("", 0);
+        assert_eq!(
+            id,
+            Err(IdExtractError::NoIdFound),
+            "Expected NoIdFound, got {:?}",
+            id
+        );
+    }
+    // ... snip ...
+    // >@tests
+
+    // @!halt
+
+    #[test]
+    fn test_extract_id_good() {
+        let id = extract_id("foobarbaz", 0);
+        assert_eq!(
+            id.expect("Expected successful ID extraction"),
+            String::from("foobarbaz")
+        );
+    }
+
+    #[test]
+    fn test_extract_id_multibyte() {
+        // Found by test_weave_never_panics: a reserved character after a multibyte one.
+        let id = extract_id("@@é?", 2);
+        assert_eq!(id, Err(IdExtractError::ReservedCharacterUsed('?')));
+        let id = extract_id("§§日本", "§§".chars().count());
+        assert_eq!(id, Ok(String::from("日本")));
+    }
+
+    #[test]
+    fn test_extract_id_whitespace() {
+        let id = extract_id("foo bar baz quuz", 0);
+        assert_eq!(
+            id.expect("Expected successful ID extraction"),
+            String::from("foo")
+        );
+    }
+
+    #[test]
+    fn test_extract_id_acceptable_separators() {
+        let id_elements = ["foo", "bar", "baz", "quuz"];
+        for sep in ID_SAFE_CHARS {
+            let input = &id_elements.join(&sep.to_string());
+            let id = extract_id(input, 0);
+            assert_eq!(&id.expect("Expected successful ID extraction"), input);
+        }
+    }
+
+    #[test]
+    fn test_extract_id_reserved_chars() {
+        let id_elements = ["foo", "bar", "baz", "quuz"];
+        let id_reserved_chars = &[METADATA_SEPARATOR];
+        for sep in id_reserved_chars {
+            let input = &id_elements.join(&sep.to_string());
             let id = extract_id(input, 0);
             assert_eq!(
-                id.expect_err(&format!(
-                    "Expected ID extraction to fail due to reserved char '{}'",
-                    sep
-                )),
-                IdExtractError::ReservedCharacterUsed(*sep)
+                id.expect_err(&format!(
+                    "Expected ID extraction to fail due to reserved char '{}'",
+                    sep
+                )),
+                IdExtractError::ReservedCharacterUsed(*sep)
+            );
+        }
+    }
+
+    #[test]
+    fn test_extract_id_offset() {
+        let id = extract_id("foo bar baz quuz", 4);
+        assert_eq!(
+            id.expect("Expected successful ID extraction"),
+            String::from("bar")
+        );
+    }
+
+    #[test]
+    fn test_extract_pattern_good() {
+        let pattern = extract_pattern("[a-z0-9]+", 0);
+        assert!(pattern
+            .expect("Expected successful pattern extraction")
+            .is_match("abc123"));
+    }
+
+    #[test]
+    fn test_extract_pattern_missing() {
+        let pattern =
+            extract_pattern("   ", 0).expect_err("Expected error extracting empty pattern");
+        assert_eq!(
+            pattern,
+            PatternExtractError::NoPatternFound,
+            "Expected NoPatternFound, got {:?}",
+            pattern
+        );
+    }
+
+    #[test]
+    fn test_extract_pattern_invalid() {
+        let pattern =
+            extract_pattern("{[}]", 0).expect_err("Expected error extracting invalid pattern");
+        assert!(
+            matches!(pattern, PatternExtractError::RegexConstruction(_)),
+            "Expected RegexConstruction, got {:?}",
+            pattern
+        );
+    }
+
+    #[test]
+    fn test_extract_fragments() {
+        let fragments: Result<Vec<Fragment>, FileError<ParseError>> = extract_fragments(
+            "# This is an example
+import sys
+
+# @<foobarbaz The fragment starts and its ID is defined on this line; it is foobarbaz.
+def main():
+    do_stuff()
+    make_awesome()
+    # >@ This line ends the fragment.
+    sys.exit(1) # oops",
+            "test.py",
+            &SymbolKey::default(),
+        );
+
+        let fragments = fragments.expect("Expected no parse errors");
+        assert!(
+            fragments.len() == 1,
+            "Expected one fragment, found {}",
+            fragments.len()
+        );
+        assert!(
+            fragments[0].body
+                == *"def main():
+    do_stuff()
+    make_awesome()",
+            "Unexpected code fragment {:?}",
+            fragments[0].body
+        );
+        assert!(
+            fragments[0].id == *"foobarbaz",
+            "Unexpected ID {:?}",
+            fragments[0].id
+        );
+    }
+
+    #[test]
+    fn test_extract_fragments_nested() {
+        let fragments: Result<Vec<Fragment>, FileError<ParseError>> = extract_fragments(
+            "# This is an example.
+    # @<foobarbaz This begins the fragment.
+    Start of outer
+    # @<qux
+        Start of inner
+            # @<quux
+            # >@
+        End of inner
+    # >@
+    End of outer
+    # >@",
+            "test.py",
+            &SymbolKey::default(),
+        );
+
+        let fragments = fragments.expect("Expected no parse errors");
+        assert!(
+            fragments.len() == 3, // Two fragments in addition to the full file
+            "Expected two fragments, found {}",
+            fragments.len()
+        );
+        // Innermost, empty fragment
+        assert_eq!(fragments[0].body, "", "Expected fragment to be empty");
+        assert!(
+            fragments[0].id == *"quux",
+            "Unexpected ID {:?}",
+            fragments[0].id
+        );
+        // Middle fragment, that has content, including all child fragments with tags removed.
+        assert_eq!(
+            fragments[1].body,
+            "        Start of inner
+        End of inner",
+            "Expected nested fragment markers to be removed"
+        );
+        assert!(
+            fragments[1].id == *"qux",
+            "Unexpected ID {:?}",
+            fragments[1].id
+        );
+        // Outer fragment, that has content, including all child fragments with tags removed.
+        assert_eq!(
+            fragments[2].body,
+            "    Start of outer
+        Start of inner
+        End of inner
+    End of outer",
+            "Expected nested fragment markers to be removed"
+        );
+        assert!(
+            fragments[2].id == *"foobarbaz",
+            "Unexpected ID {:?}",
+            fragments[2].id
+        );
+    }
+
+    fn toggle_symbols() -> SymbolKey {
+        SymbolKey {
+            fragment_open: String::from("%%"),
+            toggle: true,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_extract_fragments_toggle() {
+        let text = "# %%outer
+a
+# %%inner
+b
+# %%inner
+# %%
+c";
+        let fragments = extract_fragments(text, "test", &toggle_symbols())
+            .expect("Expected extract_fragments to return Ok");
+        let bodies: Vec<(&str, &str)> = fragments
+            .iter()
+            .map(|f| (f.id.as_str(), f.body.as_str()))
+            .collect();
+        assert_eq!(bodies, vec![("inner", "b"), ("outer", "a\nb")]);
+    }
+
+    #[test]
+    fn test_extract_fragments_toggle_mismatched() {
+        let text = "# %%outer
+# %%inner
+# %%outer
+# %%inner";
+        match extract_fragments(text, "test", &toggle_symbols()) {
+            Err(FileError {
+                err_type: ParseError::MismatchedToggle,
+                line: 3,
+                ..
+            }) => {}
+            result => panic!("Expected a mismatched toggle error, got {:?}", result),
+        }
+
+        match extract_fragments("# %%", "test", &toggle_symbols()) {
+            Err(FileError {
+                err_type: ParseError::CloseBeforeOpen,
+                ..
+            }) => {}
+            result => panic!("Expected a close before open error, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_extract_fragments_same_line_opens() {
+        let text = "// @<outer@<inner
+code
+// >@
+// >@";
+        let fragments = extract_fragments(text, "test", &SymbolKey::default())
+            .expect("Expected extract_fragments to return Ok");
+        let ids: Vec<(&str, usize, Option<&str>)> = fragments
+            .iter()
+            .map(|f| (f.id.as_str(), f.line, f.open_prefix.as_deref()))
+            .collect();
+        assert_eq!(
+            ids,
+            vec![("inner", 2, Some("//")), ("outer", 2, Some("//"))]
+        );
+        assert_eq!(fragments[1].body, "code");
+
+        match extract_fragments("// @<a @<a\n// >@\n// >@", "test", &SymbolKey::default()) {
+            Err(FileError {
+                err_type: ParseError::DoubleOpen,
+                col: 7,
+                ..
+            }) => {}
+            result => panic!("Expected a double open error, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_extract_fragments_trim() {
+        let text = "// @<1
+
+  code();  
+  more(); 
+
+// >@";
+        let cases = [
+            (TrimPolicy::None, "\n  code();  \n  more(); \n\n"),
+            (TrimPolicy::TrailingNewlines, "\n  code();  \n  more(); "),
+            (TrimPolicy::TrailingWhitespace, "\n  code();\n  more();"),
+            (TrimPolicy::Full, "  code();\n  more();"),
+        ];
+        for (trim, expected) in cases.iter() {
+            let options = ExtractOptions {
+                trim: *trim,
+                ..Default::default()
+            };
+            let fragments =
+                extract_fragments_with_options(text, "test", &SymbolKey::default(), &options)
+                    .expect("Expected extract_fragments to return Ok");
+            assert_eq!(&fragments[0].body, expected, "trimming with {:?}", trim);
+        }
+    }
+
+    #[test]
+    fn test_collapse_nested_blanks() {
+        let text = "// @<parent
+first();
+
+// @<child
+// >@
+
+second();
+// @<other
+third();
+// >@
+
+// >@";
+        let extract = |collapse_nested_blanks| {
+            let options = ExtractOptions {
+                collapse_nested_blanks,
+                ..Default::default()
+            };
+            extract_fragments_with_options(text, "test", &SymbolKey::default(), &options)
+                .expect("Expected extract_fragments to return Ok")
+        };
+
+        let fragments = extract(false);
+        let parent = fragments.iter().find(|f| f.id == "parent").unwrap();
+        assert_eq!(parent.body, "first();\n\n\nsecond();\nthird();");
+
+        let fragments = extract(true);
+        let parent = fragments.iter().find(|f| f.id == "parent").unwrap();
+        assert_eq!(parent.body, "first();\n\nsecond();\nthird();");
+    }
+
+    #[test]
+    fn test_output_path() {
+        let out_dir = Path::new("out");
+        assert_eq!(
+            output_path(out_dir, "./docs/intro.md").unwrap(),
+            out_dir.join("./docs/intro.md")
+        );
+        for filename in &["../intro.md", "docs/../../intro.md", "/etc/passwd"] {
+            match output_path(out_dir, filename) {
+                Err(OutputError::OutsideOutDir(path)) => assert_eq!(&path, filename),
+                result => panic!("Expected {} to be rejected, got {:?}", filename, result),
+            }
+        }
+    }
+
+    #[test]
+    fn test_create_output_dir() {
+        let root = std::env::temp_dir().join(format!("verso-output-{}", std::process::id()));
+        let blocker = root.join("file");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(&blocker, "").unwrap();
+
+        create_output_dir(&root.join("a/b")).expect("Expected create_output_dir to return Ok");
+        assert!(root.join("a/b").is_dir());
+        for dir in &[blocker.clone(), blocker.join("sub")] {
+            match create_output_dir(dir) {
+                Err(OutputError::NotADirectory(path)) => assert_eq!(path, blocker),
+                result => panic!("Expected {:?} to be blocked, got {:?}", dir, result),
+            }
+        }
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_weave_with_report() {
+        let annotations = annotations_of(vec![
+            fragment("a", "", "one\ntwo"),
+            fragment("b", "", "three"),
+        ]);
+        let symbols = SymbolKey::default();
+        let placement = |id: &str, kind, line| Placement {
+            file: String::from("test"),
+            id: id.to_owned(),
+            kind,
+            line,
+        };
+
+        let text = "Intro\n@@a\nSee @?b.line and @?c.line.\n@*[ab]";
+        let options = WeaveOptions {
+            passthrough_missing: true,
+            ..Default::default()
+        };
+        let (woven, placements) = weave_with_report("test", text, &annotations, &symbols, &options)
+            .expect("Expected weave to return Ok");
+        assert_eq!(
+            woven,
+            "Intro\none\ntwo\nSee 0 and @?c.line.\none\ntwo\nthree"
+        );
+        assert_eq!(
+            placements,
+            vec![
+                placement("a", ReferenceKind::Insertion, 2),
+                placement("b", ReferenceKind::Metadata, 4),
+                placement("a", ReferenceKind::Pattern, 5),
+                placement("b", ReferenceKind::Pattern, 7),
+            ]
+        );
+
+        let options = WeaveOptions {
+            syntax: Syntax::Mustache,
+            ..Default::default()
+        };
+        let text = "{{verso:a}} and {{verso:b.line}}\n{{verso:b!wrap=80}}";
+        let (_, placements) = weave_with_report("test", text, &annotations, &symbols, &options)
+            .expect("Expected weave to return Ok");
+        assert_eq!(
+            placements,
+            vec![
+                placement("a", ReferenceKind::Insertion, 1),
+                placement("b", ReferenceKind::Metadata, 1),
+                placement("b", ReferenceKind::Insertion, 3),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_weave_pattern_duplicates() {
+        let annotations = annotations_of(vec![
+            fragment("a", "one.rs", "a from one.rs"),
+            fragment("a", "two.rs", "a from two.rs"),
+            fragment("b", "", "b"),
+        ]);
+        let symbols = SymbolKey::default();
+        let weave_placed = |options: &WeaveOptions| {
+            let (woven, placements) =
+                weave_with_report("test", "@*[ab]", &annotations, &symbols, options)
+                    .expect("Expected weave to return Ok");
+            (woven, placements.len())
+        };
+        let woven = String::from("a from one.rs\na from two.rs\nb");
+
+        // Both of the fragments kept for `a` are inserted, joined into one by default.
+        assert_eq!(weave_placed(&WeaveOptions::default()), (woven.clone(), 2));
+        let options = WeaveOptions {
+            pattern_duplicates: true,
+            ..Default::default()
+        };
+        assert_eq!(weave_placed(&options), (woven, 3));
+        assert_eq!(
+            find_fragment(&annotations, "a").map(|f| f.body.clone()),
+            Some(String::from("a from one.rs\na from two.rs"))
+        );
+    }
+
+    #[test]
+    fn test_posix_paths() {
+        let text = "// @<1\ncode();\n// >@";
+        let options = ExtractOptions {
+            posix_paths: true,
+            ..Default::default()
+        };
+        let filename = ["src", "lib", "main.rs"].join(std::path::MAIN_SEPARATOR_STR);
+        let fragments =
+            extract_fragments_with_options(text, &filename, &SymbolKey::default(), &options)
+                .expect("Expected extract_fragments to return Ok");
+        assert_eq!(fragments[0].file, "src/lib/main.rs");
+        let json = serde_json::to_string(&fragments[0]).unwrap();
+        assert!(json.contains(r#""file":"src/lib/main.rs""#), "{}", json);
+    }
+
+    #[test]
+    fn test_posix_path() {
+        assert_eq!(posix_path("src/main.rs"), "src/main.rs");
+        let expected = if cfg!(windows) {
+            "src/a/b.rs"
+        } else {
+            r"src\a\b.rs"
+        };
+        assert_eq!(posix_path(r"src\a\b.rs"), expected);
+    }
+
+    #[test]
+    fn test_read_fragments_dir() {
+        let dir = std::env::temp_dir().join(format!("verso-fragments-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let write = |name: &str, id: &str| {
+            let fragments = vec![Fragment {
+                id: id.to_owned(),
+                body: format!("body of {}", id),
+                file: format!("{}.rs", id),
+                ..Default::default()
+            }];
+            std::fs::write(dir.join(name), serde_json::to_string(&fragments).unwrap()).unwrap();
+        };
+        write("b.json", "second");
+        write("a.json", "first");
+        std::fs::write(dir.join("notes.txt"), "not fragments").unwrap();
+
+        let fragments = read_fragments_dir(&dir).expect("Expected read_fragments_dir to return Ok");
+        let ids: Vec<&str> = fragments.iter().map(|f| f.id.as_str()).collect();
+        assert_eq!(ids, vec!["first", "second"]);
+        assert_eq!(fragments[1].body, "body of second");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_write_fragments() {
+        let fragments = vec![fragment("a", "a.rs", "x\ny"), fragment("b", "b.rs", "z")];
+
+        let mut json = vec![];
+        write_fragments(&mut json, &fragments, false).unwrap();
+        let read: Vec<Fragment> = serde_json::from_slice(&json).unwrap();
+        assert_eq!(read, fragments);
+
+        let mut jsonl = vec![];
+        write_fragments(&mut jsonl, &fragments, true).unwrap();
+        let jsonl = String::from_utf8(jsonl).unwrap();
+        assert_eq!(jsonl.lines().count(), 2);
+        assert!(
+            jsonl.starts_with(r#"{"body":"x\ny","id":"a","#),
+            "{}",
+            jsonl
+        );
+
+        // Blank lines between fragments are skipped.
+        let padded = jsonl.replace('\n', "\n\n  \n");
+        let read: Vec<Fragment> = read_jsonl(padded.as_bytes()).map(Result::unwrap).collect();
+        assert_eq!(read, fragments);
+    }
+
+    #[test]
+    fn test_fragments_file() {
+        let dir = std::env::temp_dir().join(format!("verso-output-file-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("fragments.json").to_string_lossy().into_owned();
+        let fragments = vec![fragment("a", "a.rs", "x"), fragment("b", "b.rs", "y")];
+        let read = |jsonl| {
+            read_fragments_file(&path, jsonl)
+                .unwrap()
+                .map(Result::unwrap)
+                .collect::<Vec<_>>()
+        };
+
+        std::fs::write(&path, "stale").unwrap();
+        write_fragments_file(&path, &fragments, false).unwrap();
+        assert_eq!(read(false), fragments);
+        write_fragments_file(&path, &fragments[1..], true).unwrap();
+        assert_eq!(read(true), &fragments[1..]);
+        let files: Vec<_> = std::fs::read_dir(&dir).unwrap().collect();
+        assert_eq!(files.len(), 1);
+
+        // JSON Lines aren't an array.
+        let e = read_fragments_file(&path, false).err().unwrap().to_string();
+        assert!(e.starts_with(&format!("Error: ({}) ", path)), "{}", e);
+        let missing = dir.join("missing.json").to_string_lossy().into_owned();
+        let e = read_fragments_file(&missing, false)
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(e.contains("missing.json"), "{}", e);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_combine_fragment_readers() {
+        let numbered = |id: &str, index| Fragment {
+            index,
+            ..fragment(id, "a.rs", "x")
+        };
+        let reader = |fragments: Vec<Fragment>| -> FragmentReader {
+            Box::new(fragments.into_iter().map(Ok))
+        };
+        let indices = |readers| -> Vec<(String, usize)> {
+            combine_fragment_readers(readers)
+                .map(|f| f.map(|f| (f.id, f.index)).unwrap())
+                .collect()
+        };
+
+        // One file keeps the numbers `verso` gave it.
+        let one = vec![reader(vec![numbered("a", 1), numbered("b", 2)])];
+        assert_eq!(indices(one), [("a".into(), 1), ("b".into(), 2)]);
+        let two = vec![
+            reader(vec![numbered("a", 1), numbered("b", 2)]),
+            reader(vec![numbered("c", 1)]),
+        ];
+        assert_eq!(
+            indices(two),
+            [("a".into(), 0), ("b".into(), 0), ("c".into(), 0)]
+        );
+    }
+
+    #[test]
+    fn test_fragment_index() {
+        let fragments = || {
+            vec![
+                Fragment {
+                    line: 1,
+                    ..fragment("a", "a.rs", "first")
+                },
+                fragment("b", "a.rs", "other"),
+                Fragment {
+                    line: 7,
+                    ..fragment("a", "b.rs", "second")
+                },
+            ]
+        };
+        let index = |policy| {
+            let mut index = FragmentIndex::new(policy);
+            let kept: Vec<bool> = fragments()
+                .into_iter()
+                .map(|f| index.add(f).unwrap())
+                .collect();
+            (kept, index.into_annotations())
+        };
+        let bodies = |annotations: &Annotations| -> Vec<String> {
+            annotations["a"].iter().map(|f| f.body.clone()).collect()
+        };
+
+        let (kept, annotations) = index(DuplicatePolicy::Merge);
+        assert_eq!(kept, [true, true, true]);
+        assert_eq!(bodies(&annotations), ["first", "second"]);
+        // Unnumbered fragments are numbered in the order they were read.
+        let indices: Vec<usize> = annotations["a"].iter().map(|f| f.index).collect();
+        assert_eq!((indices, annotations["b"][0].index), (vec![1, 3], 2));
+        let (_, annotations) = index(DuplicatePolicy::Last);
+        assert_eq!(bodies(&annotations), ["second"]);
+        let (kept, annotations) = index(DuplicatePolicy::First);
+        assert_eq!(kept, [true, true, false]);
+        assert_eq!(bodies(&annotations), ["first"]);
+
+        let mut index = FragmentIndex::new(DuplicatePolicy::Error);
+        let mut fragments = fragments().into_iter();
+        assert_eq!(index.add(fragments.next().unwrap()), Ok(true));
+        assert_eq!(index.add(fragments.next().unwrap()), Ok(true));
+        assert_eq!(
+            index.add(fragments.next().unwrap()),
+            Err(String::from(
+                "Error: fragment 'a' is defined in both a.rs:1 and b.rs:7"
+            ))
+        );
+    }
+
+    #[test]
+    fn test_read_jsonl_errors() {
+        let input = "{\"body\":\"x\",\"id\":\"a\",\"file\":\"a.rs\",\"line\":1,\"col\":0}\n[\n";
+        let read: Vec<_> = read_jsonl(input.as_bytes()).collect();
+        assert_eq!(read.len(), 2);
+        assert_eq!(read[0].as_ref().unwrap().id, "a");
+        assert!(read[1].is_err());
+    }
+
+    #[test]
+    fn test_watch_targets() {
+        let dir = std::env::temp_dir().join(format!("verso-watch-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("docs")).unwrap();
+        std::fs::create_dir_all(dir.join("store")).unwrap();
+        for name in &["docs/a.md", "docs/b.md", "fragments.json"] {
+            std::fs::write(dir.join(name), "").unwrap();
+        }
+        let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+        let prose = [path("docs/a.md"), path("docs/b.md")];
+        let targets = WatchTargets::new(&prose, &[path("fragments.json"), path("store")]).unwrap();
+        let canonical = |name: &str| std::fs::canonicalize(dir.join(name)).unwrap();
+
+        let expected = vec![
+            (canonical("store"), true),
+            (canonical(""), false),
+            (canonical("docs"), false),
+        ];
+        assert_eq!(targets.watches(), expected);
+
+        let changed = [canonical("store").join("index.json")];
+        assert!(targets.reloads(&changed));
+        assert!(targets.reloads(&[canonical("fragments.json")]));
+        assert!(!targets.reloads(&[canonical("docs/a.md"), dir.join("other.json")]));
+
+        let changed = [canonical("docs/b.md")];
+        assert_eq!(
+            targets.to_weave(&changed, false, false),
+            [path("docs/b.md")]
+        );
+        assert_eq!(targets.to_weave(&changed, false, true), prose);
+        assert_eq!(targets.to_weave(&[], true, false), prose);
+        let changed = [dir.join("other.md")];
+        assert!(targets.to_weave(&changed, false, true).is_empty());
+
+        assert!(WatchTargets::new(&[path("missing.md")], &[]).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_walk_dir() {
+        let dir = std::env::temp_dir().join(format!("verso-walk-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        std::fs::write(dir.join(".gitignore"), "ignored.rs\n").unwrap();
+        for name in &["b.rs", "a.rs", "ignored.rs", ".hidden.rs", "sub/c.rs"] {
+            std::fs::write(dir.join(name), "").unwrap();
+        }
+
+        let root = dir.to_string_lossy();
+        let relative = |files: Vec<String>| -> Vec<String> {
+            let prefix = format!("{}{}", root, std::path::MAIN_SEPARATOR);
+            let strip = |f: &String| f.strip_prefix(&prefix).unwrap().replace('\\', "/");
+            files.iter().map(strip).collect()
+        };
+        let files = relative(walk_dir(&root, false).unwrap());
+        assert_eq!(files, ["a.rs", "b.rs", "sub/c.rs"]);
+        let files = relative(walk_dir(&root, true).unwrap());
+        assert_eq!(files, ["a.rs", "b.rs", "ignored.rs", "sub/c.rs"]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_extract_fragments_file() {
+        let dir = std::env::temp_dir().join(format!("verso-extract-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let text = dir.join("a.rs");
+        std::fs::write(&text, "// @<a\nx\n// >@\n").unwrap();
+        let binary = dir.join("b.rs");
+        std::fs::write(&binary, b"// @<b\n\xff\xfe\n// >@\n").unwrap();
+
+        let symbols = SymbolKey::default();
+        let options = ExtractOptions::default();
+        let read =
+            |path: &Path| extract_fragments_file(&path.to_string_lossy(), &symbols, &options);
+        let (fragments, warnings) = read(&text).unwrap().unwrap().unwrap();
+        assert_eq!((fragments.len(), fragments[0].body.as_str()), (1, "x"));
+        assert!(warnings.is_empty());
+        assert!(read(&binary).unwrap().is_none());
+        assert!(read(&dir.join("missing.rs")).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_weave_conditional() {
+        let frag = Fragment {
+            id: String::from("1"),
+            body: String::from("code();"),
+            ..Default::default()
+        };
+        let annotations = annotations_of(vec![frag]);
+        let symbols = SymbolKey::default();
+        let text = "Before\n@@?linux:1\n@@!linux:1!wrap=80\nAfter";
+
+        let result =
+            weave("test", text, &annotations, &symbols).expect("Expected weave to return Ok");
+        assert_eq!(result, "Before\ncode();\nAfter");
+
+        let mut options = WeaveOptions::default();
+        options.defines.insert(String::from("linux"));
+        let result = weave_with_options("test", text, &annotations, &symbols, &options)
+            .expect("Expected weave to return Ok");
+        assert_eq!(result, "Before\ncode();\nAfter");
+
+        let text = "@@?linux:1\n@@@?linux:1\n@@?windows:1";
+        let result = weave_with_options("test", text, &annotations, &symbols, &options)
+            .expect("Expected weave to return Ok");
+        assert_eq!(result, "code();\n```\ncode();\n```");
+
+        let references = find_references("test", text, &symbols);
+        assert_eq!(references.len(), 3);
+        assert!(references.iter().all(|r| r.target == "1"));
+    }
+
+    #[test]
+    fn test_comment_prefixes() {
+        let prefixes = CommentPrefixes::parse("rs=//,.py=#,--").unwrap();
+        assert_eq!(prefixes.for_file("src/main.rs"), Some("//"));
+        assert_eq!(prefixes.for_file("tool.py"), Some("#"));
+        assert_eq!(prefixes.for_file("schema.sql"), Some("--"));
+        assert!(CommentPrefixes::parse("rs=").is_err());
+
+        let extract = |filename: &str, text: &str| {
+            let options = ExtractOptions {
+                comment_prefix: prefixes.for_file(filename).map(String::from),
+                ..Default::default()
+            };
+            extract_fragments_with_options(text, filename, &SymbolKey::default(), &options)
+                .expect("Expected extract_fragments to return Ok")
+        };
+
+        let fragments = extract(
+            "main.rs",
+            "// @<1\nlet open = \"@<\";\nlet close = \">@\";\n// >@",
+        );
+        assert_eq!(fragments.len(), 1);
+        assert_eq!(fragments[0].body, "let open = \"@<\";\nlet close = \">@\";");
+
+        let fragments = extract("tool.py", "# @<1\n// @<2\nmarker = \">@\"\n# >@");
+        assert_eq!(fragments.len(), 1);
+        assert_eq!(fragments[0].body, "// @<2\nmarker = \">@\"");
+    }
+
+    #[test]
+    fn test_weave_toc() {
+        let listings = [(2, "listing/b"), (1, "listing/a"), (3, "other")];
+        let annotations = annotations_of(listings.iter().map(|&(index, id)| Fragment {
+            line: index * 10,
+            index,
+            ..fragment(id, "main.rs", "")
+        }));
+        let symbols = SymbolKey::default();
+
+        let result = weave("test", "Listings:\n@toc ^listing/", &annotations, &symbols)
+            .expect("Expected weave to return Ok");
+        assert_eq!(
+            result,
+            "Listings:\n- `listing/a` (main.rs (10:0))\n- `listing/b` (main.rs (20:0))"
+        );
+
+        let mut options = WeaveOptions {
+            toc_template: Some(String::from("{index}. [{id}]({file}#L{line})")),
+            ..Default::default()
+        };
+        let result = weave_with_options("test", "@toc .*", &annotations, &symbols, &options)
+            .expect("Expected weave to return Ok");
+        assert_eq!(
+            result,
+            "1. [listing/a](main.rs#L10)\n2. [listing/b](main.rs#L20)\n3. [other](main.rs#L30)"
+        );
+
+        options.toc_template = Some(String::from("- {caption}"));
+        match weave_with_options("test", "@toc .*", &annotations, &symbols, &options) {
+            Err(FileError {
+                err_type: WeaveError::UnknownProperty(prop),
+                ..
+            }) => assert_eq!(prop, "caption"),
+            result => panic!("Expected an unknown property error, got {:?}", result),
+        }
+    }
+
+    // Feeds many lines built from awkward pieces (symbols, multibyte characters, separators)
+    // through weaving and extraction. Errors are fine; panics are not.
+    #[test]
+    fn test_weave_never_panics() {
+        let pieces = [
+            "@?", "@@", "@@@", "@=", "@*", "@toc", "@<", ">@", "@!halt", "@", "?", ".", "!", "(",
+            ")", ":", "{{verso:", "}}", "1", "file", "snippet(", "wrap=", "é", "日本", "🦀", " ",
+            "\t", "\n", "\\", "[", "*", "§§", "¿", "ñ*", "«", "»",
+        ];
+        let frag = Fragment {
+            id: String::from("1"),
+            body: String::from("é\n日本"),
+            index: 1,
+            ..Default::default()
+        };
+        let annotations = annotations_of(vec![frag]);
+        let symbols = SymbolKey::default();
+        let multibyte = symbols
+            .with_overrides(&SymbolOverrides {
+                fragment_open: Some(String::from("«")),
+                fragment_close: Some(String::from("»")),
+                insertion: Some(String::from("§§")),
+                pattern: Some(String::from("ñ*")),
+                metadata: Some(String::from("¿")),
+                ..Default::default()
+            })
+            .unwrap();
+        let mustache = WeaveOptions {
+            syntax: Syntax::Mustache,
+            ..Default::default()
+        };
+
+        // A fixed linear congruential generator keeps the inputs the same from run to run.
+        let mut state: u64 = 0x5eed;
+        for _ in 0..10_000 {
+            let mut line = String::new();
+            for _ in 0..(state % 8) {
+                state = state
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                line.push_str(pieces[(state >> 33) as usize % pieces.len()]);
+            }
+            state = state.wrapping_add(1);
+
+            let _ = weave("test", &line, &annotations, &symbols);
+            let _ = weave_with_options("test", &line, &annotations, &symbols, &mustache);
+            let _ = extract_fragments(&line, "test", &symbols);
+            let _ = find_references("test", &line, &symbols);
+            let _ = weave("test", &line, &annotations, &multibyte);
+            let _ = extract_fragments(&line, "test", &multibyte);
+            let _ = find_references("test", &line, &multibyte);
+        }
+    }
+
+    #[test]
+    fn test_line_ending() {
+        let frag = Fragment {
+            id: String::from("1"),
+            body: String::from("one\r\ntwo"),
+            ..Default::default()
+        };
+        let annotations = annotations_of(vec![frag]);
+        let woven = weave(
+            "test",
+            "Start\n@@1\nEnd\n",
+            &annotations,
+            &SymbolKey::default(),
+        )
+        .expect("Expected weave to return Ok");
+
+        assert_eq!(
+            LineEnding::Crlf.apply(&woven),
+            "Start\r\none\r\ntwo\r\nEnd\r\n"
+        );
+        assert_eq!(LineEnding::Lf.apply(&woven), "Start\none\ntwo\nEnd\n");
+        assert_eq!(LineEnding::from_name("crlf"), Some(LineEnding::Crlf));
+        assert_eq!(LineEnding::from_name("cr"), None);
+    }
+
+    #[test]
+    fn test_diff_fragments() {
+        let fragments = |list: &[(&str, &str, &str)]| {
+            annotations_of(list.iter().map(|(id, file, body)| fragment(id, file, body)))
+        };
+        let old = fragments(&[
+            ("same", "a.rs", "a"),
+            ("edited", "a.rs", "b"),
+            ("moved", "a.rs", "c"),
+            ("gone", "a.rs", "d"),
+        ]);
+        let new = fragments(&[
+            ("same", "a.rs", "a"),
+            ("edited", "a.rs", "b2"),
+            ("moved", "b.rs", "c"),
+            ("fresh", "b.rs", "e"),
+        ]);
+
+        let diff = diff_fragments(&old, &new);
+        assert_eq!(diff.added, vec!["fresh"]);
+        assert_eq!(diff.removed, vec!["gone"]);
+        assert_eq!(diff.changed, vec!["edited"]);
+        assert_eq!(diff.moved, vec!["moved"]);
+        assert!(!diff.is_empty());
+        assert!(diff_fragments(&old, &old).is_empty());
+    }
+
+    #[test]
+    fn test_max_nesting() {
+        let text = "// @<a\n// @<b\n// >@\n// @<c\n  // @<d\ncode();\n// >@\n// >@\n// >@";
+        let extract = |max_nesting| {
+            let options = ExtractOptions {
+                max_nesting,
+                ..Default::default()
+            };
+            extract_fragments_with_options(text, "test", &SymbolKey::default(), &options)
+        };
+
+        assert!(extract(None).is_ok());
+        assert!(extract(Some(3)).is_ok());
+        match extract(Some(2)) {
+            Err(FileError {
+                err_type: ParseError::NestingTooDeep,
+                line,
+                col,
+                message,
+                ..
+            }) => {
+                assert_eq!((line, col), (5, 5));
+                assert!(message.unwrap().contains("open: a, c, d"));
+            }
+            result => panic!("Expected a nesting error, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_weave_source() {
+        let frag = Fragment {
+            id: String::from("1"),
+            body: String::from("code();"),
+            file: String::from("src/main.rs"),
+            line: 12,
+            ..Default::default()
+        };
+        let annotations = annotations_of(vec![frag]);
+        let symbols = SymbolKey::default();
+
+        let result = weave("test", "@@1!source\n@@@1!source=#", &annotations, &symbols)
+            .expect("Expected weave to return Ok");
+        assert_eq!(
+            result,
+            "// from src/main.rs:12\ncode();\n```rust\n# from src/main.rs:12\ncode();\n```"
+        );
+
+        let options = WeaveOptions {
+            source_comment_style: Some(String::from("<!-- -->")),
+            ..Default::default()
+        };
+        let result = weave_with_options("test", "@@1!source", &annotations, &symbols, &options)
+            .expect("Expected weave to return Ok");
+        assert_eq!(result, "<!-- from src/main.rs:12 -->\ncode();");
+
+        assert_eq!(parse_render_option("source="), Err(String::from("source=")));
+    }
+
+    #[test]
+    fn test_weave_trim() {
+        let frag = Fragment {
+            id: String::from("1"),
+            body: String::from("\ncode();  \n"),
+            ..Default::default()
+        };
+        let annotations = annotations_of(vec![frag]);
+
+        let weave_trimmed = |text: &str, trim| {
+            let options = WeaveOptions {
+                trim,
+                ..Default::default()
+            };
+            weave_with_options("test", text, &annotations, &SymbolKey::default(), &options)
+                .expect("Expected weave to return Ok")
+        };
+        // The woven file ends with a newline only if the prose does, even when the body ends with one.
+        assert_eq!(weave_trimmed("@@1", Some(TrimPolicy::None)), "\ncode();  ");
+        assert_eq!(weave_trimmed("@@1", Some(TrimPolicy::Full)), "code();");
+        assert_eq!(
+            weave_trimmed("@@1\n", Some(TrimPolicy::None)),
+            "\ncode();  \n"
+        );
+        // Verbatim insertions are never trimmed.
+        assert_eq!(weave_trimmed("@=1", Some(TrimPolicy::Full)), "\ncode();  ");
+        // Unless a policy is given, bodies are inserted as they were extracted.
+        assert_eq!(weave_trimmed("@@1", None), "\ncode();  ");
+    }
+
+    #[test]
+    fn test_extract_fragments_close_line_markers() {
+        let text = "# @<a
+a
+# >@ and then @<oops
+b";
+        let (fragments, warnings) = extract_fragments_with_warnings(
+            text,
+            "test",
+            &SymbolKey::default(),
+            &ExtractOptions::default(),
+        )
+        .expect("Expected extract_fragments to return Ok");
+
+        // The line still closes `a`, but the author should hear about `oops`.
+        assert_eq!(fragments.len(), 1);
+        assert_eq!(fragments[0].body, "a");
+        assert_eq!(warnings.len(), 1);
+        assert_eq!((warnings[0].line, warnings[0].col), (3, 14));
+        assert!(warnings[0].message.contains("oops"));
+    }
+
+    #[test]
+    fn test_extract_inline_fragments() {
+        let text = "# Tutorial
+
+<!-- @<greet -->
+print(\"hi\")
+<!-- >@ -->
+
+Run it:
+
+@@greet
+@@shared
+";
+        let (fragments, prose) = extract_inline_fragments(text, "doc.md", &SymbolKey::default())
+            .expect("Expected extract_inline_fragments to return Ok");
+        assert_eq!(fragments.len(), 1);
+        assert_eq!(fragments[0].body, "print(\"hi\")");
+        assert_eq!(
+            prose,
+            "# Tutorial
+
+print(\"hi\")
+
+Run it:
+
+@@greet
+@@shared
+"
+        );
+
+        // Fragments from source files take precedence over inline ones with the same ID.
+        let mut annotations = annotations_of(vec![
+            fragment("greet", "", "from source"),
+            fragment("shared", "", "shared"),
+        ]);
+        for f in fragments {
+            annotations
+                .entry(f.id.to_owned())
+                .or_insert_with(|| vec![f]);
+        }
+        let result = weave("doc.md", &prose, &annotations, &SymbolKey::default())
+            .expect("Expected weave to return Ok");
+        assert!(result.ends_with("Run it:\n\nfrom source\nshared\n"));
+    }
+
+    #[test]
+    fn test_split_archive() {
+        let header = Regex::new(r"^=== (.+) ===$").unwrap();
+        let text = "preamble
+=== a.py ===
+# @<a
+print(1)
+# >@a
+=== b/c.py ===
+
+# @<c
+print(2)
+# >@c
+";
+        let files = split_archive(text, &header);
+        let names: Vec<&str> = files.iter().map(|(path, _)| *path).collect();
+        assert_eq!(names, vec!["a.py", "b/c.py"]);
+
+        // Line numbers are counted from the start of each file, not the archive.
+        let lines: Vec<(String, usize)> = files
+            .iter()
+            .flat_map(|(path, contents)| {
+                extract_fragments(contents, path, &SymbolKey::default()).unwrap()
+            })
+            .map(|f| (f.file, f.line))
+            .collect();
+        assert_eq!(
+            lines,
+            vec![(String::from("a.py"), 2), (String::from("b/c.py"), 3)]
+        );
+    }
+
+    #[test]
+    fn test_extract_fragments_dedent_nested() {
+        let text = "fn main() {
+    // @<outer
+    if true {
+        // @<inner
+        let x = 1;
+            println!(\"{}\", x);
+        // >@
+    }
+    // >@
+}";
+        let options = ExtractOptions {
+            dedent_nested: true,
+            ..Default::default()
+        };
+        let fragments =
+            extract_fragments_with_options(text, "test.rs", &SymbolKey::default(), &options)
+                .expect("Expected no parse errors");
+
+        // Inserted on its own, the inner fragment is dedented relative to its first line...
+        assert_eq!(fragments[0].id, "inner");
+        assert_eq!(fragments[0].body, "let x = 1;\n    println!(\"{}\", x);");
+        // ... while its parent keeps the original indentation.
+        assert_eq!(fragments[1].id, "outer");
+        assert_eq!(
+            fragments[1].body,
+            "    if true {
+        let x = 1;
+            println!(\"{}\", x);
+    }"
+        );
+
+        // Without the option, the inner fragment keeps its indentation too.
+        let fragments = extract_fragments(text, "test.rs", &SymbolKey::default())
+            .expect("Expected no parse errors");
+        assert_eq!(
+            fragments[0].body,
+            "        let x = 1;\n            println!(\"{}\", x);"
+        );
+    }
+
+    #[test]
+    fn test_dedent_to_first_line() {
+        assert_eq!(
+            dedent_to_first_line("    a\n      b\n  c\n\n    d"),
+            "a\n  b\nc\n\nd"
+        );
+    }
+
+    #[test]
+    fn test_extract_fragments_open_prefix() {
+        let fragments = extract_fragments(
+            "# @<python
+pass
+# >@
+    // @<rust
+    let x = 1;
+    // >@
+@<bare
+bare
+>@",
+            "test.txt",
+            &SymbolKey::default(),
+        )
+        .expect("Expected no parse errors");
+
+        let prefixes: Vec<Option<&str>> =
+            fragments.iter().map(|f| f.open_prefix.as_deref()).collect();
+        assert_eq!(prefixes, vec![Some("#"), Some("//"), None]);
+    }
+
+    #[test]
+    fn test_extract_fragments_close_before_open() {
+        let fragments: Result<Vec<Fragment>, FileError<ParseError>> = extract_fragments(
+            "# This is an example.
+
+# >@ This is an error on line 3.
+012345 The error is at column 2.
+# @< This begins the fragment.
+# >@ This line ends the fragment.",
+            "test.py",
+            &SymbolKey::default(),
+        );
+
+        let fragments = fragments.expect_err("Expected a parsing error");
+        match fragments {
+            FileError {
+                err_type: ParseError::CloseBeforeOpen,
+                line,
+                col,
+                ..
+            } => {
+                assert_eq!(line, 3, "Expected error on line 3, found line {:?}", line);
+                assert_eq!(col, 2, "Expected error on col 2, found col {:?}", col);
+            }
+            _ => panic!("Expected ParseError::CloseBeforeOpen, got {:?}", fragments),
+        }
+    }
+
+    #[test]
+    fn test_extract_fragments_halt() {
+        let fragments: Result<Vec<Fragment>, FileError<ParseError>> = extract_fragments(
+            "# This is an example.
+
+# @<1
+Fragment 1
+# >@
+
+# @!halt This line causes the reader to stop looking for fragments in this file.
+# Mostly this is useful for keeping Verso out of uninteresting areas, like these tests.
+
+# >@ This would cause an error.",
+            "test.py",
+            &SymbolKey::default(),
+        );
+
+        let fragments = fragments.expect("Expected a clean read");
+        assert!(
+            fragments.len() == 1,
+            "Expected one fragment, found {}",
+            fragments.len()
+        );
+    }
+
+    #[test]
+    fn test_halts_before_content() {
+        let symbols = SymbolKey::default();
+        let options = ExtractOptions::default();
+        let halts = |text: &str| halts_before_content(text.lines(), &symbols, &options);
+        assert!(halts("\n# @!halt\nfoo()"));
+        assert!(!halts("foo()\nbar()"));
+        // The file only counts as excluded if nothing comes before the halt, or after a resume.
+        assert!(!halts("foo()\n# @!halt\nbar()"));
+        assert!(!halts("# @!halt\nfoo()\n# @!resume\nbar()"));
+
+        let symbols = SymbolKey {
+            require_comment_prefix: true,
+            ..SymbolKey::default()
+        };
+        // As in extraction, a halt symbol in a string doesn't count when a comment is required.
+        let halts = |text: &str| halts_before_content(text.lines(), &symbols, &options);
+        assert!(halts("# @!halt\nfoo()"));
+        assert!(!halts("x = '@!halt'\nfoo()"));
+    }
+
+    #[test]
+    fn test_extract_fragments_halt_while_open() {
+        let fragments: Result<Vec<Fragment>, FileError<ParseError>> = extract_fragments(
+            "# This is an example.
+# @<1
+Fragment 1
+# @!halt This line causes an error as we have an open Fragment.
+# >@",
+            "test.py",
+            &SymbolKey::default(),
+        );
+
+        let fragments = fragments.expect_err("Expected a parsing error");
+        match fragments {
+            FileError {
+                err_type: ParseError::HaltWhileOpen,
+                line,
+                col,
+                ..
+            } => {
+                assert_eq!(line, 4, "Expected error on line 4, found line {:?}", line);
+                assert_eq!(col, 2, "Expected error on col 2, found col {:?}", col);
+            }
+            _ => panic!("Expected ParseError::HaltWhileOpen, got {:?}", fragments),
+        }
+    }
+
+    #[test]
+    fn test_extract_fragments_missing_id() {
+        let fragments: Result<Vec<Fragment>, FileError<ParseError>> = extract_fragments(
+            "# This is an example.
+
+# @< This is an error on line 3: No ID.
+012345 The error is at column 2.
+# >@ This line ends the fragment.",
+            "test.py",
+            &SymbolKey::default(),
+        );
+
+        let fragments = fragments.expect_err("Expected a parsing error");
+        match fragments {
+            FileError {
+                err_type: ParseError::MissingId,
+                line,
+                col,
+                ..
+            } => {
+                assert_eq!(line, 3, "Expected error on line 3, found line {:?}", line);
+                assert_eq!(col, 2, "Expected error on col 2, found col {:?}", col);
+            }
+            _ => panic!("Expected ParseError::MissingId, got {:?}", fragments),
+        }
+    }
+
+    #[test]
+    fn test_weave_good() {
+        let text = "This is the first line!
+
+@@1
+  @@1
+@* [0-9]
+@?1.file (@?1.line:@?1.col)
+@?1.loc
+
+Another line.";
+
+        let frag = Fragment {
+            id: String::from("1"),
+            body: String::from("{Example Code}"),
+            file: String::from("example.code"),
+            line: 1,
+            col: 0,
+            ..Default::default()
+        };
+
+        let annotations = annotations_of(vec![frag]);
+        let result = weave("test", text, &annotations, &SymbolKey::default())
+            .expect("Expected weave to return Ok");
+
+        assert_eq!(
+            result,
+            String::from(
+                "This is the first line!
+
+{Example Code}
+  {Example Code}
+{Example Code}
+example.code (1:0)
+example.code (1:0)
+
+Another line."
+            )
+        );
+    }
+
+    #[test]
+    fn test_weave_pattern_order() {
+        let text = "@* [0-9]";
+
+        let frag1 = Fragment {
+            id: String::from("1"),
+            body: String::from("{Example Code 1}"),
+            file: String::from("example.code"),
+            line: 1,
+            col: 0,
+            ..Default::default()
+        };
+
+        let frag2 = Fragment {
+            id: String::from("2"),
+            body: String::from("{Example Code 2}"),
+            file: String::from("example.code"),
+            line: 2,
+            col: 0,
+            ..Default::default()
+        };
+
+        let annotations = annotations_of(vec![frag2, frag1]);
+        let result = weave("test", text, &annotations, &SymbolKey::default())
+            .expect("Expected weave to return Ok");
+
+        assert_eq!(
+            result,
+            String::from(
+                "{Example Code 1}
+{Example Code 2}"
+            )
+        );
+    }
+
+    #[test]
+    fn test_weave_missing_fragment() {
+        let text = "This is the first line!
+
+@@1
+
+Another line.";
+
+        let annotations = BTreeMap::new();
+        weave("test", text, &annotations, &SymbolKey::default())
+            .expect_err("Expected weave to return an error");
+    }
+
+    #[test]
+    fn test_weave_insertion_modes() {
+        let text = "@@1!wrap=10
+@@@1!wrap=10
+@=1";
+
+        let frag = Fragment {
+            id: String::from("1"),
+            body: String::from("one two three four five"),
+            file: String::from("example.code"),
+            line: 1,
+            col: 0,
+            ..Default::default()
+        };
+
+        let annotations = annotations_of(vec![frag]);
+        let result = weave("test", text, &annotations, &SymbolKey::default())
+            .expect("Expected weave to return Ok");
+
+        assert_eq!(
+            result,
+            String::from(
+                "one two
+three four
+five
+```
+one two
+three four
+five
+```
+one two three four five"
+            )
+        );
+    }
+
+    #[test]
+    fn test_match_insertion() {
+        let symbols = SymbolKey::default();
+        assert_eq!(
+            symbols.match_insertion("@@id"),
+            Some(("@@", InsertionMode::Plain))
+        );
+        assert_eq!(
+            symbols.match_insertion("@@@id"),
+            Some(("@@@", InsertionMode::Fenced))
+        );
+        assert_eq!(
+            symbols.match_insertion("@=id"),
+            Some(("@=", InsertionMode::Verbatim))
+        );
+        assert_eq!(symbols.match_insertion("@?id.loc"), None);
+    }
+
+    #[test]
+    fn test_weave_passthrough_missing() {
+        let text = "@@1
+  @@missing
+@?missing.loc and @?1.line";
+
+        let frag = Fragment {
+            id: String::from("1"),
+            body: String::from("{Example Code}"),
+            file: String::from("example.code"),
+            line: 1,
+            col: 0,
+            ..Default::default()
+        };
+
+        let annotations = annotations_of(vec![frag]);
+        let options = WeaveOptions {
+            passthrough_missing: true,
+            ..Default::default()
+        };
+        let result =
+            weave_with_options("test", text, &annotations, &SymbolKey::default(), &options)
+                .expect("Expected weave to return Ok");
+
+        assert_eq!(
+            result,
+            String::from(
+                "{Example Code}
+  @@missing
+@?missing.loc and 1"
+            )
+        );
+
+        // The line is passed through as written, with its condition and default intact.
+        let mut options = WeaveOptions {
+            passthrough_missing: true,
+            ignore_defaults: true,
+            ..Default::default()
+        };
+        options.defines.insert(String::from("flag"));
+        let text = "  @@?flag:notyet\n  @@later ?? TBD";
+        let result =
+            weave_with_options("test", text, &annotations, &SymbolKey::default(), &options)
+                .expect("Expected weave to return Ok");
+        assert_eq!(result, text);
+    }
+
+    #[test]
+    fn test_weave_pandoc() {
+        let annotations = annotations_of(vec![
+            Fragment {
+                line: 3,
+                ..fragment("a", "a.rs", "let x = 1;")
+            },
+            fragment("two", "a.rs", "first();\nsecond();"),
+        ]);
+        let symbols = SymbolKey::default();
+        let options = WeaveOptions::default();
+        let mut tree = serde_json::json!({
+            "blocks": [
+                {"t": "Para", "c": [
+                    {"t": "Str", "c": "Line"},
+                    {"t": "Space"},
+                    {"t": "Str", "c": "@?a.line"}
+                ]},
+                {"t": "CodeBlock", "c": [["listing", ["rust"], [["k", "v"]]], "@@two"]}
+            ]
+        });
+        weave_pandoc(&mut tree, "doc.json", &annotations, &symbols, &options).unwrap();
+        assert_eq!(tree["blocks"][0]["c"][2]["c"], "3");
+        // Code blocks keep their attributes, and take any number of lines.
+        assert_eq!(
+            tree["blocks"][1]["c"],
+            serde_json::json!([["listing", ["rust"], [["k", "v"]]], "first();\nsecond();"])
+        );
+
+        // A word can't hold more than one line.
+        let mut tree = serde_json::json!([{"t": "Str", "c": "@@two"}]);
+        let err = weave_pandoc(&mut tree, "doc.json", &annotations, &symbols, &options);
+        assert_eq!(
+            err.unwrap_err().err_type,
+            WeaveError::MultilineInsertion(String::from("@@two"))
+        );
+    }
+
+    #[test]
+    fn test_format_date() {
+        assert_eq!(format_date(0), "1970-01-01");
+        assert_eq!(format_date(86399), "1970-01-01");
+        assert_eq!(format_date(999_993_600), "2001-09-09");
+        // Leap days, including the century rules.
+        assert_eq!(format_date(951_782_400), "2000-02-29");
+        assert_eq!(format_date(1_709_164_800 + 86399), "2024-02-29");
+        assert_eq!(format_date(4_107_456_000), "2100-02-28");
+        assert_eq!(format_date(4_107_456_000 + 86400), "2100-03-01");
+        assert_eq!(format_date(253_402_214_400), "9999-12-31");
+    }
+
+    #[test]
+    fn test_banners() {
+        let header = expand_banner("<!-- {file}, {date} -->\n\n", "doc.md", "2024-01-02");
+        assert_eq!(header, "<!-- doc.md, 2024-01-02 -->");
+
+        let (woven, before) = add_banners("", "text\n", Some(&header), Some("end"));
+        assert_eq!(woven, "<!-- doc.md, 2024-01-02 -->\ntext\nend\n");
+        assert_eq!(before, 1);
+        // The header follows front matter, and the footer keeps a missing final newline missing.
+        let (woven, before) = add_banners("---\nx: 1\n---\n", "text", Some("top"), Some("end"));
+        assert_eq!(woven, "---\nx: 1\n---\ntop\ntext\nend");
+        assert_eq!(before, 4);
+        let (woven, before) = add_banners("", "", None, Some("end"));
+        assert_eq!((woven.as_str(), before), ("end", 0));
+    }
+
+    #[test]
+    fn test_select_bodies() {
+        let fragments = vec![
+            fragment("a", "a.rs", "first"),
+            fragment("b", "a.rs", "second"),
+            fragment("a", "b.rs", "again"),
+        ];
+        let ids = [String::from("b"), String::from("a")];
+        assert_eq!(
+            select_bodies(&fragments, &ids, "\n---\n"),
+            Ok(String::from("second\n---\nfirst"))
+        );
+        let ids = [String::from("a"), String::from("c")];
+        assert_eq!(select_bodies(&fragments, &ids, "\n"), Err("c"));
+    }
+
+    #[test]
+    fn test_fragments_to_check() {
+        let annotations = annotations_of(vec![
+            fragment("a", "src/a.rs", "x"),
+            fragment("b", "doc/b.md", "y"),
+            fragment("c", "c", "z"),
+        ]);
+        let ids = |fs: Vec<Cow<Fragment>>| fs.iter().map(|f| f.id.clone()).collect::<Vec<_>>();
+        assert_eq!(ids(fragments_to_check(&annotations, &[])), ["a", "b", "c"]);
+        let extensions = [String::from("rs"), String::from("py")];
+        assert_eq!(ids(fragments_to_check(&annotations, &extensions)), ["a"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_run_check_cmd() {
+        assert_eq!(run_check_cmd("grep -q main", "fn main() {}").unwrap(), None);
+        let stderr = run_check_cmd("grep -q main", "fn f() {}").unwrap();
+        assert_eq!(stderr.as_deref(), Some(""));
+        let stderr = run_check_cmd("ls /verso-no-such-file", "")
+            .unwrap()
+            .unwrap();
+        assert!(stderr.contains("verso-no-such-file"), "{}", stderr);
+        assert!(run_check_cmd("  ", "x").is_err());
+        assert!(run_check_cmd("verso-no-such-command", "x").is_err());
+    }
+
+    #[test]
+    fn test_compat_fragment() {
+        let f = Fragment {
+            line: 2,
+            col: 3,
+            marker_line: 1,
+            end_line: 3,
+            open_prefix: Some(String::from("//")),
+            index: 4,
+            deprecated: Some(String::from("use b")),
+            source_lines: vec![2, 3],
+            ..fragment("a", "a.rs", "x\ny")
+        };
+        let json = serde_json::to_string(&CompatFragment::from(&f)).unwrap();
+        assert_eq!(
+            json,
+            r#"{"body":"x\ny","id":"a","file":"a.rs","line":2,"col":3}"#
+        );
+        // recto still reads it, without the newer fields.
+        let read: Fragment = serde_json::from_str(&json).unwrap();
+        let expected = Fragment {
+            line: 2,
+            col: 3,
+            ..fragment("a", "a.rs", "x\ny")
+        };
+        assert_eq!(read, expected);
+    }
+
+    #[test]
+    fn test_describe_config() {
+        let symbols = SymbolKey::default();
+        let own = symbols
+            .with_overrides(&SymbolOverrides {
+                insertion: Some(String::from("%%%")),
+                ..Default::default()
+            })
+            .unwrap();
+        let description = describe_config(
+            &WeaveOptions::default(),
+            &symbols,
+            vec![("a.md", &symbols), ("b.md", &own)],
+        );
+        assert!(
+            description.starts_with("WeaveOptions {\n"),
+            "{}",
+            description
+        );
+        assert!(description.contains("\nSymbols: SymbolKey {\n"));
+        assert!(!description.contains("Symbols for a.md"));
+        let b = description
+            .find("\nSymbols for b.md: SymbolKey {\n")
+            .unwrap();
+        assert!(description[b..].contains("insertion: \"%%%\""));
+        assert_eq!(description.matches("insertion: \"%%%\"").count(), 1);
+    }
+
+    #[test]
+    fn test_weave_errors() {
+        let annotations = annotations_of(vec![fragment("a", "a.rs", "x")]);
+        let symbols = SymbolKey::default();
+        let prose = "@@a\n@@missing\n@?a.nope\n@@a!bogus\n@?a.line";
+        let errors = weave_errors("doc.md", prose, &annotations, &symbols, &Default::default());
+        let found: Vec<(usize, &WeaveError)> =
+            errors.iter().map(|e| (e.line, &e.err_type)).collect();
+        assert_eq!(
+            found,
+            vec![
+                (2, &WeaveError::MissingFragment(String::from("missing"))),
+                (3, &WeaveError::UnknownProperty(String::from("nope"))),
+                (4, &WeaveError::BadRenderOption(String::from("bogus"))),
+            ]
+        );
+        let options = WeaveOptions {
+            syntax: Syntax::Mustache,
+            ..Default::default()
+        };
+        let prose = "{{verso:missing}} and {{verso:a}}\n{{verso:a\n{{verso:a.line}}";
+        let errors = weave_errors("doc.md", prose, &annotations, &symbols, &options);
+        let lines: Vec<usize> = errors.iter().map(|e| e.line).collect();
+        assert_eq!(lines, vec![1, 2]);
+
+        assert!(weave_errors("doc.md", "@@a", &annotations, &symbols, &options).is_empty());
+    }
+
+    #[test]
+    fn test_weave_mustache() {
+        let text = "{% raw %}@@1 stays put{% endraw %}
+Code: {{verso:1}}, from {{verso:1.file}} line {{verso:1.line}}.
+{{verso:1!wrap=80}}
+";
+
+        let frag = Fragment {
+            id: String::from("1"),
+            body: String::from("{Example Code}"),
+            file: String::from("example.code"),
+            line: 7,
+            col: 0,
+            ..Default::default()
+        };
+
+        let annotations = annotations_of(vec![frag]);
+        let options = WeaveOptions {
+            syntax: Syntax::Mustache,
+            ..Default::default()
+        };
+        let result =
+            weave_with_options("test", text, &annotations, &SymbolKey::default(), &options)
+                .expect("Expected weave to return Ok");
+
+        assert_eq!(
+            result,
+            "{% raw %}@@1 stays put{% endraw %}
+Code: {Example Code}, from example.code line 7.
+{Example Code}
+"
+        );
+
+        let result = weave_with_options(
+            "test",
+            "{{verso:missing}}",
+            &annotations,
+            &SymbolKey::default(),
+            &options,
+        );
+        match result {
+            Err(FileError {
+                err_type: WeaveError::MissingFragment(id),
+                ..
+            }) => assert_eq!(id, "missing"),
+            _ => panic!("Expected a missing fragment error, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_weave_final_newline() {
+        let frag = Fragment {
+            id: String::from("1"),
+            body: String::from("{Example Code}"),
+            ..Default::default()
+        };
+        let annotations = annotations_of(vec![frag]);
+
+        let cases = [
+            (FinalNewline::Preserve, "text\n", "text\n"),
+            (FinalNewline::Preserve, "text", "text"),
+            (FinalNewline::Preserve, "@@1\n", "{Example Code}\n"),
+            (FinalNewline::Preserve, "@@1", "{Example Code}"),
+            (FinalNewline::Always, "text\n", "text\n"),
+            (FinalNewline::Always, "text", "text\n"),
+            (FinalNewline::Always, "@@1\n", "{Example Code}\n"),
+            (FinalNewline::Always, "@@1", "{Example Code}\n"),
+            (FinalNewline::Never, "text\n", "text"),
+            (FinalNewline::Never, "text", "text"),
+            (FinalNewline::Never, "@@1\n", "{Example Code}"),
+            (FinalNewline::Never, "@@1", "{Example Code}"),
+        ];
+        for (final_newline, text, expected) in cases.iter() {
+            let options = WeaveOptions {
+                final_newline: *final_newline,
+                ..Default::default()
+            };
+            let result =
+                weave_with_options("test", text, &annotations, &SymbolKey::default(), &options)
+                    .expect("Expected weave to return Ok");
+            assert_eq!(
+                &result, expected,
+                "weaving {:?} with {:?}",
+                text, final_newline
+            );
+        }
+    }
+
+    #[test]
+    fn test_weave_final_newline_exact() {
+        let annotations = annotations_of(vec![
+            fragment("multi", "main.rs", "fn main() {\n    run();\n}"),
+            fragment("untrimmed", "main.rs", "x();\n"),
+        ]);
+        let weave_ending = |text: &str, syntax| {
+            let options = WeaveOptions {
+                trim: Some(TrimPolicy::None),
+                syntax,
+                ..Default::default()
+            };
+            weave_with_options("test", text, &annotations, &SymbolKey::default(), &options)
+                .expect("Expected weave to return Ok")
+        };
+
+        // Whatever the document ends with, its final newline is the prose's.
+        let cases = [
+            ("Intro\n@@multi", "Intro\nfn main() {\n    run();\n}"),
+            ("Intro\n@@multi\n", "Intro\nfn main() {\n    run();\n}\n"),
+            ("Intro\n@@untrimmed", "Intro\nx();"),
+            ("Intro\n@@untrimmed\n", "Intro\nx();\n"),
+            (
+                "@@multi\nIn @?multi.file",
+                "fn main() {\n    run();\n}\nIn main.rs",
+            ),
+            (
+                "@@multi\nIn @?multi.file\n",
+                "fn main() {\n    run();\n}\nIn main.rs\n",
+            ),
+        ];
+        for (text, expected) in cases.iter() {
+            assert_eq!(
+                weave_ending(text, Syntax::Symbols),
+                *expected,
+                "weaving {:?}",
+                text
+            );
+        }
+        assert_eq!(
+            weave_ending("{{verso:untrimmed}}", Syntax::Mustache),
+            "x();"
+        );
+        assert_eq!(
+            weave_ending("{{verso:untrimmed}}\n", Syntax::Mustache),
+            "x();\n"
+        );
+    }
+
+    #[test]
+    fn test_weave_snippet() {
+        let frag = Fragment {
+            id: String::from("1"),
+            body: String::from("one\ntwo\nthree"),
+            ..Default::default()
+        };
+        let annotations = annotations_of(vec![frag]);
+        let symbols = SymbolKey::default();
+
+        let result = weave("test", "Starts: @?1.snippet(2).", &annotations, &symbols)
+            .expect("Expected weave to return Ok");
+        assert_eq!(result, "Starts: one\ntwo....");
+
+        let options = WeaveOptions {
+            snippet_ellipsis: Some(String::from(" [more]")),
+            ..Default::default()
+        };
+        let result = weave_with_options("test", "@?1.snippet(1)", &annotations, &symbols, &options)
+            .expect("Expected weave to return Ok");
+        assert_eq!(result, "one [more]");
+
+        let result = weave("test", "@?1.snippet(5)", &annotations, &symbols)
+            .expect("Expected weave to return Ok");
+        assert_eq!(result, "one\ntwo\nthree");
+
+        match weave("test", "@?1.snippet(x)", &annotations, &symbols) {
+            Err(FileError {
+                err_type: WeaveError::BadMetadata(arg),
+                ..
+            }) => assert_eq!(arg, "x"),
+            result => panic!("Expected a bad metadata error, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_property_registry() {
+        let frag = Fragment {
+            id: String::from("1"),
+            body: String::from("one\ntwo\nthree"),
+            file: String::from("src/main.rs"),
+            ..Default::default()
+        };
+        let annotations = annotations_of(vec![frag]);
+        let symbols = SymbolKey::default();
+
+        let mut options = WeaveOptions::default();
+        options
+            .properties
+            .register("lines", |f, _| Ok(f.body.lines().count().to_string()));
+        options.properties.register_with_arg("FILE", |f, cx| {
+            Ok(format!("{}:{}", f.file, cx.arg.unwrap_or("?")))
+        });
+
+        let text = "@?1.lines lines in @?1.file(top), @?1.loc";
+        let result = weave_with_options("test", text, &annotations, &symbols, &options)
+            .expect("Expected weave to return Ok");
+        assert_eq!(result, "3 lines in src/main.rs:top, src/main.rs (0:0)");
+
+        let result = weave_with_options("test", "@?1.lines(2)", &annotations, &symbols, &options);
+        match result {
+            Err(FileError {
+                err_type: WeaveError::BadMetadata(arg),
+                message,
+                ..
+            }) => {
+                assert_eq!(arg, "2");
+                assert_eq!(
+                    message.as_deref(),
+                    Some("bad argument '2' for property 'lines(2)'")
+                );
+            }
+            result => panic!("Expected a bad argument error, got {:?}", result),
+        }
+
+        let result = weave_with_options(
+            "test",
+            "@?1.lines",
+            &annotations,
+            &symbols,
+            &WeaveOptions {
+                properties: PropertyRegistry::empty(),
+                ..Default::default()
+            },
+        );
+        match result {
+            Err(FileError {
+                err_type: WeaveError::UnknownProperty(prop),
+                ..
+            }) => assert_eq!(prop, "lines"),
+            result => panic!("Expected an unknown property error, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_weave_global_metadata() {
+        let text = "Built on @?@.date from @?@.root.";
+
+        let mut options = WeaveOptions::default();
+        options
+            .globals
+            .insert(String::from("date"), String::from("2020-01-01"));
+        options
+            .globals
+            .insert(String::from("root"), String::from("/src"));
+        let result = weave_with_options(
+            "test",
+            text,
+            &BTreeMap::new(),
+            &SymbolKey::default(),
+            &options,
+        )
+        .expect("Expected weave to return Ok");
+        assert_eq!(result, "Built on 2020-01-01 from /src.");
+
+        let result = weave_with_options(
+            "test",
+            "@?@.version",
+            &BTreeMap::new(),
+            &SymbolKey::default(),
+            &options,
+        );
+        match result {
+            Err(FileError {
+                err_type: WeaveError::UnknownProperty(prop),
+                ..
+            }) => assert_eq!(prop, "version"),
+            _ => panic!("Expected an unknown property error, got {:?}", result),
+        }
+
+        // Only `@` itself names the globals; it doesn't begin an ID.
+        let result = weave_with_options(
+            "test",
+            "@?@date.line",
+            &BTreeMap::new(),
+            &SymbolKey::default(),
+            &options,
+        );
+        match result {
+            Err(FileError {
+                err_type: WeaveError::BadMetadata(_),
+                ..
+            }) => {}
+            _ => panic!("Expected a malformed reference error, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_weave_bad_metadata_type() {
+        let text = "This is the first line!
+
+@?1.foo
+
+Another line.";
+
+        let frag = Fragment {
+            id: String::from("1"),
+            body: String::from("{Example Code}"),
+            file: String::from("example.code"),
+            line: 1,
+            col: 0,
+            ..Default::default()
+        };
+
+        let annotations = annotations_of(vec![frag]);
+
+        let err = weave("test", text, &annotations, &SymbolKey::default())
+            .expect_err("Expected weave to return an error");
+        match err {
+            FileError {
+                err_type: WeaveError::UnknownProperty(s),
+                line,
+                col,
+                ..
+            } => {
+                assert_eq!(line, 3, "Expected error on line 3, found line {:?}", line);
+                assert_eq!(col, 4, "Expected error on col 4, found col {:?}", col);
+                assert_eq!(
+                    s, "foo",
+                    "Expected error message to be \"foo\", got {:?}",
+                    s
+                );
+            }
+            _ => panic!("Expected WeaveError::UnknownProperty, got {:?}", err),
+        }
+    }
+
+    #[test]
+    fn test_weave_bad_metadata_id() {
+        let text = "This is the first line!
+
+@?1.loc
+
+Another line.";
+
+        let annotations = BTreeMap::new();
+
+        let err = weave("test", text, &annotations, &SymbolKey::default())
+            .expect_err("Expected weave to return an error");
+        match err {
+            FileError {
+                err_type: WeaveError::MissingFragment(s),
+                line,
+                col,
+                ..
+            } => {
+                assert_eq!(line, 3, "Expected error on line 3, found line {:?}", line);
+                assert_eq!(col, 2, "Expected error on col 2, found col {:?}", col);
+                assert_eq!(s, "1", "Expected error message to be \"1\", got {:?}", s);
+            }
+            _ => panic!("Expected WeaveError::MissingFragment, got {:?}", err),
+        }
+    }
+
+    #[test]
+    fn test_resolve_metadata() {
+        let frag = Fragment {
+            id: String::from("1"),
+            body: String::from("{Example Code}"),
+            file: String::from("src/example.code"),
+            line: 3,
+            col: 0,
+            index: 7,
+            ..Default::default()
+        };
+
+        let annotations = annotations_of(vec![frag]);
+        let symbols = SymbolKey::default();
+        let options = WeaveOptions::default();
+
+        assert_eq!(
+            resolve_metadata("1", "loc", &annotations, "test", &symbols, &options),
+            Ok(String::from("src/example.code (3:0)"))
+        );
+        assert_eq!(
+            resolve_metadata("1", "LINE", &annotations, "test", &symbols, &options),
+            Ok(String::from("3"))
+        );
+        assert_eq!(
+            resolve_metadata("1", "index", &annotations, "test", &symbols, &options),
+            Ok(String::from("7"))
+        );
+        assert_eq!(
+            resolve_metadata("1", "next", &annotations, "test", &symbols, &options),
+            Ok(String::new())
+        );
+        assert_eq!(
+            resolve_metadata("1", "foo", &annotations, "test", &symbols, &options),
+            Err(WeaveError::UnknownProperty(String::from("foo")))
+        );
+        assert_eq!(
+            resolve_metadata("2", "loc", &annotations, "test", &symbols, &options),
+            Err(WeaveError::MissingFragment(String::from("2")))
+        );
+
+        // Only properties which take an argument accept one.
+        assert_eq!(
+            resolve_metadata("1", "file(top)", &annotations, "test", &symbols, &options),
+            Err(WeaveError::BadMetadata(String::from("top")))
+        );
+        assert_eq!(
+            resolve_metadata("1", "snippet(1)", &annotations, "test", &symbols, &options),
+            Ok(String::from("{Example Code}"))
+        );
+
+        // Properties are resolved with the options given.
+        let mut options = WeaveOptions::default();
+        options
+            .properties
+            .register("owner", |f, _| Ok(format!("{}-owner", f.id)));
+        options.abspath_root = AbsPathRoot::Base(String::from("https://example.com/repo"));
+        let resolve = |prop| resolve_metadata("1", prop, &annotations, "test", &symbols, &options);
+        assert_eq!(resolve("owner"), Ok(String::from("1-owner")));
+        let abspath = String::from("https://example.com/repo/src/example.code");
+        assert_eq!(resolve("abspath"), Ok(abspath));
+        assert_eq!(
+            resolve("owner(x)"),
+            Err(WeaveError::BadMetadata(String::from("x")))
+        );
+    }
+
+    #[test]
+    fn test_resolve_metadata_neighbours() {
+        let ids = ["first", "second", "third"];
+        let annotations = annotations_of(ids.iter().enumerate().map(|(index, id)| Fragment {
+            index: index + 1,
+            ..fragment(id, "", "")
+        }));
+        let symbols = SymbolKey::default();
+        let options = WeaveOptions::default();
+        let resolve =
+            |id, prop| resolve_metadata(id, prop, &annotations, "test", &symbols, &options);
+
+        assert_eq!(resolve("second", "prev"), Ok(String::from("first")));
+        assert_eq!(resolve("second", "next"), Ok(String::from("third")));
+        assert_eq!(resolve("first", "prev"), Ok(String::new()));
+        assert_eq!(resolve("third", "next"), Ok(String::new()));
+    }
+
+    #[test]
+    fn test_find_relative_path() {
+        {
+            let a = std::path::PathBuf::from("/a/b/c/d.foo");
+            let b = std::path::PathBuf::from("/a/b/e/f/g.bar");
+            let rel_path = find_relative_path(&a, &b);
+            assert_eq!(rel_path, std::path::PathBuf::from("../e/f/g.bar"));
+        }
+
+        {
+            let a = std::path::PathBuf::from("/a/b/c/d.foo");
+            let b = std::path::PathBuf::from("/a/g.bar");
+            let rel_path = find_relative_path(&a, &b);
+            assert_eq!(rel_path, std::path::PathBuf::from("../../g.bar"));
+        }
+
+        {
+            let a = std::path::PathBuf::from("/a/b/c/d.foo");
+            let b = std::path::PathBuf::from("/e/f/g.bar");
+            let rel_path = find_relative_path(&a, &b);
+            assert_eq!(rel_path, std::path::PathBuf::from("../../../e/f/g.bar"));
+        }
+
+        {
+            let a = std::path::PathBuf::from(r"C:\docs\intro.md");
+            let b = std::path::PathBuf::from(r"D:\src\main.rs");
+            let rel_path = find_relative_path(&a, &b);
+            assert_eq!(rel_path, std::path::PathBuf::from(r"D:\src\main.rs"));
+        }
+
+        {
+            let a = std::path::PathBuf::from("/home/docs/intro.md");
+            let b = std::path::PathBuf::from("src/main.rs");
+            let rel_path = find_relative_path(&a, &b);
+            assert_eq!(rel_path, std::path::PathBuf::from("/src/main.rs"));
+
+            let rel_path = find_relative_path(&b, &a);
+            assert_eq!(rel_path, std::path::PathBuf::from("/home/docs/intro.md"));
+        }
+
+        // Paths are relative to the directory holding A, as a browser resolves links.
+        let relative = |a: &str, b: &str| find_relative_path(Path::new(a), Path::new(b));
+        assert_eq!(
+            relative("chap1.tex", "src/foo.rs"),
+            PathBuf::from("src/foo.rs")
+        );
+        assert_eq!(
+            relative("./chap1.tex", "src/foo.rs"),
+            PathBuf::from("src/foo.rs")
+        );
+        assert_eq!(
+            relative("docs/chap1.md", "docs/chap1.md"),
+            PathBuf::from("chap1.md")
+        );
+        assert_eq!(
+            relative("docs/book/ch.md", "./src/a.rs"),
+            PathBuf::from("../../src/a.rs")
+        );
+        assert_eq!(
+            relative("docs/ch.md", "docs/img/a.png"),
+            PathBuf::from("img/a.png")
+        );
+    }
+
+    #[test]
+    fn test_normalize_id() {
+        let symbols = SymbolKey::default();
+        let normalize = |id| normalize_id(id, &symbols);
+        assert_eq!(normalize("foo"), "foo");
+        assert_eq!(normalize("foo-"), "foo");
+        assert_eq!(normalize("/foo//"), "foo");
+        assert_eq!(normalize("foo--bar"), "foo-bar");
+        // Runs of different separators are alike, in any order.
+        assert_eq!(normalize("foo/_bar"), "foo-bar");
+        assert_eq!(normalize("foo_/bar"), "foo-bar");
+        assert_eq!(normalize("foo/bar"), "foo-bar");
+        assert_eq!(normalize("foo:bar"), "foo:bar");
+
+        // Separators added to the ID characters count, and the metadata separator never does.
+        let symbols = SymbolKey {
+            id_chars: String::from("/_-:."),
+            metadata_separator: '#',
+            ..SymbolKey::default()
+        };
+        assert_eq!(normalize_id("foo:bar.", &symbols), "foo-bar");
+        assert_eq!(normalize_id("foo#bar", &symbols), "foo#bar");
+    }
+
+    #[test]
+    fn test_find_similar_ids() {
+        let make_fragment = |id: &str, line| Fragment {
+            line,
+            ..fragment(id, "example.code", "{Example Code}")
+        };
+
+        let fragments = vec![
+            make_fragment("foo", 1),
+            make_fragment("bar", 5),
+            make_fragment("foo-", 10),
+            make_fragment("baz", 15),
+            make_fragment("baz", 20),
+            make_fragment("foo/", 25),
+            make_fragment("a/_b", 30),
+            make_fragment("a_/b", 35),
+        ];
+
+        let groups = find_similar_ids(&fragments, &SymbolKey::default());
+        let ids: Vec<Vec<&str>> = groups
+            .iter()
+            .map(|group| group.iter().map(|f| f.id.as_str()).collect())
+            .collect();
+        assert_eq!(ids, vec![vec!["a/_b", "a_/b"], vec!["foo", "foo-", "foo/"]]);
+
+        let groups = find_duplicate_ids(&fragments);
+        let lines: Vec<Vec<(&str, usize)>> = groups
+            .iter()
+            .map(|group| group.iter().map(|f| (f.id.as_str(), f.line)).collect())
+            .collect();
+        assert_eq!(lines, vec![vec![("baz", 15), ("baz", 20)]]);
+    }
+
+    #[test]
+    fn test_split_front_matter() {
+        let text = "---
+title: Example
+---
+Body text.
+";
+        let (front_matter, body) = split_front_matter(text).expect("Expected front matter");
+        assert_eq!(front_matter, "title: Example\n");
+        assert_eq!(body, "Body text.\n");
+
+        assert_eq!(split_front_matter("No front matter.\n---\n"), None);
+        assert_eq!(split_front_matter("---\nUnclosed front matter.\n"), None);
+    }
+
+    #[test]
+    fn test_symbols_with_overrides() {
+        let overrides = SymbolOverrides {
+            insertion: Some(String::from("%%")),
+            ..SymbolOverrides::default()
+        };
+        let symbols = SymbolKey::default().with_overrides(&overrides).unwrap();
+        assert_eq!(symbols.insertion, "%%");
+        assert_eq!(symbols.metadata, SymbolKey::default().metadata);
+
+        // Settings which can't be used are reported, wherever they come from.
+        let overrides = SymbolOverrides {
+            id_chars: Some(String::from("_ ")),
+            ..SymbolOverrides::default()
+        };
+        assert_eq!(
+            SymbolKey::default().with_overrides(&overrides),
+            Err(SymbolError::IdChars(String::from("_ ")))
+        );
+        // `@` is the ID of global metadata, so no fragment may have it.
+        let overrides = SymbolOverrides {
+            id_chars: Some(String::from("_@")),
+            ..SymbolOverrides::default()
+        };
+        assert_eq!(
+            SymbolKey::default().with_overrides(&overrides),
+            Err(SymbolError::IdChars(String::from("_@")))
+        );
+        let vars = |separator: &'static str, id_chars: &'static str| {
+            move |name: &str| match name {
+                "VERSO_METADATA_SEPARATOR" => Some(separator.to_owned()),
+                "VERSO_ID_CHARS" => Some(id_chars.to_owned()),
+                _ => None,
+            }
+        };
+        let symbols = SymbolKey::from_vars(vars("#", "._")).unwrap();
+        assert_eq!(
+            (symbols.metadata_separator, symbols.id_chars.as_str()),
+            ('#', "._")
+        );
+        for (separator, id_chars, message) in [
+            (
+                "##",
+                "_",
+                "Error: (VERSO_METADATA_SEPARATOR) invalid metadata separator '##'",
+            ),
+            (
+                "a",
+                "_",
+                "Error: (VERSO_METADATA_SEPARATOR) invalid metadata separator 'a'",
+            ),
+            (
+                "#",
+                "_:",
+                "Error: (VERSO_ID_CHARS) invalid ID characters '_:'",
+            ),
+        ] {
+            let err = SymbolKey::from_vars(vars(separator, id_chars)).unwrap_err();
+            assert_eq!(err.to_string(), message);
+        }
+    }
+
+    #[cfg(feature = "frontmatter")]
+    #[test]
+    fn test_weave_front_matter_symbols() {
+        let text = "---
+title: Example
+verso:
+  insertion: \"%%\"
+  root: https://example.com/repo
+---
+%%1
+@@1";
+
+        let frag = Fragment {
+            id: String::from("1"),
+            body: String::from("{Example Code}"),
+            file: String::from("example.code"),
+            line: 1,
+            col: 0,
+            ..Default::default()
+        };
+
+        let annotations = annotations_of(vec![frag]);
+
+        let (front_matter, body) = split_front_matter(text).expect("Expected front matter");
+        let symbols = symbols_from_front_matter(front_matter, &SymbolKey::default())
+            .expect("Expected front matter to parse");
+        let result =
+            weave("test", body, &annotations, &symbols).expect("Expected weave to return Ok");
+
+        assert_eq!(result, String::from("{Example Code}\n@@1"));
+
+        let root = abspath_root_from_front_matter(front_matter).unwrap();
+        let base = String::from("https://example.com/repo");
+        assert_eq!(root, Some(AbsPathRoot::Base(base)));
+        assert_eq!(abspath_root_from_front_matter("title: x\n").unwrap(), None);
+    }
+
+    #[test]
+    fn test_find_references() {
+        let text = "This is the first line!
+
+@@1
+  @@2
+@* [0-9]
+@?1.file (@?3.line:@?1.col)";
+
+        let references = find_references("test", text, &SymbolKey::default());
+        let found: Vec<(ReferenceKind, &str, usize)> = references
+            .iter()
+            .map(|r| (r.kind.clone(), r.target.as_str(), r.line))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                (ReferenceKind::Insertion, "1", 3),
+                (ReferenceKind::Insertion, "2", 4),
+                (ReferenceKind::Pattern, "[0-9]", 5),
+                (ReferenceKind::Metadata, "1", 6),
+                (ReferenceKind::Metadata, "3", 6),
+                (ReferenceKind::Metadata, "1", 6),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_find_undefined_metadata() {
+        let frag = Fragment {
+            id: String::from("1"),
+            body: String::from("{Example Code}"),
+            file: String::from("example.code"),
+            line: 1,
+            col: 0,
+            ..Default::default()
+        };
+
+        let annotations = annotations_of(vec![frag]);
+
+        let text = "@@2
+See @?1.loc and @?2.loc.
+
+@?3.file";
+        let references = find_references("test", text, &SymbolKey::default());
+        let undefined: Vec<(&str, usize)> = find_undefined_metadata(&annotations, &references)
+            .iter()
+            .map(|r| (r.target.as_str(), r.line))
+            .collect();
+
+        // The missing insertion is not a metadata reference, so it isn't reported.
+        assert_eq!(undefined, vec![("2", 2), ("3", 4)]);
+    }
+
+    #[test]
+    fn test_id_pattern() {
+        let pattern = IdPattern::new("[a-z]+/[a-z]+|main").unwrap();
+        let mut fragments = vec![
+            fragment("app/init", "a.rs", ""),
+            fragment("main", "a.rs", ""),
+        ];
+        assert_eq!(pattern.check(&fragments), Ok(()));
+
+        // Each ID must match in full, not just contain a match.
+        fragments.push(Fragment {
+            marker_line: 7,
+            col: 3,
+            ..fragment("app/init2", "b.rs", "")
+        });
+        fragments.push(fragment("Other", "b.rs", ""));
+        let errors = pattern.check(&fragments).unwrap_err();
+        let mismatched: Vec<&ParseError> = errors.iter().map(|e| &e.err_type).collect();
+        assert_eq!(
+            mismatched,
+            vec![
+                &ParseError::IdPatternMismatch(String::from("app/init2")),
+                &ParseError::IdPatternMismatch(String::from("Other")),
+            ]
+        );
+        let err = &errors[0];
+        assert_eq!((err.filename.as_str(), err.line, err.col), ("b.rs", 7, 3));
+
+        assert!(IdPattern::new("(unclosed").is_err());
+    }
+
+    #[test]
+    fn test_audit() {
+        let ids = ["1", "2", "a", "b", "unused"];
+        let annotations = annotations_of(ids.iter().map(|id| Fragment {
+            line: 1,
+            ..fragment(id, "example.code", "{Example Code}")
+        }));
+
+        let text = "@@1
+@?2.loc
+@* ^[ab]$
+@@missing
+@* ^nothing$";
+        let references = find_references("test", text, &SymbolKey::default());
+        let report = audit(&annotations, &references);
+
+        let unreferenced: Vec<&str> = report.unreferenced.iter().map(|f| f.id.as_str()).collect();
+        assert_eq!(unreferenced, vec!["unused"]);
+
+        let undefined: Vec<(&str, usize)> = report
+            .undefined
+            .iter()
+            .map(|r| (r.target.as_str(), r.line))
+            .collect();
+        assert_eq!(undefined, vec![("missing", 4), ("^nothing$", 5)]);
+        assert!(!report.is_clean());
+
+        // Mustache references are audited just the same.
+        let options = WeaveOptions {
+            syntax: Syntax::Mustache,
+            ..WeaveOptions::default()
+        };
+        let text = "{{verso:1}} {{verso:2.loc}} {{verso:a}}\n{{verso:b}} {{verso:missing}}";
+        let references =
+            find_references_with_options("test", text, &SymbolKey::default(), &options);
+        let report = audit(&annotations, &references);
+        assert_eq!(
+            report.to_string(),
+            "Fragments defined but never referenced:
+    unused (example.code:1)
+References to fragments that don't exist:
+    missing (test:2)"
+        );
+    }
+
+    #[test]
+    fn test_wrap_lines() {
+        let body = "The quick brown fox jumps over
+the lazy dog.
+
+    let indented = \"code that should not be touched by the wrapper\";
+Short.";
+
+        let (lines, from): (Vec<String>, Vec<Option<usize>>) =
+            wrap_lines(body, 16).into_iter().unzip();
+        assert_eq!(from, vec![None, None, None, Some(2), Some(3), None]);
+        assert_eq!(
+            lines.join("\n"),
+            "The quick brown
+fox jumps over
+the lazy dog.
+
+    let indented = \"code that should not be touched by the wrapper\";
+Short."
+        );
+    }
+
+    #[test]
+    fn test_weave_wrap() {
+        let text = "@@1!wrap=10";
+
+        let frag = Fragment {
+            id: String::from("1"),
+            body: String::from("one two three four five"),
+            file: String::from("example.code"),
+            line: 1,
+            col: 0,
+            ..Default::default()
+        };
+
+        let annotations = annotations_of(vec![frag]);
+        let result = weave("test", text, &annotations, &SymbolKey::default())
+            .expect("Expected weave to return Ok");
+
+        assert_eq!(result, "one two\nthree four\nfive");
+    }
+
+    #[test]
+    fn test_weave_bad_render_option() {
+        let frag = Fragment {
+            id: String::from("1"),
+            body: String::from("{Example Code}"),
+            file: String::from("example.code"),
+            line: 1,
+            col: 0,
+            ..Default::default()
+        };
+
+        let annotations = annotations_of(vec![frag]);
+
+        for (text, option) in &[
+            ("@@1!wrap=abc", "wrap=abc"),
+            ("@@1!frobnicate", "frobnicate"),
+        ] {
+            let err = weave("test", text, &annotations, &SymbolKey::default())
+                .expect_err("Expected weave to return an error");
+            match err {
+                FileError {
+                    err_type: WeaveError::BadRenderOption(s),
+                    ..
+                } => assert_eq!(&s, option),
+                _ => panic!("Expected WeaveError::BadRenderOption, got {:?}", err),
+            }
+        }
+    }
+
+    #[test]
+    fn test_deprecated_fragments() {
+        let text = "// @<old @deprecated=\"use new instead\"
+old();
+// >@
+// @<outer @<inner @deprecated=gone
+inner();
+// >@
+// >@";
+        let fragments = extract_fragments(text, "test", &SymbolKey::default()).unwrap();
+        let deprecated: Vec<(&str, Option<&str>)> = fragments
+            .iter()
+            .map(|f| (f.id.as_str(), f.deprecated.as_deref()))
+            .collect();
+        assert_eq!(
+            deprecated,
+            vec![
+                ("old", Some("use new instead")),
+                ("inner", Some("gone")),
+                ("outer", None)
+            ]
+        );
+
+        let annotations = annotations_of(fragments);
+        let prose = "@@old
+@?outer.line
+@?old.loc and @?inner.file
+@* ^o
+@@outer";
+        let references = find_references("test", prose, &SymbolKey::default());
+        let warnings: Vec<(usize, &str)> = find_deprecated_references(&annotations, &references)
+            .iter()
+            .map(|(r, f)| (r.line, f.id.as_str()))
+            .collect();
+        assert_eq!(
+            warnings,
+            vec![(1, "old"), (3, "old"), (3, "inner"), (4, "old")]
+        );
+
+        // Fragments kept under one ID give one warning per reference, and invalid patterns none.
+        let mut annotations = annotations;
+        let again = Fragment {
+            deprecated: Some(String::from("still old")),
+            ..fragment("old", "other", "old();")
+        };
+        annotations.get_mut("old").unwrap().push(again);
+        let mut references = find_references("test", "@@old\n@* ^o", &SymbolKey::default());
+        references.push(Reference {
+            kind: ReferenceKind::Pattern,
+            target: String::from("(unclosed"),
+            ..references[0].clone()
+        });
+        let warnings: Vec<(usize, &str, &str)> =
+            find_deprecated_references(&annotations, &references)
+                .iter()
+                .map(|(r, f)| (r.line, f.id.as_str(), f.deprecated.as_deref().unwrap()))
+                .collect();
+        assert_eq!(
+            warnings,
+            vec![(1, "old", "use new instead"), (2, "old", "use new instead")]
+        );
+    }
+
+    #[test]
+    fn test_weave_expand_tabs() {
+        let frag = Fragment {
+            id: String::from("1"),
+            body: String::from("fn main() {\n\tif x {\n\t\tf();\t// call\n  \t}\n}"),
+            ..Default::default()
+        };
+        let annotations = annotations_of(vec![frag]);
+        let weave_tabs = |prose: &str, expand_tabs| {
+            let options = WeaveOptions {
+                expand_tabs,
+                ..Default::default()
+            };
+            weave_with_options("test", prose, &annotations, &SymbolKey::default(), &options)
+                .unwrap()
+        };
+
+        assert_eq!(
+            weave_tabs("@@1!tabs=4", None),
+            "fn main() {\n    if x {\n        f();\t// call\n    }\n}"
+        );
+        assert_eq!(
+            weave_tabs("@@1!alltabs=2", None),
+            "fn main() {\n  if x {\n    f();  // call\n    }\n}"
+        );
+
+        // The option from `WeaveOptions` applies to every insertion but verbatim ones, unless the
+        // insertion gives its own.
+        let leading = Some(TabExpansion {
+            width: 8,
+            all: false,
+        });
+        assert_eq!(
+            weave_tabs("@@1\n@=1", leading),
+            "fn main() {\n        if x {\n                f();\t// call\n        }\n}\n\
+             fn main() {\n\tif x {\n\t\tf();\t// call\n  \t}\n}"
+        );
+        assert_eq!(
+            weave_tabs("@@1!alltabs=3", leading),
+            "fn main() {\n   if x {\n      f();  // call\n   }\n}"
+        );
+
+        match weave("test", "@@1!tabs=0", &annotations, &SymbolKey::default()) {
+            Err(FileError {
+                err_type: WeaveError::BadRenderOption(_),
+                ..
+            }) => {}
+            result => panic!("Expected WeaveError::BadRenderOption, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_to_fenced_block() {
+        let frag = Fragment {
+            id: String::from("1"),
+            body: String::from("fn main() {}"),
+            file: String::from("src/main.rs"),
+            ..Default::default()
+        };
+        assert_eq!(
+            frag.to_fenced_block(&FenceOptions::default()),
+            "```rust\nfn main() {}\n```"
+        );
+
+        let options = FenceOptions {
+            fence_char: '~',
+            info: Some(String::from("text")),
+            caption: Some(String::from("The entry point.")),
+        };
+        assert_eq!(
+            frag.to_fenced_block(&options),
+            "The entry point.\n\n~~~text\nfn main() {}\n~~~"
+        );
+
+        // A body holding a fence of its own gets a longer one, and unknown extensions no language.
+        let frag = Fragment {
+            body: String::from("Usage:\n\n  ````sh\n  run\n  ````"),
+            file: String::from("README.txt"),
+            ..frag
+        };
+        assert_eq!(
+            frag.to_fenced_block(&FenceOptions::default()),
+            "`````\nUsage:\n\n  ````sh\n  run\n  ````\n`````"
+        );
+        assert_eq!(
+            frag.to_fenced_block(&FenceOptions {
+                fence_char: '~',
+                ..Default::default()
+            }),
+            "~~~\nUsage:\n\n  ````sh\n  run\n  ````\n~~~"
+        );
+    }
+
+    #[test]
+    fn test_split_patch() {
+        let patch = "diff --git a/src/main.rs b/src/main.rs
+--- a/src/main.rs
++++ b/src/main.rs
+@@ -1,2 +1,4 @@
+ use std::io;
++// @<imports
++use std::fs;
+ 
+@@ -9,3 +11,4 @@ fn main() {
+-    old();
++    new();
+ }
++// >@
+ 
+--- a/gone.rs
++++ /dev/null
+@@ -1 +0,0 @@
+-// @<gone
+";
+        let files = split_patch(patch);
+        let names: Vec<&str> = files.iter().map(|(path, _)| path.as_str()).collect();
+        assert_eq!(names, vec!["src/main.rs"]);
+
+        // Lines between hunks are unknown, and left blank.
+        let (path, contents) = &files[0];
+        let fragments = extract_fragments(contents, path, &SymbolKey::default()).unwrap();
+        assert_eq!(fragments.len(), 1);
+        assert_eq!(
+            (fragments[0].id.as_str(), fragments[0].line),
+            ("imports", 3)
+        );
+        assert_eq!(
+            fragments[0].body,
+            "use std::fs;\n\n\n\n\n\n\n\n    new();\n}"
+        );
+        assert_eq!(contents.lines().nth(10), Some("    new();"));
+    }
+
+    #[test]
+    fn test_squeeze_blank_lines() {
+        let text = "# Title\n\n\n\nSome text.\n\n\n```\ncode();\n\n\n\nmore();\n```\n\n\n\nEnd.\n";
+        assert_eq!(
+            squeeze_blank_lines(text, 1),
+            "# Title\n\nSome text.\n\n```\ncode();\n\n\n\nmore();\n```\n\nEnd.\n"
+        );
+        assert_eq!(
+            squeeze_blank_lines(text, 2),
+            "# Title\n\n\nSome text.\n\n\n```\ncode();\n\n\n\nmore();\n```\n\n\nEnd.\n"
+        );
+
+        // A shorter or different fence inside a block doesn't close it, and whitespace-only lines
+        // count as blank.
+        let text = "~~~~\n```\n\n\n~~~\n\n\n~~~~\n \n\t\n\nx";
+        assert_eq!(
+            squeeze_blank_lines(text, 1),
+            "~~~~\n```\n\n\n~~~\n\n\n~~~~\n \nx"
+        );
+    }
+
+    #[test]
+    fn test_weave_namespaced_metadata() {
+        let in_a = Fragment {
+            line: 10,
+            ..fragment("main", "src/a.rs", "")
+        };
+        let in_b = Fragment {
+            line: 20,
+            ..fragment("main", "b.rs", "")
+        };
+        let annotations = annotations_of(vec![in_a, in_b]);
+        let weave_ns = |prose: &str| weave("test", prose, &annotations, &SymbolKey::default());
+
+        // The namespace may contain `.`, since it is split off before the property. Without one,
+        // the fragments are joined, and start where the first does.
+        assert_eq!(
+            weave_ns("@?src/a.rs::main.line, @?b.rs::main.line, @?main.line.").unwrap(),
+            "10, 20, 10."
+        );
+        assert_eq!(
+            find_references("test", "@?src/a.rs::main.loc", &SymbolKey::default())[0].target,
+            "main"
+        );
+
+        match weave_ns("@?c.rs::main.line") {
+            Err(FileError {
+                err_type: WeaveError::MissingFragment(_),
+                col,
+                ..
+            }) => assert_eq!(col, 8),
+            result => panic!("Expected WeaveError::MissingFragment, got {:?}", result),
+        }
+
+        // Only the first `::` separates the namespace, so a second one is part of the ID, which is
+        // malformed.
+        match weave_ns("@?a.rs::b.rs::main.line") {
+            Err(FileError {
+                err_type: WeaveError::BadMetadata(word),
+                ..
+            }) => assert_eq!(word, "a.rs::b.rs::main.line"),
+            result => panic!("Expected WeaveError::BadMetadata, got {:?}", result),
+        }
+
+        // A `::` which isn't followed by an ID doesn't start a namespace.
+        assert_eq!(weave_ns("@?main.line:: x").unwrap(), "10:: x");
+    }
+
+    #[test]
+    fn test_weave_with_header() {
+        let text = "// @<file
+use std::io;
+
+fn main() {
+    let x = 1;
+
+    // @<inner
+    println!(\"{}\", x);
+    // >@
+}
+// >@";
+        let symbols = SymbolKey::default();
+        let weave_header = |options: &ExtractOptions, prose: &str| {
+            let annotations = annotations_of(
+                extract_fragments_with_options(text, "main.rs", &symbols, options).unwrap(),
             );
+            weave("test", prose, &annotations, &symbols).unwrap()
+        };
+
+        let options = ExtractOptions::default();
+        assert_eq!(
+            weave_header(&options, "@@inner!withheader"),
+            "fn main() {\n    println!(\"{}\", x);"
+        );
+        assert_eq!(
+            weave_header(&options, "@@inner!source!withheader"),
+            "// from main.rs:8\nfn main() {\n    println!(\"{}\", x);"
+        );
+        // Fragments which aren't nested have no header to find.
+        assert_eq!(
+            weave_header(&options, "@@file!withheader"),
+            weave_header(&options, "@@file")
+        );
+
+        // The header loses as much indentation as a dedented fragment did, where it has any.
+        let options = ExtractOptions {
+            dedent_nested: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            weave_header(&options, "@@inner!withheader"),
+            "fn main() {\nprintln!(\"{}\", x);"
+        );
+    }
+
+    #[test]
+    fn test_indexed_store() {
+        let dir = std::env::temp_dir().join(format!("verso-store-{}", std::process::id()));
+        let fragments: Vec<Fragment> = ["a", "b", "c", "d", "b", "ba"]
+            .iter()
+            .enumerate()
+            .map(|(i, id)| Fragment {
+                index: i + 1,
+                ..fragment(id, &format!("{}.rs", i + 1), &format!("body {}", i + 1))
+            })
+            .collect();
+        IndexedStore::write(&dir, &fragments).expect("Expected IndexedStore::write to return Ok");
+        let store = IndexedStore::open(&dir).expect("Expected IndexedStore::open to return Ok");
+
+        // The indexed store answers just as the in-memory one does, keeping every fragment with a
+        // reused ID.
+        let in_memory = annotations_of(fragments.clone());
+        let bodies = |fragments: Vec<Fragment>| -> Vec<String> {
+            fragments.into_iter().map(|f| f.body).collect()
+        };
+        for backend in [&store as &dyn FragmentStore, &in_memory] {
+            assert_eq!(bodies(backend.get("b").unwrap()), vec!["body 2", "body 5"]);
+            assert!(backend.get("missing").unwrap().is_empty());
+            for (pattern, expected) in [
+                ("^[bc]$", vec!["b", "b", "c"]),
+                ("^b", vec!["b", "b", "ba"]),
+            ] {
+                let matched = backend.matching(&Regex::new(pattern).unwrap()).unwrap();
+                let ids: Vec<String> = matched.into_iter().map(|f| f.id).collect();
+                assert_eq!(ids, expected);
+            }
+            assert_eq!(backend.by_index(3).unwrap().unwrap().id, "c");
+            assert_eq!(backend.by_index(5).unwrap().unwrap().file, "5.rs");
+        }
+
+        // Only referenced fragments and their neighbours are loaded, whichever syntax refers to them.
+        let references = find_references("test", "@@c\n", &SymbolKey::default());
+        let loaded = load_referenced(&store, &references).unwrap();
+        let ids: Vec<&str> = loaded.keys().map(String::as_str).collect();
+        assert_eq!(ids, vec!["b", "c", "d"]);
+        assert_eq!(loaded["b"].len(), 2);
+        let options = WeaveOptions {
+            syntax: Syntax::Mustache,
+            ..WeaveOptions::default()
+        };
+        let prose = "See {{verso:a!wrap=40}} and {{verso:5.rs::ba.file}}.\n";
+        let references =
+            find_references_with_options("test", prose, &SymbolKey::default(), &options);
+        let loaded = load_referenced(&store, &references).unwrap();
+        let ids: Vec<&str> = loaded.keys().map(String::as_str).collect();
+        assert_eq!(ids, vec!["a", "b", "ba"]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_load_referenced_with_header() {
+        let text = "// @<main
+fn main() {
+    // @<inner
+    run();
+    // >@
+    // @<a
+    a();
+    // >@
+    // @<b
+    b();
+    // >@
+}
+// >@";
+        let symbols = SymbolKey::default();
+        let mut fragments = extract_fragments(text, "main.rs", &symbols).unwrap();
+        fragments.push(fragment("other", "other.rs", "other();"));
+        number_fragments(&mut fragments, 1);
+        let dir = std::env::temp_dir().join(format!("verso-store-header-{}", std::process::id()));
+        IndexedStore::write(&dir, &fragments).unwrap();
+        let store = IndexedStore::open(&dir).unwrap();
+
+        // The parent closes after its other children, so it isn't a neighbour of `inner`, but is
+        // loaded all the same. Fragments from other files are not.
+        let prose = "@@inner!withheader";
+        let loaded = load_referenced(&store, &find_references("test", prose, &symbols)).unwrap();
+        let ids: Vec<&str> = loaded.keys().map(String::as_str).collect();
+        assert_eq!(ids, vec!["a", "inner", "main"]);
+        let in_memory = annotations_of(fragments);
+        let woven = weave("test", prose, &loaded, &symbols).unwrap();
+        assert_eq!(woven, weave("test", prose, &in_memory, &symbols).unwrap());
+        assert_eq!(woven, "fn main() {\n    run();");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_literal_prefix() {
+        assert_eq!(literal_prefix("^listing/intro"), "listing/intro");
+        assert_eq!(literal_prefix("^listing/[0-9]+"), "listing/");
+        assert_eq!(literal_prefix("^abc*"), "ab");
+        assert_eq!(literal_prefix("^a|^b"), "");
+        assert_eq!(literal_prefix("listing"), "");
+    }
+
+    #[test]
+    fn test_weave_line_range() {
+        let frag = Fragment {
+            id: String::from("main"),
+            body: String::from("fn main() {\n    let x = 1;\n    let y = 2;\n    f(x, y);\n}"),
+            file: String::from("main.rs"),
+            line: 10,
+            ..Default::default()
+        };
+        let annotations = annotations_of(vec![frag]);
+        let symbols = SymbolKey::default();
+
+        let result = weave(
+            "test",
+            "@@main:2-3\n@=main:5\n@@@main:4!source",
+            &annotations,
+            &symbols,
+        )
+        .unwrap();
+        assert_eq!(
+            result,
+            "    let x = 1;\n    let y = 2;\n}\n```rust\n// from main.rs:13\n    f(x, y);\n```"
+        );
+
+        let options = WeaveOptions {
+            syntax: Syntax::Mustache,
+            ..Default::default()
+        };
+        let result = weave_with_options(
+            "test",
+            "a {{verso:main:1}} b",
+            &annotations,
+            &symbols,
+            &options,
+        );
+        assert_eq!(result.unwrap(), "a fn main() { b");
+
+        for range in &["0", "2-6", "4-3"] {
+            let prose = format!("@@main:{}", range);
+            match weave("test", &prose, &annotations, &symbols) {
+                Err(FileError {
+                    err_type: WeaveError::RangeOutOfBounds(id, r),
+                    ..
+                }) => assert_eq!((id.as_str(), r.as_str()), ("main", *range)),
+                result => panic!("Expected WeaveError::RangeOutOfBounds, got {:?}", result),
+            }
+        }
+
+        // A suffix which isn't a range leaves a reserved character in the ID.
+        match weave("test", "@@main:x", &annotations, &symbols) {
+            Err(FileError {
+                err_type: WeaveError::IdExtractError,
+                ..
+            }) => {}
+            result => panic!("Expected WeaveError::IdExtractError, got {:?}", result),
         }
+
+        let references = find_references("test", "@@main:2-3!wrap=20", &symbols);
+        assert_eq!(references[0].target, "main");
     }
 
     #[test]
-    fn test_extract_id_offset() {
-        let id = extract_id("foo bar baz quuz", 4);
-        assert_eq!(
-            id.expect("Expected successful ID extraction"),
-            String::from("bar")
+    fn test_end_line() {
+        let text = "// @<outer
+first();
+// @<inner
+second();
+
+// >@
+// >@";
+        let symbols = SymbolKey::default();
+        let fragments = extract_fragments(text, "main.rs", &symbols).unwrap();
+        let lines: Vec<(&str, usize, usize)> = fragments
+            .iter()
+            .map(|f| (f.id.as_str(), f.line, f.end_line))
+            .collect();
+        assert_eq!(lines, vec![("inner", 4, 5), ("outer", 2, 6)]);
+
+        // Fragments from older versions of verso have no end line, so one is worked out.
+        let old: Fragment =
+            serde_json::from_str(r#"{"body":"a\nb","id":"old","file":"x","line":3,"col":0}"#)
+                .unwrap();
+        assert_eq!(old.end_line, 0);
+
+        let annotations = annotations_of(fragments.into_iter().chain(Some(old)));
+        let result = weave(
+            "test",
+            "lines @?inner.line-@?inner.endline, @?old.line-@?old.endline",
+            &annotations,
+            &symbols,
         );
+        assert_eq!(result.unwrap(), "lines 4-5, 3-4");
     }
 
     #[test]
-    fn test_extract_pattern_good() {
-        let pattern = extract_pattern("[a-z0-9]+", 0);
-        assert!(pattern
-            .expect("Expected successful pattern extraction")
-            .is_match("abc123"));
+    fn test_dedent() {
+        let text = "class A:
+    def f(self):
+        # @<f
+        if x:
+            return 1
+
+        return 2
+        # >@
+";
+        let options = ExtractOptions {
+            dedent: true,
+            ..Default::default()
+        };
+        let fragments =
+            extract_fragments_with_options(text, "a.py", &SymbolKey::default(), &options).unwrap();
+        assert_eq!(fragments[0].body, "if x:\n    return 1\n\nreturn 2");
+
+        // Blank lines keep their whitespace, and tabs don't count as spaces.
+        let mut fragment = Fragment {
+            body: String::from("\t  a\n\t \n\t    b"),
+            ..Default::default()
+        };
+        dedent(&mut fragment);
+        assert_eq!(fragment.body, "a\n\t \n  b");
+
+        let mut fragment = Fragment {
+            body: String::from("\tmixed\n    spaces"),
+            ..Default::default()
+        };
+        dedent(&mut fragment);
+        assert_eq!(fragment.body, "\tmixed\n    spaces");
     }
 
     #[test]
-    fn test_extract_pattern_missing() {
-        let pattern =
-            extract_pattern("   ", 0).expect_err("Expected error extracting empty pattern");
+    fn test_weave_indented_insertion() {
+        let frag = Fragment {
+            id: String::from("1"),
+            body: String::from("fn main() {\n\n    f();\n}"),
+            ..Default::default()
+        };
+        let annotations = annotations_of(vec![frag]);
+        let symbols = SymbolKey::default();
+
+        // Blank lines in the body aren't given trailing whitespace.
+        let result = weave(
+            "test",
+            "- Item:\n\n    @@1\n\t@* ^1$",
+            &annotations,
+            &symbols,
+        );
         assert_eq!(
-            pattern,
-            PatternExtractError::NoPatternFound,
-            "Expected NoPatternFound, got {:?}",
-            pattern
+            result.unwrap(),
+            "- Item:\n\n    fn main() {\n\n        f();\n    }\n\tfn main() {\n\n\t    f();\n\t}"
         );
-    }
 
-    #[test]
-    fn test_extract_pattern_invalid() {
-        let pattern =
-            extract_pattern("{[}]", 0).expect_err("Expected error extracting invalid pattern");
-        assert!(
-            matches!(pattern, PatternExtractError::RegexConstruction(_)),
-            "Expected RegexConstruction, got {:?}",
-            pattern
+        let result = weave("test", "  @@@1\n  @=1", &annotations, &symbols);
+        assert_eq!(
+            result.unwrap(),
+            "  ```\n  fn main() {\n\n      f();\n  }\n  ```\nfn main() {\n\n    f();\n}"
         );
     }
 
     #[test]
-    fn test_extract_fragments() {
-        let fragments: Result<Vec<Fragment>, FileError<ParseError>> = extract_fragments(
-            "# This is an example
-import sys
+    fn test_reopened_fragment() {
+        let text = "// @<point
+struct Point {
+    x: i32,
+}
+// >@
 
-# @<foobarbaz The fragment starts and its ID is defined on this line; it is foobarbaz.
-def main():
-    do_stuff()
-    make_awesome()
-    # >@ This line ends the fragment.
-    sys.exit(1) # oops",
-            "test.py",
-            &SymbolKey::default(),
-        );
+fn unrelated() {}
 
-        let fragments = fragments.expect("Expected no parse errors");
-        assert!(
-            fragments.len() == 1,
-            "Expected one fragment, found {}",
-            fragments.len()
-        );
-        assert!(
-            fragments[0].body
-                == *"def main():
-    do_stuff()
-    make_awesome()",
-            "Unexpected code fragment {:?}",
-            fragments[0].body
-        );
-        assert!(
-            fragments[0].id == *"foobarbaz",
-            "Unexpected ID {:?}",
-            fragments[0].id
+// @<point
+impl Point {
+    fn new() -> Point { Point { x: 0 } }
+}
+// >@";
+        let fragments = extract_fragments(text, "point.rs", &SymbolKey::default()).unwrap();
+        assert_eq!(fragments.len(), 1);
+        let point = &fragments[0];
+        assert_eq!(
+            point.body,
+            "struct Point {\n    x: i32,\n}\nimpl Point {\n    fn new() -> Point { Point { x: 0 } }\n}"
         );
+        assert_eq!((point.line, point.end_line), (2, 12));
     }
 
     #[test]
-    fn test_extract_fragments_nested() {
-        let fragments: Result<Vec<Fragment>, FileError<ParseError>> = extract_fragments(
-            "# This is an example.
-    # @<foobarbaz This begins the fragment.
-    Start of outer
-    # @<qux
-        Start of inner
-            # @<quux
-            # >@
-        End of inner
-    # >@
-    End of outer
-    # >@",
-            "test.py",
-            &SymbolKey::default(),
+    fn test_extract_fragments_all() {
+        let text = "// >@
+// @<first
+one
+// >@
+// @<
+two
+// >@
+// @<unclosed
+three";
+        let symbols = SymbolKey::default();
+        let errors = match extract_fragments_all(text, "errors.rs", &symbols) {
+            Err(errors) => errors,
+            Ok(fragments) => panic!("Expected errors, got {:?}", fragments),
+        };
+        let found: Vec<(ParseError, usize)> = errors
+            .iter()
+            .map(|e| (e.err_type.clone(), e.line))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                (ParseError::CloseBeforeOpen, 1),
+                (ParseError::MissingId, 5),
+                (ParseError::UnclosedFragment, 9),
+            ]
         );
 
-        let fragments = fragments.expect("Expected no parse errors");
-        assert!(
-            fragments.len() == 3, // Two fragments in addition to the full file
-            "Expected two fragments, found {}",
-            fragments.len()
-        );
-        // Innermost, empty fragment
-        assert_eq!(fragments[0].body, "", "Expected fragment to be empty");
-        assert!(
-            fragments[0].id == *"quux",
-            "Unexpected ID {:?}",
-            fragments[0].id
-        );
-        // Middle fragment, that has content, including all child fragments with tags removed.
+        // The single-error version still reports only the first.
+        match extract_fragments(text, "errors.rs", &symbols) {
+            Err(FileError {
+                err_type: ParseError::CloseBeforeOpen,
+                line: 1,
+                ..
+            }) => (),
+            other => panic!("Expected ParseError::CloseBeforeOpen, got {:?}", other),
+        }
+
+        let fine = "// @<a\na\n// >@";
         assert_eq!(
-            fragments[1].body,
-            "        Start of inner
-        End of inner",
-            "Expected nested fragment markers to be removed"
+            extract_fragments_all(fine, "fine.rs", &symbols)
+                .unwrap()
+                .len(),
+            1
         );
-        assert!(
-            fragments[1].id == *"qux",
-            "Unexpected ID {:?}",
-            fragments[1].id
+    }
+
+    #[test]
+    fn test_named_close() {
+        let symbols = SymbolKey::default();
+        let text = "// @<outer\n// @<inner\nx\n// >@inner\n// >@ outer, described\n";
+        let fragments = extract_fragments(text, "named.rs", &symbols).unwrap();
+        assert_eq!(fragments.len(), 2);
+
+        let text = "// @<outer\n// @<inner\nx\n// >@outer\n// >@\n";
+        match extract_fragments(text, "named.rs", &symbols) {
+            Err(FileError {
+                err_type: ParseError::MismatchedClose(expected, found),
+                line: 4,
+                col: 3,
+                ..
+            }) => assert_eq!((expected.as_str(), found.as_str()), ("inner", "outer")),
+            other => panic!("Expected ParseError::MismatchedClose, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_inline_fragment() {
+        let symbols = SymbolKey::default();
+        let text = "// @<outer
+let x = 5; // @<constx 5 >@constx
+let y = x;
+// >@";
+        let fragments = extract_fragments(text, "inline.rs", &symbols).unwrap();
+        let constx = fragments.iter().find(|f| f.id == "constx").unwrap();
+        assert_eq!(constx.body, "5");
+        assert_eq!((constx.line, constx.end_line), (2, 2));
+        assert_eq!((constx.marker_line, constx.col), (2, 23));
+        assert_eq!(constx.open_prefix.as_deref(), Some("let x = 5; //"));
+        // The parent keeps the code on the marker's line, rather than the child's text.
+        let outer = fragments.iter().find(|f| f.id == "outer").unwrap();
+        assert_eq!((outer.marker_line, outer.line), (1, 2));
+        assert_eq!(outer.body, "let x = 5;\nlet y = x;");
+
+        let text = "# @<outer\n    # @<n 1 >@\n    y = n # @<m 2 >@\n# >@\n";
+        let fragments = extract_fragments(text, "inline.py", &symbols).unwrap();
+        let outer = fragments.iter().find(|f| f.id == "outer").unwrap();
+        assert_eq!(outer.body, "    y = n");
+
+        match extract_fragments("// @<a 1 >@b\n", "inline.rs", &symbols) {
+            Err(FileError {
+                err_type: ParseError::MismatchedClose(..),
+                line: 1,
+                col: 9,
+                ..
+            }) => (),
+            other => panic!("Expected ParseError::MismatchedClose, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_symbols_from_file() {
+        let dir = std::env::temp_dir().join(format!("verso-symbols-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(SYMBOL_FILE);
+
+        std::fs::write(&path, "fragment_open = \"%<\"\nfragment_close = \">%\"\n").unwrap();
+        let symbols = SymbolKey::from_file(&path).unwrap();
+        let fragments = extract_fragments("* %<abap\nWRITE 'x'.\n* >%", "a.abap", &symbols);
+        assert_eq!(fragments.unwrap()[0].id, "abap");
+        // Anything not in the file keeps its default.
+        assert_eq!(symbols.insertion, SymbolKey::default().insertion);
+
+        std::fs::write(&path, "[language_names]\n\".CBL\" = \"COBOL\"\n").unwrap();
+        let symbols = SymbolKey::from_file(&path).unwrap();
+        let names: Vec<_> = symbols.language_names().collect();
+        assert_eq!(names, vec![(String::from("cbl"), "COBOL")]);
+
+        std::fs::write(&path, "fragment_open = 5\n").unwrap();
+        assert!(SymbolKey::from_file(&path).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_extract_fragments_reader() {
+        let symbols = SymbolKey::default();
+        let options = ExtractOptions::default();
+        let text = "fn main() {\r\n    // @<body\r\n    run();\r\n    // >@\r\n}\r\n// @<open\n\n";
+        let streamed = extract_fragments_reader(text.as_bytes(), "big.rs", &symbols, &options);
+        assert_eq!(
+            streamed.unwrap(),
+            extract_fragments_all_with_warnings(text, "big.rs", &symbols, &options)
         );
-        // Outer fragment, that has content, including all child fragments with tags removed.
+
+        let closed = "// @<a\nx\n// >@\n";
+        let (fragments, _) =
+            extract_fragments_reader(closed.as_bytes(), "a.rs", &symbols, &options)
+                .unwrap()
+                .unwrap();
         assert_eq!(
-            fragments[2].body,
-            "    Start of outer
-        Start of inner
-        End of inner
-    End of outer",
-            "Expected nested fragment markers to be removed"
+            fragments,
+            extract_fragments(closed, "a.rs", &symbols).unwrap()
         );
-        assert!(
-            fragments[2].id == *"foobarbaz",
-            "Unexpected ID {:?}",
-            fragments[2].id
+
+        let binary: &[u8] = b"// @<a\n\xff\xfe\n// >@\n";
+        let err = extract_fragments_reader(binary, "a.bin", &symbols, &options).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_weave_fence_language() {
+        let annotations = annotations_of(vec![
+            fragment("main", "src/main.rs", "body"),
+            fragment("tool", "tool.py", "body"),
+            fragment("notes", "notes", "body"),
+        ]);
+        let symbols = SymbolKey::default();
+        let prose = "@@@main\n@@@notes\n@@tool";
+
+        let result = weave("doc.md", prose, &annotations, &symbols).unwrap();
+        assert_eq!(result, "```rust\nbody\n```\n```\nbody\n```\nbody");
+
+        let mut options = WeaveOptions {
+            fence_languages: BTreeMap::from([(String::from("py"), String::from("python3"))]),
+            ..Default::default()
+        };
+        let result = weave_with_options("doc.md", "@@@tool", &annotations, &symbols, &options);
+        assert_eq!(result.unwrap(), "```python3\nbody\n```");
+
+        options.fence_all = true;
+        let result = weave_with_options("doc.md", prose, &annotations, &symbols, &options).unwrap();
+        assert_eq!(
+            result,
+            "```rust\nbody\n```\n```\nbody\n```\n```python3\nbody\n```"
         );
     }
 
     #[test]
-    fn test_extract_fragments_close_before_open() {
-        let fragments: Result<Vec<Fragment>, FileError<ParseError>> = extract_fragments(
-            "# This is an example.
+    fn test_weave_size_metadata() {
+        let frag = Fragment {
+            id: String::from("main"),
+            body: String::from("fn main() {\n    println!(\"héllo\");\n}"),
+            ..Default::default()
+        };
+        let annotations = annotations_of(vec![frag]);
+        let symbols = SymbolKey::default();
 
-# >@ This is an error on line 3.
-012345 The error is at column 2.
-# @< This begins the fragment.
-# >@ This line ends the fragment.",
-            "test.py",
-            &SymbolKey::default(),
+        let result = weave(
+            "test",
+            "This @?main.lines-line function has @?main.chars characters.",
+            &annotations,
+            &symbols,
+        )
+        .unwrap();
+        assert_eq!(result, "This 3-line function has 36 characters.");
+    }
+
+    #[test]
+    fn test_weave_lang_metadata() {
+        let annotations = annotations_of(vec![
+            fragment("main", "src/main.rs", ""),
+            fragment("report", "zreport.ABAP", ""),
+            fragment("q", "q.kdb", ""),
+        ]);
+        let symbols = SymbolKey::default();
+        let prose = "Listing 3 (@?main.lang), @?report.lang, @?q.lang";
+
+        let result = weave("test", prose, &annotations, &symbols).unwrap();
+        assert_eq!(result, "Listing 3 (Rust), ABAP, kdb");
+
+        let options = WeaveOptions {
+            language_names: BTreeMap::from([(String::from("kdb"), String::from("q"))]),
+            ..Default::default()
+        };
+        let result = weave_with_options("test", prose, &annotations, &symbols, &options).unwrap();
+        assert_eq!(result, "Listing 3 (Rust), ABAP, q");
+
+        // Fences know the same languages.
+        assert_eq!(fence_language("zreport.ABAP"), Some("abap"));
+    }
+
+    #[test]
+    fn test_weave_pattern_sort() {
+        let steps = [
+            ("step/a", "b.rs", 1),
+            ("step/b", "a.rs", 9),
+            ("step/c", "a.rs", 2),
+        ];
+        let annotations = annotations_of(steps.iter().map(|&(id, file, line)| Fragment {
+            line,
+            ..fragment(id, file, id)
+        }));
+        let symbols = SymbolKey::default();
+
+        let result = weave("test", "@* ^step/ sort=loc", &annotations, &symbols).unwrap();
+        assert_eq!(result, "step/c\nstep/b\nstep/a");
+        let result = weave("test", "@* ^step/ sort=id", &annotations, &symbols).unwrap();
+        assert_eq!(result, "step/a\nstep/b\nstep/c");
+        let result = weave("test", "@* ^step/", &annotations, &symbols).unwrap();
+        assert_eq!(result, "step/a\nstep/b\nstep/c");
+
+        match weave("test", "@* ^step/ sort=size", &annotations, &symbols) {
+            Err(FileError {
+                err_type: WeaveError::PatternExtractError,
+                ..
+            }) => (),
+            other => panic!("Expected WeaveError::PatternExtractError, got {:?}", other),
+        }
+
+        let references = find_references("test", "@* ^step/ sort=loc", &symbols);
+        assert_eq!(references[0].target, "^step/");
+    }
+
+    #[test]
+    fn test_weave_pattern_separator() {
+        let examples = [("ex/a", 3), ("ex/b", 2), ("ex/c", 1)];
+        let annotations = annotations_of(examples.iter().map(|&(id, line)| Fragment {
+            line,
+            ..fragment(id, "", id)
+        }));
+        let symbols = SymbolKey::default();
+
+        let result = weave("test", "@* ^ex/ sep=\"---\"", &annotations, &symbols).unwrap();
+        assert_eq!(result, "ex/a\n---\nex/b\n---\nex/c");
+        let prose = "  @* ^ex/ sep=\"\\t\\\"x\\\" y\" sort=loc";
+        let result = weave("test", prose, &annotations, &symbols).unwrap();
+        assert_eq!(result, "  ex/c\n  \t\"x\" y\n  ex/b\n  \t\"x\" y\n  ex/a");
+        // A separator of two newlines leaves two blank lines between the fragments.
+        let result = weave("test", "@* ^ex/ sep=\"\\n\\n\"", &annotations, &symbols).unwrap();
+        assert_eq!(result, "ex/a\n\n\nex/b\n\n\nex/c");
+
+        assert_eq!(unescape(r"a\n\\n\q\"), "a\n\\n\\q\\");
+    }
+
+    #[test]
+    fn test_weave_escaped_symbols() {
+        let frag = Fragment {
+            id: String::from("main"),
+            body: String::from("fn main() {}"),
+            file: String::from("src/main.rs"),
+            ..Default::default()
+        };
+        let annotations = annotations_of(vec![frag]);
+        let symbols = SymbolKey::default();
+        let prose = "\\@@main inserts @?main.file.\n  \\@@@?flag:main\n\\@* ^m\nWrite \\@?main.file for the file.\n@@main";
+
+        let result = weave("doc.md", prose, &annotations, &symbols).unwrap();
+        assert_eq!(
+            result,
+            "@@main inserts @?main.file.\n  @@@?flag:main\n@* ^m\nWrite @?main.file for the file.\nfn main() {}"
         );
 
-        let fragments = fragments.expect_err("Expected a parsing error");
-        match fragments {
-            FileError {
-                err_type: ParseError::CloseBeforeOpen,
-                line,
-                col,
+        let references = find_references("doc.md", prose, &symbols);
+        assert_eq!(references.len(), 1);
+        assert_eq!(references[0].line, 5);
+    }
+
+    #[test]
+    fn test_weave_insertion_default() {
+        let annotations = annotations_of(vec![fragment("done", "", "done();")]);
+        let symbols = SymbolKey::default();
+        let prose = "@@done ?? \"not used\"\n  @@mainfn ?? \"TODO: \\\"main\\\"\"\n@@@other!source ?? later";
+
+        let result = weave("test", prose, &annotations, &symbols).unwrap();
+        assert_eq!(result, "done();\n  TODO: \"main\"\nlater");
+
+        let options = WeaveOptions {
+            ignore_defaults: true,
+            ..Default::default()
+        };
+        match weave_with_options("test", prose, &annotations, &symbols, &options) {
+            Err(FileError {
+                err_type: WeaveError::MissingFragment(id),
+                line: 2,
                 ..
-            } => {
-                assert_eq!(line, 3, "Expected error on line 3, found line {:?}", line);
-                assert_eq!(col, 2, "Expected error on col 2, found col {:?}", col);
-            }
-            _ => panic!("Expected ParseError::CloseBeforeOpen, got {:?}", fragments),
+            }) => assert_eq!(id, "mainfn"),
+            other => panic!("Expected WeaveError::MissingFragment, got {:?}", other),
         }
     }
 
     #[test]
-    fn test_extract_fragments_halt() {
-        let fragments: Result<Vec<Fragment>, FileError<ParseError>> = extract_fragments(
-            "# This is an example.
+    fn test_crlf_line_endings() {
+        let symbols = SymbolKey::default();
+        let lf = "fn main() {\n    // @<body\n    run();\n    // >@body\n    // @<inline x >@inline\n}\n";
+        let crlf = lf.replace('\n', "\r\n");
+        let from_lf = extract_fragments(lf, "main.rs", &symbols).unwrap();
+        let from_crlf = extract_fragments(&crlf, "main.rs", &symbols).unwrap();
+        assert_eq!(from_crlf, from_lf);
+        assert_eq!(from_crlf[0].body, "    run();");
+        assert_eq!(from_crlf[1].body, "x");
 
-# @<1
-Fragment 1
-# >@
+        // Errors point at the same place either way.
+        let bad = "// @<a\r\nx\r\n  // >@b\r\n";
+        let from_lf = extract_fragments(&bad.replace("\r\n", "\n"), "a.rs", &symbols);
+        assert_eq!(extract_fragments(bad, "a.rs", &symbols), from_lf);
 
-# @!halt This line causes the reader to stop looking for fragments in this file.
-# Mostly this is useful for keeping Verso out of uninteresting areas, like these tests.
+        let annotations = annotations_of(from_crlf);
+        let result = weave("doc.md", "Code:\r\n@@body\r\n", &annotations, &symbols).unwrap();
+        assert_eq!(result, "Code:\r\n    run();\r\n");
+        let result = weave("doc.md", "Code:\n@@body\n", &annotations, &symbols).unwrap();
+        assert_eq!(result, "Code:\n    run();\n");
+    }
+
+    #[test]
+    fn test_multibyte_columns() {
+        let symbols = SymbolKey::default();
+        let text = "// é 🦀 @<accented\nx\n// ☃ >@other\n";
+        match extract_fragments(text, "multibyte.rs", &symbols) {
+            Err(FileError {
+                err_type: ParseError::MismatchedClose(_, _),
+                line: 3,
+                col: 5,
+                ..
+            }) => {}
+            other => panic!("Expected ParseError::MismatchedClose, got {:?}", other),
+        }
+
+        let symbols = SymbolKey {
+            insertion: String::from("§§"),
+            ..Default::default()
+        };
+        let annotations = BTreeMap::new();
+        match weave("multibyte.md", "§§missing\n", &annotations, &symbols) {
+            Err(FileError {
+                err_type: WeaveError::MissingFragment(id),
+                col: 2,
+                ..
+            }) => assert_eq!(id, "missing"),
+            other => panic!("Expected WeaveError::MissingFragment, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_require_comment_prefix() {
+        let text = "// @<quoted
+let close = \">@\";
+let open = '@<';
+x // @<inline
+// >@ quoted
+";
+        let symbols = SymbolKey {
+            require_comment_prefix: true,
+            ..Default::default()
+        };
+        let fragments = extract_fragments(text, "quoted.rs", &symbols).unwrap();
+        assert_eq!(fragments.len(), 1);
+        assert_eq!(
+            fragments[0].body,
+            "let close = \">@\";\nlet open = '@<';\nx // @<inline"
+        );
+
+        // Without the flag, the string literal closes the fragment.
+        let fragments = extract_fragments(text, "quoted.rs", &SymbolKey::default());
+        assert!(fragments.is_err());
+    }
+
+    #[test]
+    fn test_sort_by_location() {
+        let text = "// @<outer
+let x = 5; // @<constx 5 >@
+// @<inner
+let y = x;
+// >@
+// >@
+// @<after
+z
+// >@";
+        let mut fragments = extract_fragments(text, "sorted.rs", &SymbolKey::default()).unwrap();
+        let ids: Vec<&str> = fragments.iter().map(|f| f.id.as_str()).collect();
+        assert_eq!(ids, vec!["constx", "inner", "outer", "after"]);
+
+        sort_by_location(&mut fragments);
+        let ids: Vec<&str> = fragments.iter().map(|f| f.id.as_str()).collect();
+        assert_eq!(ids, vec!["outer", "constx", "inner", "after"]);
+    }
 
-# >@ This would cause an error.",
-            "test.py",
-            &SymbolKey::default(),
-        );
+    #[test]
+    fn test_number_fragments() {
+        let text = "// @<outer\n// @<inner\nx\n// >@\n// >@\n// @<after\ny\n// >@\n";
+        let mut fragments = extract_fragments(text, "a.rs", &SymbolKey::default()).unwrap();
+        number_fragments(&mut fragments, 3);
+        // Fragments are numbered in the order they close, which sorting doesn't change.
+        sort_by_location(&mut fragments);
+        let numbered: Vec<(&str, usize)> =
+            fragments.iter().map(|f| (f.id.as_str(), f.index)).collect();
+        assert_eq!(numbered, vec![("outer", 4), ("inner", 3), ("after", 5)]);
 
-        let fragments = fragments.expect("Expected a clean read");
-        assert!(
-            fragments.len() == 1,
-            "Expected one fragment, found {}",
-            fragments.len()
+        let sources = [("a.rs", text), ("b.rs", "// @<b\nz\n// >@\n")];
+        let woven = process(
+            &sources,
+            "doc.md",
+            "@?inner.index @?b.index @?b.prev",
+            &SymbolKey::default(),
         );
+        assert_eq!(woven.unwrap(), "1 4 after");
     }
 
     #[test]
-    fn test_extract_fragments_halt_while_open() {
-        let fragments: Result<Vec<Fragment>, FileError<ParseError>> = extract_fragments(
-            "# This is an example.
-# @<1
-Fragment 1
-# @!halt This line causes an error as we have an open Fragment.
-# >@",
-            "test.py",
+    fn test_weave_with_source_map() {
+        let annotations = annotations_of(vec![Fragment {
+            line: 2,
+            ..fragment("a", "s.rs", "x\ny\nz")
+        }]);
+        let prose = "# Title\n@@@a\n  @@a:2-3\nThe end: @?a.file\n";
+        let (woven, _, source_map) = weave_with_source_map(
+            "doc.md",
+            prose,
+            &annotations,
             &SymbolKey::default(),
+            &WeaveOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            woven,
+            "# Title\n```rust\nx\ny\nz\n```\n  y\n  z\nThe end: s.rs\n"
+        );
+        let source = |line| Some((String::from("s.rs"), line));
+        assert_eq!(
+            source_map,
+            vec![
+                (1, None),
+                (2, None),
+                (3, source(2)),
+                (4, source(3)),
+                (5, source(4)),
+                (6, None),
+                (7, source(3)),
+                (8, source(4)),
+                (9, None),
+            ]
         );
 
-        let fragments = fragments.expect_err("Expected a parsing error");
-        match fragments {
-            FileError {
-                err_type: ParseError::HaltWhileOpen,
-                line,
-                col,
-                ..
-            } => {
-                assert_eq!(line, 4, "Expected error on line 4, found line {:?}", line);
-                assert_eq!(col, 2, "Expected error on col 2, found col {:?}", col);
-            }
-            _ => panic!("Expected ParseError::HaltWhileOpen, got {:?}", fragments),
-        }
+        // Repeated lines map to the lines which were inserted, not the first with the same text.
+        let annotations = annotations_of(vec![Fragment {
+            line: 10,
+            ..fragment("a", "s.rs", "fn a() {\n}\nfn b() {\n}")
+        }]);
+        let options = WeaveOptions::default();
+        let symbols = SymbolKey::default();
+        let (woven, _, source_map) = weave_with_source_map(
+            "doc.md",
+            "@@a:4-4\n@@a:3-4\n",
+            &annotations,
+            &symbols,
+            &options,
+        )
+        .unwrap();
+        assert_eq!(woven, "}\nfn b() {\n}\n");
+        assert_eq!(
+            source_map,
+            vec![(1, source(13)), (2, source(12)), (3, source(13))]
+        );
     }
 
     #[test]
-    fn test_extract_fragments_missing_id() {
-        let fragments: Result<Vec<Fragment>, FileError<ParseError>> = extract_fragments(
-            "# This is an example.
+    fn test_extract_fragments_resume() {
+        let text = "# @<1
+Fragment 1
+# >@
+# @!halt
+# >@ Skipped, so not an error.
+# RESUME
+# @<2
+Fragment 2
+# >@
+# @!halt
+# @<3
+Never closed, but never read either."
+            .replace("RESUME", RESUME_SYMBOL);
+        let fragments = extract_fragments(&text, "test.py", &SymbolKey::default()).unwrap();
+        let bodies: Vec<&str> = fragments.iter().map(|f| f.body.as_str()).collect();
+        assert_eq!(bodies, vec!["Fragment 1", "Fragment 2"]);
+    }
 
-# @< This is an error on line 3: No ID.
-012345 The error is at column 2.
-# >@ This line ends the fragment.",
-            "test.py",
+    #[test]
+    fn test_extract_fragments_skip() {
+        let text = "fn f() { // @<f
+    step();
+    // SKIP
+    log();
+    // SKIP
+    trace();
+    // END
+    // @<g
+    assert!(ready());
+    // END
+    go();
+    // >@
+} // >@
+// END"
+            .replace("SKIP", SKIP_SYMBOL)
+            .replace("END", END_SKIP_SYMBOL);
+        let (fragments, warnings) = extract_fragments_with_warnings(
+            &text,
+            "skip.rs",
             &SymbolKey::default(),
-        );
-
-        let fragments = fragments.expect_err("Expected a parsing error");
-        match fragments {
-            FileError {
-                err_type: ParseError::MissingId,
-                line,
-                col,
-                ..
-            } => {
-                assert_eq!(line, 3, "Expected error on line 3, found line {:?}", line);
-                assert_eq!(col, 2, "Expected error on col 2, found col {:?}", col);
-            }
-            _ => panic!("Expected ParseError::MissingId, got {:?}", fragments),
-        }
+            &ExtractOptions::default(),
+        )
+        .unwrap();
+        let body = |id| &fragments.iter().find(|f| f.id == id).unwrap().body;
+        assert_eq!(body("f"), "    step();\n    go();");
+        assert_eq!(body("g"), "    go();");
+        assert_eq!(warnings.len(), 1);
+        assert_eq!((warnings[0].line, warnings[0].col), (14, 3));
     }
 
     #[test]
-    fn test_weave_good() {
-        let text = "This is the first line!
+    fn test_extract_fragments_skip_replacement() {
+        let text = "// @<f
+fn f() {
+    // SKIP \"// ...\" logging
+    log();
+    // SKIP \"unused\"
+    trace();
+    // END
+    // END
+    go();
+}
+// >@"
+            .replace("SKIP", SKIP_SYMBOL)
+            .replace("END", END_SKIP_SYMBOL);
+        let fragments = extract_fragments(&text, "skip.rs", &SymbolKey::default()).unwrap();
+        assert_eq!(fragments[0].body, "fn f() {\n    // ...\n    go();\n}");
+    }
 
-@@1
-  @@1
-@* [0-9]
-@?1.file (@?1.line:@?1.col)
-@?1.loc
+    #[test]
+    fn test_extract_fragments_source_lines() {
+        let text = concat!(
+            "// @<outer\n\nfn a() {\n    // @<inner\n    b();\n    // >@\n",
+            "    // SKIP\n    c();\n    // END\n    d();\n}\n// >@",
+        )
+        .replace("SKIP", SKIP_SYMBOL)
+        .replace("END", END_SKIP_SYMBOL);
+        let options = ExtractOptions {
+            source_lines: true,
+            trim: TrimPolicy::Full,
+            ..Default::default()
+        };
+        let fragments =
+            extract_fragments_with_options(&text, "main.rs", &SymbolKey::default(), &options)
+                .expect("Expected extract_fragments to return Ok");
+        let inner = fragments.iter().find(|f| f.id == "inner").unwrap();
+        assert_eq!(inner.source_lines, vec![5]);
+        let outer = fragments.iter().find(|f| f.id == "outer").unwrap();
+        assert_eq!(outer.body.lines().count(), outer.source_lines.len());
+        assert_eq!(outer.source_lines, vec![3, 5, 10, 11]);
 
-Another line.";
+        // Without the option, nothing is recorded or serialized.
+        let fragments = extract_fragments(&text, "main.rs", &SymbolKey::default()).unwrap();
+        assert!(fragments.iter().all(|f| f.source_lines.is_empty()));
+        let json = serde_json::to_string(&fragments[0]).unwrap();
+        assert!(!json.contains("source_lines"), "{}", json);
+    }
 
+    #[test]
+    fn test_weave_line_numbers() {
+        let weave_lines = |fragment: Fragment, prose: &str, options| {
+            let annotations = annotations_of(vec![fragment]);
+            weave_with_options("test", prose, &annotations, &SymbolKey::default(), &options)
+                .unwrap()
+        };
         let frag = Fragment {
-            id: String::from("1"),
-            body: String::from("{Example Code}"),
-            file: String::from("example.code"),
-            line: 1,
-            col: 0,
+            line: 8,
+            ..fragment("mainfn", "", "fn main() {\n\n    run();\n}\n\n")
         };
-
-        let mut annotations = BTreeMap::new();
-        annotations.insert(frag.id.to_owned(), frag);
-        let result = weave("test", text, &annotations, &SymbolKey::default())
-            .expect("Expected weave to return Ok");
-
+        let untrimmed = WeaveOptions {
+            trim: Some(TrimPolicy::None),
+            ..Default::default()
+        };
+        // The body's final newline ends the woven file, in place of the prose's, so the body's
+        // blank last line is all that follows it.
         assert_eq!(
-            result,
-            String::from(
-                "This is the first line!
-
-{Example Code}
-{Example Code}
-{Example Code}
-example.code (1:0)
-example.code (1:0)
+            weave_lines(frag, "@@mainfn!lines\n", untrimmed),
+            " 8 | fn main() {\n 9 |\n10 |     run();\n11 | }\n\n"
+        );
 
-Another line."
-            )
+        // The option can apply to every insertion, and follows the recorded source lines.
+        let frag = Fragment {
+            line: 3,
+            source_lines: vec![3, 97],
+            ..fragment("mainfn", "", "a();\nb();")
+        };
+        let numbered = WeaveOptions {
+            line_numbers: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            weave_lines(frag, "@@mainfn\n", numbered),
+            " 3 | a();\n97 | b();\n"
         );
     }
 
     #[test]
-    fn test_weave_pattern_order() {
-        let text = "@* [0-9]";
-
-        let frag1 = Fragment {
-            id: String::from("1"),
-            body: String::from("{Example Code 1}"),
-            file: String::from("example.code"),
-            line: 1,
-            col: 0,
-        };
-
-        let frag2 = Fragment {
-            id: String::from("2"),
-            body: String::from("{Example Code 2}"),
-            file: String::from("example.code"),
-            line: 2,
-            col: 0,
+    fn test_metadata_separator() {
+        let symbols = SymbolKey {
+            metadata_separator: '#',
+            ..Default::default()
         };
+        let text = "// @<api.v2\nfn get() {}\n// >@";
+        let fragments = extract_fragments(text, "api.rs", &symbols).unwrap();
+        assert_eq!(fragments[0].id, "api.v2");
+        // The default separator is still reserved.
+        assert!(extract_fragments(text, "api.rs", &SymbolKey::default()).is_err());
 
-        let mut annotations = BTreeMap::new();
-        annotations.insert(frag2.id.to_owned(), frag2);
-        annotations.insert(frag1.id.to_owned(), frag1);
-        let result = weave("test", text, &annotations, &SymbolKey::default())
-            .expect("Expected weave to return Ok");
+        let annotations = annotations_of(fragments);
+        let prose = "@@api.v2\nSee @?api.v2#file, line @?api.v2#line.\n";
+        let woven = weave("prose.md", prose, &annotations, &symbols).unwrap();
+        assert_eq!(woven, "fn get() {}\nSee api.rs, line 2.\n");
 
+        // The separator itself is the one reserved character.
         assert_eq!(
-            result,
-            String::from(
-                "{Example Code 1}
-{Example Code 2}"
-            )
+            extract_id_with_symbols("api#v2", 0, &symbols),
+            Err(IdExtractError::ReservedCharacterUsed('#'))
+        );
+        let overrides = SymbolOverrides {
+            metadata_separator: Some('a'),
+            ..Default::default()
+        };
+        assert_eq!(
+            symbols.with_overrides(&overrides),
+            Err(SymbolError::MetadataSeparator(String::from("a")))
         );
     }
 
     #[test]
-    fn test_weave_missing_fragment() {
-        let text = "This is the first line!
+    fn test_id_chars() {
+        let symbols = SymbolKey {
+            metadata_separator: '#',
+            id_chars: String::from("._"),
+            ..Default::default()
+        };
+        assert_eq!(
+            extract_id_with_symbols("module.sub_module.func", 0, &symbols),
+            Ok(String::from("module.sub_module.func"))
+        );
+        assert_eq!(
+            extract_id_with_symbols("module-func", 0, &symbols),
+            Err(IdExtractError::ReservedCharacterUsed('-'))
+        );
+        // Listing the separator doesn't allow it in IDs.
+        let symbols = SymbolKey {
+            id_chars: String::from("._"),
+            ..Default::default()
+        };
+        assert_eq!(
+            extract_id_with_symbols("module.func", 0, &symbols),
+            Err(IdExtractError::ReservedCharacterUsed('.'))
+        );
 
-@@1
+        assert!(is_id_charset("./-_+"));
+        assert!(!is_id_charset("a b"));
+        assert!(!is_id_charset(":"));
+    }
 
-Another line.";
+    #[test]
+    fn test_warn_empty() {
+        let text = concat!(
+            "// @<empty\n// >@\n  # @<blank\n\n   \n// >@\n// @<full\nx();\n// >@\n",
+            "x(); // @<inline >@"
+        );
+        let options = ExtractOptions {
+            warn_empty: true,
+            ..Default::default()
+        };
+        let (fragments, warnings) =
+            extract_fragments_with_warnings(text, "main.rs", &SymbolKey::default(), &options)
+                .unwrap();
+        assert_eq!(fragments.len(), 4);
+        // Each warning points at the fragment's open symbol.
+        let warned: Vec<(usize, usize, &str)> = warnings
+            .iter()
+            .map(|w| (w.line, w.col, w.message.as_str()))
+            .collect();
+        assert_eq!(
+            warned,
+            vec![
+                (1, 3, "fragment 'empty' is empty"),
+                (3, 4, "fragment 'blank' is empty"),
+                (10, 8, "fragment 'inline' is empty")
+            ]
+        );
 
-        let annotations = BTreeMap::new();
-        weave("test", text, &annotations, &SymbolKey::default())
-            .expect_err("Expected weave to return an error");
+        let (_, warnings) = extract_fragments_with_warnings(
+            text,
+            "main.rs",
+            &SymbolKey::default(),
+            &ExtractOptions::default(),
+        )
+        .unwrap();
+        assert!(warnings.is_empty());
     }
 
     #[test]
-    fn test_weave_bad_metadata_type() {
-        let text = "This is the first line!
-
-@?1.foo
+    fn test_weave_relpath_between_fragments() {
+        let annotations = annotations_of(vec![
+            fragment("client", "src/net/client.rs", ""),
+            fragment("server", "src/net/server.rs", ""),
+            fragment("util", "src/util.rs", ""),
+        ]);
+        let weave_line =
+            |prose: &str| weave("docs/net.md", prose, &annotations, &SymbolKey::default());
 
-Another line.";
+        assert_eq!(
+            weave_line("[util](@?client.relpath.util), [server](@?client.relpath.server).")
+                .unwrap(),
+            "[util](../util.rs), [server](server.rs)."
+        );
+        // Both fragments count as referenced.
+        let references = find_references(
+            "docs/net.md",
+            "@?util.relpath.client",
+            &SymbolKey::default(),
+        );
+        let targets: Vec<&str> = references.iter().map(|r| r.target.as_str()).collect();
+        assert_eq!(targets, vec!["util", "client"]);
+        let err = weave_line("See @?client.lines.util").unwrap_err();
+        assert_eq!(
+            err.err_type,
+            WeaveError::BadMetadata("client.lines.util".to_owned())
+        );
+        let err = weave_line("See @?client.relpath.missing").unwrap_err();
+        assert_eq!(
+            err.err_type,
+            WeaveError::MissingFragment("missing".to_owned())
+        );
+        assert_eq!(err.col, 21);
+    }
 
+    #[test]
+    fn test_abspath_root() {
         let frag = Fragment {
-            id: String::from("1"),
-            body: String::from("{Example Code}"),
-            file: String::from("example.code"),
-            line: 1,
-            col: 0,
+            id: String::from("main"),
+            file: String::from("src/main.rs"),
+            ..Default::default()
+        };
+        let annotations = annotations_of(vec![frag]);
+        let abspath = |abspath_root| {
+            let options = WeaveOptions {
+                abspath_root,
+                ..Default::default()
+            };
+            let symbols = SymbolKey::default();
+            weave_with_options("doc.md", "@?main.abspath", &annotations, &symbols, &options)
         };
 
-        let mut annotations = BTreeMap::new();
-        annotations.insert(frag.id.to_owned(), frag);
+        assert_eq!(abspath(AbsPathRoot::Slash).unwrap(), "/src/main.rs");
+        let base = AbsPathRoot::Base(String::from("https://example.com/repo/"));
+        assert_eq!(
+            abspath(base).unwrap(),
+            "https://example.com/repo/src/main.rs"
+        );
+        // The platform's separators become forward slashes under either root.
+        let file = ["src", "main.rs"].join(std::path::MAIN_SEPARATOR_STR);
+        assert_eq!(AbsPathRoot::Slash.join(&file).unwrap(), "/src/main.rs");
+        let base = AbsPathRoot::Base(String::from("https://example.com/repo"));
+        assert_eq!(
+            base.join(&file).unwrap(),
+            "https://example.com/repo/src/main.rs"
+        );
 
-        let err = weave("test", text, &annotations, &SymbolKey::default())
-            .expect_err("Expected weave to return an error");
-        match err {
-            FileError {
-                err_type: WeaveError::UnknownProperty(s),
-                line,
-                col,
-                ..
-            } => {
-                assert_eq!(line, 3, "Expected error on line 3, found line {:?}", line);
-                assert_eq!(col, 4, "Expected error on col 4, found col {:?}", col);
-                assert_eq!(
-                    s, "foo",
-                    "Expected error message to be \"foo\", got {:?}",
-                    s
-                );
-            }
-            _ => panic!("Expected WeaveError::UnknownProperty, got {:?}", err),
-        }
+        let canonical = std::fs::canonicalize("Cargo.toml").unwrap();
+        assert_eq!(
+            AbsPathRoot::Filesystem.join("Cargo.toml"),
+            Ok(canonical.to_string_lossy().into_owned())
+        );
+        assert_eq!(
+            AbsPathRoot::Filesystem.join("no/such/file.rs"),
+            Err(WeaveError::BadMetadata(String::from("no/such/file.rs")))
+        );
     }
 
     #[test]
-    fn test_weave_bad_metadata_id() {
-        let text = "This is the first line!
-
-@?1.loc
-
-Another line.";
-
-        let annotations = BTreeMap::new();
-
-        let err = weave("test", text, &annotations, &SymbolKey::default())
-            .expect_err("Expected weave to return an error");
-        match err {
-            FileError {
-                err_type: WeaveError::MissingFragment(s),
-                line,
-                col,
-                ..
-            } => {
-                assert_eq!(line, 3, "Expected error on line 3, found line {:?}", line);
-                assert_eq!(col, 2, "Expected error on col 2, found col {:?}", col);
-                assert_eq!(s, "1", "Expected error message to be \"1\", got {:?}", s);
-            }
-            _ => panic!("Expected WeaveError::MissingFragment, got {:?}", err),
-        }
+    fn test_fragments_schema() {
+        let schema = serde_json::to_value(fragments_schema()).unwrap();
+        assert_eq!(schema["type"], "array");
+        let fragment = &schema["definitions"]["Fragment"];
+        // Fields added since 0.3.0 have defaults, so older output still validates.
+        let required: Vec<&str> = fragment["required"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter_map(|field| field.as_str())
+            .collect();
+        assert_eq!(required, vec!["body", "col", "file", "id", "line"]);
+        assert!(fragment["properties"]["end_line"].is_object());
     }
 
     #[test]
-    fn test_find_relative_path() {
-        {
-            let a = std::path::PathBuf::from("/a/b/c/d.foo");
-            let b = std::path::PathBuf::from("/a/b/e/f/g.bar");
-            let rel_path = find_relative_path(&a, &b);
-            assert_eq!(rel_path, std::path::PathBuf::from("../e/f/g.bar"));
-        }
+    fn test_process() {
+        let symbols = SymbolKey::default();
+        let sources = [
+            ("a.rs", "// @<greet\nhello();\n// >@\n"),
+            ("b.rs", "// @<part\nbye();\n// >@\n"),
+        ];
+        let woven = process(
+            &sources,
+            "doc.md",
+            "@@greet\n@@part\n@?part.index\n",
+            &symbols,
+        )
+        .expect("Expected process to return Ok");
+        assert_eq!(woven, "hello();\nbye();\n2\n");
 
-        {
-            let a = std::path::PathBuf::from("/a/b/c/d.foo");
-            let b = std::path::PathBuf::from("/a/g.bar");
-            let rel_path = find_relative_path(&a, &b);
-            assert_eq!(rel_path, std::path::PathBuf::from("../../g.bar"));
-        }
+        // The same ID in two sources is ambiguous.
+        let sources = [
+            ("a.rs", "// @<greet\nhello();\n// >@\n"),
+            ("b.rs", "\n// @<greet\nhi();\n// >@\n"),
+        ];
+        let err = process(&sources, "doc.md", "@@greet\n", &symbols).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "fragment 'greet' is defined more than once, at a.rs:1, b.rs:2"
+        );
+        // Fragments from older versions of verso, without a marker line, give the line before.
+        let old = Fragment {
+            line: 2,
+            ..fragment("greet", "c.rs", "")
+        };
+        assert_eq!(fragment_location(&old), "c.rs:1");
 
-        {
-            let a = std::path::PathBuf::from("/a/b/c/d.foo");
-            let b = std::path::PathBuf::from("/e/f/g.bar");
-            let rel_path = find_relative_path(&a, &b);
-            assert_eq!(rel_path, std::path::PathBuf::from("../../../e/f/g.bar"));
-        }
+        // Errors in the prose name the prose file.
+        let sources = [("a.rs", "// @<greet\nhello();\n// >@\n")];
+        let err = process(&sources, "doc.md", "@@missing\n", &symbols).unwrap_err();
+        assert!(err.to_string().contains("doc.md"), "{}", err);
     }
 }
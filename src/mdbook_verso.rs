@@ -0,0 +1,227 @@
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process;
+
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use ignore::WalkBuilder;
+use serde_json::Value;
+
+use verso::{extract_fragments, number_fragments, weave, Annotations, SymbolKey};
+
+// The renderers whose input is the Markdown that weaving produces.
+const SUPPORTED_RENDERERS: &[&str] = &["html", "markdown"];
+
+// An mdbook preprocessor which weaves fragments into each chapter, as `recto` does. Configure it in
+// `book.toml` with the source files to extract fragments from, relative to the book's root:
+//
+//     [preprocessor.verso]
+//     sources = ["src/**/*.rs"]
+fn main() {
+    let args: Vec<String> = env::args().collect();
+
+    // mdbook asks whether each renderer is supported before running the preprocessor, and skips it
+    // for those which aren't.
+    if args.get(1).map(String::as_str) == Some("supports") {
+        let renderer = args.get(2).map_or("", String::as_str);
+        process::exit(if supports(renderer) { 0 } else { 1 });
+    }
+
+    if let Err(e) = run() {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
+
+fn supports(renderer: &str) -> bool {
+    SUPPORTED_RENDERERS.contains(&renderer)
+}
+
+pub fn run() -> Result<(), Box<dyn Error>> {
+    // mdbook sends a `[context, book]` pair, and expects just the book back.
+    let (context, mut book): (Value, Value) = serde_json::from_reader(io::stdin())?;
+
+    let root = Path::new(context["root"].as_str().unwrap_or("."));
+    let sources = source_globs(&context["config"]["preprocessor"]["verso"])?;
+    let symbols = SymbolKey::from_project()?;
+    let annotations = read_annotations(root, &sources, &symbols)?;
+
+    // mdbook 0.4 calls the book's top-level items `sections`, and later versions `items`.
+    let items = if book.get("sections").is_some() {
+        "sections"
+    } else {
+        "items"
+    };
+    weave_items(&mut book[items], &annotations, &symbols)?;
+
+    serde_json::to_writer(io::stdout(), &book)?;
+    Ok(())
+}
+
+// The globs in the `sources` list of the preprocessor's table in `book.toml`.
+fn source_globs(config: &Value) -> Result<GlobSet, Box<dyn Error>> {
+    let mut globs = GlobSetBuilder::new();
+    let sources = config["sources"]
+        .as_array()
+        .ok_or("Expected a list of source globs in [preprocessor.verso] sources")?;
+    for source in sources {
+        let source = source
+            .as_str()
+            .ok_or("Expected each of [preprocessor.verso] sources to be a string")?;
+        globs.add(Glob::new(source)?);
+    }
+    Ok(globs.build()?)
+}
+
+// Extract the fragments from every file under `root` which matches `sources`, skipping those which
+// `.gitignore` or `.ignore` files exclude. Fragments which share an ID are joined, as by `recto`.
+fn read_annotations(
+    root: &Path,
+    sources: &GlobSet,
+    symbols: &SymbolKey,
+) -> Result<Annotations, Box<dyn Error>> {
+    let mut annotations = Annotations::new();
+    let mut count = 0;
+    let walk = WalkBuilder::new(root)
+        .require_git(false)
+        .sort_by_file_name(|a, b| a.cmp(b))
+        .build();
+    for entry in walk {
+        let entry = entry?;
+        let relative = entry.path().strip_prefix(root)?;
+        if !entry.file_type().is_some_and(|t| t.is_file()) || !sources.is_match(relative) {
+            continue;
+        }
+        let filename = relative.to_string_lossy();
+        let contents = fs::read_to_string(entry.path())?;
+        let mut fragments = extract_fragments(&contents, &filename, symbols)?;
+        // Number the fragments as `verso` would, so that `@?id.index` and its neighbours agree.
+        number_fragments(&mut fragments, count + 1);
+        count += fragments.len();
+        for f in fragments {
+            annotations.entry(f.id.to_owned()).or_default().push(f);
+        }
+    }
+    Ok(annotations)
+}
+
+// Weave each chapter in a list of book items, and their sub-chapters in turn. Separators and part
+// titles are left as they are.
+fn weave_items(
+    items: &mut Value,
+    annotations: &Annotations,
+    symbols: &SymbolKey,
+) -> Result<(), Box<dyn Error>> {
+    for item in items.as_array_mut().into_iter().flatten() {
+        let chapter = match item.get_mut("Chapter") {
+            Some(chapter) => chapter,
+            None => continue,
+        };
+        // Chapters which were generated rather than read have no file, so go by their name.
+        let filename = chapter["source_path"]
+            .as_str()
+            .or_else(|| chapter["name"].as_str())
+            .unwrap_or_default()
+            .to_owned();
+        if let Some(content) = chapter["content"].as_str() {
+            let woven = weave(&filename, content, annotations, symbols)?;
+            chapter["content"] = Value::String(woven);
+        }
+        weave_items(&mut chapter["sub_items"], annotations, symbols)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_supports() {
+        assert!(supports("html"));
+        assert!(supports("markdown"));
+        assert!(!supports("epub"));
+        assert!(!supports(""));
+    }
+
+    #[test]
+    fn test_source_globs() {
+        let globs = source_globs(&json!({"sources": ["src/**/*.rs", "*.py"]})).unwrap();
+        assert!(globs.is_match("src/a/b.rs"));
+        assert!(globs.is_match("tool.py"));
+        assert!(!globs.is_match("book/intro.md"));
+
+        assert!(source_globs(&json!({})).is_err());
+        assert!(source_globs(&json!({"sources": [5]})).is_err());
+        assert!(source_globs(&json!({"sources": ["src/[.rs"]})).is_err());
+    }
+
+    #[test]
+    fn test_read_annotations() {
+        let root = env::temp_dir().join(format!("mdbook-verso-{}", process::id()));
+        fs::create_dir_all(root.join("src")).unwrap();
+        let files = [
+            (
+                "src/a.rs",
+                "// @<one\nfn one() {}\n// >@\n// @<shared\nlet a = 1;\n// >@\n",
+            ),
+            ("src/b.rs", "// @<shared\nlet b = 2;\n// >@\n"),
+            ("src/ignored.rs", "// @<ignored\n// >@\n"),
+            ("notes.txt", "// @<notes\n// >@\n"),
+            (".ignore", "ignored.rs\n"),
+        ];
+        for (name, contents) in &files {
+            fs::write(root.join(name), contents).unwrap();
+        }
+
+        let sources = source_globs(&json!({"sources": ["src/**/*.rs"]})).unwrap();
+        let annotations = read_annotations(&root, &sources, &SymbolKey::default()).unwrap();
+        let ids: Vec<&str> = annotations.keys().map(String::as_str).collect();
+        assert_eq!(ids, vec!["one", "shared"]);
+        // Fragments are numbered across files, in the order of their names.
+        let shared: Vec<(&str, usize)> = annotations["shared"]
+            .iter()
+            .map(|f| (f.file.as_str(), f.index))
+            .collect();
+        assert_eq!(shared, vec![("src/a.rs", 2), ("src/b.rs", 3)]);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_weave_items() {
+        let mut annotations = Annotations::new();
+        for f in
+            extract_fragments("// @<x\nlet x = 1;\n// >@", "x.rs", &SymbolKey::default()).unwrap()
+        {
+            annotations.entry(f.id.to_owned()).or_default().push(f);
+        }
+        let mut items = json!([
+            {"PartTitle": "Part 1"},
+            {"Chapter": {
+                "name": "Intro",
+                "content": "# Intro\n@@x\n",
+                "source_path": "intro.md",
+                "sub_items": [
+                    {"Chapter": {"name": "Detail", "content": "From @?x.file", "sub_items": []}}
+                ]
+            }},
+            "Separator"
+        ]);
+        weave_items(&mut items, &annotations, &SymbolKey::default()).unwrap();
+        let intro = &items[1]["Chapter"];
+        assert_eq!(intro["content"], "# Intro\nlet x = 1;\n");
+        assert_eq!(intro["sub_items"][0]["Chapter"]["content"], "From x.rs");
+        assert_eq!(items[0], json!({"PartTitle": "Part 1"}));
+        assert_eq!(items[2], "Separator");
+
+        // An error names the chapter's file.
+        let mut items =
+            json!([{"Chapter": {"name": "Bad", "content": "@@nope", "source_path": "bad.md"}}]);
+        let err = weave_items(&mut items, &annotations, &SymbolKey::default()).unwrap_err();
+        assert!(err.to_string().contains("bad.md"), "{}", err);
+    }
+}
@@ -1,13 +1,31 @@
+use std::borrow::Cow;
 use std::collections::BTreeMap;
 
-use verso::{weave, Fragment, SymbolKey};
+#[cfg(feature = "frontmatter")]
+use verso::{abspath_root_from_front_matter, split_front_matter, symbols_from_front_matter};
+use verso::{
+    add_banners, audit, combine_fragment_readers, create_output_dir, describe_config,
+    expand_banner, extract_inline_fragments, find_deprecated_references,
+    find_references_with_options, find_undefined_metadata, format_date, fragments_to_check,
+    load_referenced, output_path, read_fragments, read_fragments_dir, read_fragments_file,
+    run_check_cmd, squeeze_blank_lines, weave_errors, weave_pandoc, weave_with_source_map,
+    AbsPathRoot, Annotations, DuplicatePolicy, FinalNewline, Fragment, FragmentIndex,
+    FragmentReader, FragmentStore, IndexedStore, LineEnding, Placement, Reference, SourceMap,
+    SymbolKey, Syntax, TabExpansion, TrimPolicy, WatchTargets, WeaveOptions,
+};
+
+use notify::{RecursiveMode, Watcher};
+use regex::Regex;
+use serde_json::Value;
 
 use std::env;
 use std::error::Error;
 use std::fs;
 use std::io;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process;
+use std::sync::mpsc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 fn main() {
     let args: Vec<String> = env::args().collect();
@@ -38,56 +56,921 @@ Hint: The 'verso' and 'recto' tools are meant to be used together, like this:
 pub struct Config {
     pub filenames: Vec<String>,
     pub out_dir: String,
+    pub keep_frontmatter: bool,
+    pub audit: bool,
+    pub check_metadata: bool,
+    // Weave each prose file without writing it, and report every error found.
+    pub check: bool,
+    pub strict: bool,
+    // Extract fragments from each prose file as well, for use within that file.
+    pub inline_fragments: bool,
+    // Print the settings that would be used, including each prose file's symbols, and stop.
+    pub show_config: bool,
+    // A command to run on each fragment's body, and the file extensions of the fragments to check.
+    pub check_cmd: Option<String>,
+    pub check_extensions: Vec<String>,
+    // Where to write a summary of the fragments placed in each woven file.
+    pub report: Option<String>,
+    // Read fragments from these files instead of stdin, so that they can be read again. Fragments
+    // from several files are combined as `on_duplicate` says.
+    pub fragments: Vec<String>,
+    // Read fragments from the JSON files in this directory instead of stdin.
+    pub fragments_dir: Option<String>,
+    // Read just the fragments the prose uses from an indexed store written by `verso --store`.
+    pub store: Option<String>,
+    // Read fragments from stdin one per line, as written by `verso --jsonl`.
+    pub jsonl: bool,
+    pub on_duplicate: DuplicatePolicy,
+    // Text added to the start and end of every woven file, such as a "generated file" notice.
+    pub header: Option<String>,
+    pub footer: Option<String>,
+    // Line endings to force on the output. By default they are LF, except where a fragment's body
+    // has others.
+    pub eol: Option<LineEnding>,
+    // The most consecutive blank lines to keep outside of code fences.
+    pub squeeze_blanks: Option<usize>,
+    // Keep running, and weave the prose again whenever it or the fragments change.
+    pub watch: bool,
+    // Treat prose files as Pandoc's JSON syntax tree, and weave the text within it.
+    pub pandoc: bool,
+    // Write a map of where each output line came from next to each output file.
+    pub sourcemap: bool,
+    pub weave_options: WeaveOptions,
 }
 
 impl Config {
     pub fn new(args: &[String]) -> Result<Config, &'static str> {
-        if args.len() < 2 {
-            return Err("Expected at least two arguments");
+        let mut keep_frontmatter = false;
+        let mut audit = false;
+        let mut check_metadata = false;
+        let mut check = false;
+        let mut strict = false;
+        let mut inline_fragments = false;
+        let mut show_config = false;
+        let mut check_cmd = None;
+        let mut check_extensions = vec![];
+        let mut report = None;
+        let mut fragments = vec![];
+        let mut fragments_dir = None;
+        let mut store = None;
+        let mut jsonl = false;
+        let mut on_duplicate = DuplicatePolicy::default();
+        let mut header = None;
+        let mut footer = None;
+        let mut eol = None;
+        let mut squeeze_blanks = None;
+        let mut watch = false;
+        let mut pandoc = false;
+        let mut sourcemap = false;
+        let mut url_base = None;
+        let mut canonical_abspath = false;
+        let mut weave_options = WeaveOptions::default();
+        let mut positional: Vec<String> = vec![];
+
+        let mut args = args[1..].iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--keep-frontmatter" => keep_frontmatter = true,
+                "--audit" => audit = true,
+                "--check-metadata" => check_metadata = true,
+                "--check" => check = true,
+                "--strict" => strict = true,
+                "--no-defaults" => weave_options.ignore_defaults = true,
+                "--inline-fragments" => inline_fragments = true,
+                "--show-config" => show_config = true,
+                "--pattern-duplicates" => weave_options.pattern_duplicates = true,
+                "--passthrough-missing" => weave_options.passthrough_missing = true,
+                "--define" => {
+                    let flag = args.next().ok_or("Expected a flag after --define")?;
+                    weave_options.defines.insert(flag.to_owned());
+                }
+                "--global" => {
+                    let (name, value) = args
+                        .next()
+                        .and_then(|g| g.split_once('='))
+                        .ok_or("Expected 'name=value' after --global")?;
+                    weave_options
+                        .globals
+                        .insert(name.to_ascii_lowercase(), value.to_owned());
+                }
+                "--fence" => weave_options.fence_all = true,
+                "--line-numbers" => weave_options.line_numbers = true,
+                "--url-base" => {
+                    url_base = Some(
+                        args.next()
+                            .ok_or("Expected a URL or path after --url-base")?
+                            .to_owned(),
+                    )
+                }
+                "--canonical-abspath" => canonical_abspath = true,
+                "--fence-language" => {
+                    let (extension, language) = args
+                        .next()
+                        .and_then(|l| l.split_once('='))
+                        .ok_or("Expected 'extension=language' after --fence-language")?;
+                    weave_options.fence_languages.insert(
+                        extension.trim_start_matches('.').to_lowercase(),
+                        language.to_owned(),
+                    );
+                }
+                "--language-name" => {
+                    let (extension, name) = args
+                        .next()
+                        .and_then(|l| l.split_once('='))
+                        .ok_or("Expected 'extension=name' after --language-name")?;
+                    weave_options.language_names.insert(
+                        extension.trim_start_matches('.').to_lowercase(),
+                        name.to_owned(),
+                    );
+                }
+                "--check-cmd" => {
+                    check_cmd = Some(
+                        args.next()
+                            .ok_or("Expected a command after --check-cmd")?
+                            .to_owned(),
+                    )
+                }
+                "--check-ext" => check_extensions.push(
+                    args.next()
+                        .ok_or("Expected an extension after --check-ext")?
+                        .trim_start_matches('.')
+                        .to_owned(),
+                ),
+                "--report" => {
+                    report = Some(
+                        args.next()
+                            .ok_or("Expected a file name after --report")?
+                            .to_owned(),
+                    )
+                }
+                "--fragments" => fragments.push(
+                    args.next()
+                        .ok_or("Expected a file name after --fragments")?
+                        .to_owned(),
+                ),
+                "--fragments-dir" => {
+                    fragments_dir = Some(
+                        args.next()
+                            .ok_or("Expected a directory after --fragments-dir")?
+                            .to_owned(),
+                    )
+                }
+                "--store" => {
+                    store = Some(
+                        args.next()
+                            .ok_or("Expected a directory after --store")?
+                            .to_owned(),
+                    )
+                }
+                "--jsonl" => jsonl = true,
+                "--watch" => watch = true,
+                "--pandoc" => pandoc = true,
+                "--sourcemap" => sourcemap = true,
+                "--on-duplicate" => {
+                    on_duplicate = match args.next().map(String::as_str) {
+                        Some("merge") => DuplicatePolicy::Merge,
+                        Some("last") => DuplicatePolicy::Last,
+                        Some("first") => DuplicatePolicy::First,
+                        Some("error") => DuplicatePolicy::Error,
+                        _ => return Err("Expected merge, last, first, or error after --on-duplicate"),
+                    }
+                }
+                "--header" => {
+                    header = Some(
+                        args.next()
+                            .ok_or("Expected text after --header")?
+                            .to_owned(),
+                    )
+                }
+                "--footer" => {
+                    footer = Some(
+                        args.next()
+                            .ok_or("Expected text after --footer")?
+                            .to_owned(),
+                    )
+                }
+                "--squeeze-blanks" => squeeze_blanks = Some(1),
+                "--max-blank-lines" => {
+                    squeeze_blanks = Some(
+                        args.next()
+                            .and_then(|n| n.parse().ok())
+                            .ok_or("Expected a number after --max-blank-lines")?,
+                    )
+                }
+                "--eol" => {
+                    eol = Some(
+                        args.next()
+                            .and_then(|name| LineEnding::from_name(name))
+                            .ok_or("Expected lf, crlf, or native after --eol")?,
+                    )
+                }
+                "--final-newline" => {
+                    weave_options.final_newline = match args.next().map(String::as_str) {
+                        Some("preserve") => FinalNewline::Preserve,
+                        Some("always") => FinalNewline::Always,
+                        Some("never") => FinalNewline::Never,
+                        _ => {
+                            return Err("Expected preserve, always, or never after --final-newline")
+                        }
+                    }
+                }
+                "--trim" => {
+                    weave_options.trim = Some(
+                        args.next()
+                            .and_then(|name| TrimPolicy::from_name(name))
+                            .ok_or("Expected a trim policy after --trim")?,
+                    )
+                }
+                "--snippet-ellipsis" => {
+                    weave_options.snippet_ellipsis = Some(
+                        args.next()
+                            .ok_or("Expected a marker after --snippet-ellipsis")?
+                            .to_owned(),
+                    )
+                }
+                "--expand-tabs" | "--expand-all-tabs" => {
+                    weave_options.expand_tabs = Some(TabExpansion {
+                        width: args
+                            .next()
+                            .and_then(|n| n.parse().ok())
+                            .filter(|&n| n > 0)
+                            .ok_or("Expected a tab width of at least 1 after --expand-tabs or --expand-all-tabs")?,
+                        all: arg == "--expand-all-tabs",
+                    })
+                }
+                "--source-comment" => {
+                    weave_options.source_comment_style = Some(
+                        args.next()
+                            .ok_or("Expected a comment style after --source-comment")?
+                            .to_owned(),
+                    )
+                }
+                "--toc-template" => {
+                    weave_options.toc_template = Some(
+                        args.next()
+                            .ok_or("Expected a template after --toc-template")?
+                            .to_owned(),
+                    )
+                }
+                "--syntax" => {
+                    weave_options.syntax = match args.next().map(String::as_str) {
+                        Some("symbols") => Syntax::Symbols,
+                        Some("mustache") => Syntax::Mustache,
+                        _ => return Err("Expected 'symbols' or 'mustache' after --syntax"),
+                    }
+                }
+                _ => positional.push(arg.to_owned()),
+            }
         }
 
-        let out_dir = String::from(&args[1]);
-        let filenames = args[2..].to_vec();
+        if store.is_some() && fragments_dir.is_some() {
+            return Err("--store and --fragments-dir can't be used together");
+        }
+        if jsonl && (store.is_some() || fragments_dir.is_some()) {
+            return Err("--jsonl can't be used with --store or --fragments-dir");
+        }
+        if !fragments.is_empty() && (store.is_some() || fragments_dir.is_some()) {
+            return Err("--fragments can't be used with --store or --fragments-dir");
+        }
+        if watch && fragments.is_empty() && fragments_dir.is_none() && store.is_none() {
+            return Err(
+                "--watch can't read stdin twice, so needs --fragments, --fragments-dir or --store",
+            );
+        }
+        if pandoc && (report.is_some() || inline_fragments) {
+            return Err("--pandoc can't be used with --report or --inline-fragments");
+        }
+        if sourcemap && (pandoc || squeeze_blanks.is_some()) {
+            return Err(
+                "--sourcemap can't be used with --pandoc, --squeeze-blanks or --max-blank-lines",
+            );
+        }
+        weave_options.abspath_root = match (url_base, canonical_abspath) {
+            (Some(_), true) => {
+                return Err("--url-base and --canonical-abspath can't be used together")
+            }
+            (Some(base), false) => AbsPathRoot::Base(base),
+            (None, true) => AbsPathRoot::Filesystem,
+            (None, false) => AbsPathRoot::Slash,
+        };
+        if watch && (audit || check_metadata || check || check_cmd.is_some() || show_config) {
+            return Err("--watch only applies when weaving");
+        }
+
+        // Checks don't write any output, so every positional argument is a prose file. The same goes
+        // for showing the configuration.
+        let out_dir = if audit || check_metadata || check || check_cmd.is_some() || show_config {
+            String::new()
+        } else if positional.is_empty() {
+            return Err("Expected at least two arguments");
+        } else {
+            positional.remove(0)
+        };
+        let filenames = positional;
 
-        Ok(Config { out_dir, filenames })
+        Ok(Config {
+            out_dir,
+            filenames,
+            keep_frontmatter,
+            audit,
+            check_metadata,
+            check,
+            strict,
+            inline_fragments,
+            show_config,
+            check_cmd,
+            check_extensions,
+            report,
+            fragments,
+            fragments_dir,
+            store,
+            jsonl,
+            on_duplicate,
+            header,
+            footer,
+            eol,
+            squeeze_blanks,
+            watch,
+            pandoc,
+            sourcemap,
+            weave_options,
+        })
     }
 }
 
-pub fn run(cfg: Config) -> Result<(), Box<dyn Error>> {
-    let symbols = SymbolKey::from_environment();
+// A prose file, ready to be woven.
+struct Prose {
+    symbols: SymbolKey,
+    // The root for `@?id.abspath` given in the file's front matter, in place of the global one.
+    abspath_root: Option<AbsPathRoot>,
+    // Text to be copied into the output verbatim, ahead of the woven body.
+    header: String,
+    body: String,
+}
+
+fn read_prose(filename: &str, symbols: &SymbolKey, cfg: &Config) -> Result<Prose, Box<dyn Error>> {
+    // TODO Improve error messages.
+    let contents =
+        fs::read_to_string(filename).map_err(|e| format!("Error: ({}) {}", filename, e))?;
+
+    // Settings in the file's front matter apply to that file alone. The front matter itself is not
+    // woven, and is only carried into the output if requested.
+    #[cfg(feature = "frontmatter")]
+    if let Some((front_matter, body)) = split_front_matter(&contents) {
+        let header = if cfg.keep_frontmatter {
+            &contents[..contents.len() - body.len()]
+        } else {
+            ""
+        };
+        return Ok(Prose {
+            symbols: symbols_from_front_matter(front_matter, symbols)
+                .map_err(|e| format!("Error: ({}) invalid front matter: {}", filename, e))?,
+            abspath_root: abspath_root_from_front_matter(front_matter)?,
+            header: header.to_owned(),
+            body: body.to_owned(),
+        });
+    }
+    #[cfg(not(feature = "frontmatter"))]
+    let _ = cfg;
 
-    // Read annotations from stdin, and index by ID.
-    let mut annotations = BTreeMap::new();
-    {
-        // Read the annotations into the map in a block to reduce memory pressure.
-        let raw_annotations: Vec<Fragment> = serde_json::from_reader(io::stdin())?;
+    Ok(Prose {
+        symbols: symbols.clone(),
+        abspath_root: None,
+        header: String::new(),
+        body: contents,
+    })
+}
 
-        for ann in raw_annotations {
-            annotations.insert(ann.id.to_owned(), ann.to_owned());
-            eprintln!("Read annotation {}", ann.id);
+// The weave options for a prose file: the global ones, with any its front matter changes.
+fn prose_options<'a>(prose: &Prose, cfg: &'a Config) -> Cow<'a, WeaveOptions> {
+    match &prose.abspath_root {
+        Some(root) => Cow::Owned(WeaveOptions {
+            abspath_root: root.clone(),
+            ..cfg.weave_options.clone()
+        }),
+        None => Cow::Borrowed(&cfg.weave_options),
+    }
+}
+
+// Values for global metadata (`@?@.name`) which are known at run time. Values given on the command
+// line take precedence over these.
+fn default_globals() -> BTreeMap<String, String> {
+    let mut globals = BTreeMap::new();
+
+    // Respect SOURCE_DATE_EPOCH so that woven output can be reproducible.
+    let epoch_secs = env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|s| s.parse::<u64>().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0)
+        });
+    globals.insert(String::from("date"), format_date(epoch_secs));
+
+    if let Ok(root) = env::current_dir() {
+        globals.insert(String::from("root"), root.display().to_string());
+    }
+
+    for (key, value) in env::vars() {
+        if let Some(name) = key.strip_prefix("RECTO_GLOBAL_") {
+            globals.insert(name.to_ascii_lowercase(), value);
+        }
+    }
+
+    globals
+}
+
+pub fn run(mut cfg: Config) -> Result<(), Box<dyn Error>> {
+    let symbols = SymbolKey::from_project()?;
+    // Names given with --language-name win over those in the project's settings.
+    for (extension, name) in symbols.language_names() {
+        cfg.weave_options
+            .language_names
+            .entry(extension)
+            .or_insert_with(|| name.to_owned());
+    }
+
+    let mut globals = default_globals();
+    globals.append(&mut cfg.weave_options.globals);
+    cfg.weave_options.globals = globals;
+
+    if cfg.show_config {
+        let mut prose_symbols = vec![];
+        for filename in &cfg.filenames {
+            prose_symbols.push((
+                filename.as_str(),
+                read_prose(filename, &symbols, &cfg)?.symbols,
+            ));
         }
+        let prose_symbols = prose_symbols.iter().map(|(f, s)| (*f, s));
+        println!("{}", describe_config(&cfg, &symbols, prose_symbols));
+        return Ok(());
+    }
+
+    let annotations = read_annotations(&mut cfg, &symbols)?;
+
+    if cfg.audit {
+        return run_audit(&cfg, &annotations, &symbols);
+    }
+    if cfg.check_metadata {
+        return run_check_metadata(&cfg, &annotations, &symbols);
+    }
+    if cfg.check {
+        return run_check(&cfg, &annotations, &symbols);
+    }
+    if let Some(check_cmd) = &cfg.check_cmd {
+        return check_fragments(check_cmd, &cfg.check_extensions, &annotations);
     }
 
     eprintln!("Creating results in directory '{}'...", &cfg.out_dir);
-    fs::create_dir_all(&cfg.out_dir)?;
+    create_output_dir(Path::new(&cfg.out_dir))?;
+    build(&cfg, &cfg.filenames, &annotations, &symbols)?;
 
-    for filename in cfg.filenames {
-        eprintln!("Expanding annotations in '{}'...", &filename);
+    if cfg.watch {
+        return watch(&mut cfg, &symbols, annotations);
+    }
+
+    Ok(())
+}
 
-        // TODO Improve error messages.
-        let contents = fs::read_to_string(&filename)?;
+// Weave each of `filenames` into the output directory, and write the report if one was asked for.
+fn build(
+    cfg: &Config,
+    filenames: &[String],
+    annotations: &Annotations,
+    symbols: &SymbolKey,
+) -> Result<(), Box<dyn Error>> {
+    let mut placements = vec![];
+
+    for filename in filenames {
+        eprintln!("Expanding annotations in '{}'...", filename);
+
+        if cfg.pandoc {
+            let mut document: Value = serde_json::from_str(&fs::read_to_string(filename)?)?;
+            weave_pandoc(
+                &mut document,
+                filename,
+                annotations,
+                symbols,
+                &cfg.weave_options,
+            )?;
+            write_output(cfg, filename, &serde_json::to_string(&document)?)?;
+            continue;
+        }
+
+        let mut prose = read_prose(filename, symbols, cfg)?;
+        // Found before inline fragments are extracted, which removes lines and so moves the rest.
+        let references =
+            find_references_with_options(filename, &prose.body, &prose.symbols, &cfg.weave_options);
+
+        let file_annotations = take_inline_fragments(cfg, filename, &mut prose, annotations)?;
 
         // Add annotations into the text body and emit to out directory
-        let woven_body = weave(&filename, &contents, &annotations, &symbols)?;
-        let out_file = Path::new(&cfg.out_dir).join(&filename);
+        let file_annotations = file_annotations.as_ref().unwrap_or(annotations);
+        let (woven_body, placed, source_map) = weave_with_source_map(
+            filename,
+            &prose.body,
+            file_annotations,
+            &prose.symbols,
+            &prose_options(&prose, cfg),
+        )?;
+        for (r, f) in find_deprecated_references(file_annotations, &references) {
+            eprintln!(
+                "Warning: ({}:{}) fragment '{}' is deprecated: {}",
+                r.file,
+                r.line,
+                f.id,
+                f.deprecated.as_deref().unwrap_or_default()
+            );
+        }
+        // Banners are added after weaving so that they are never mistaken for references.
+        let date = cfg
+            .weave_options
+            .globals
+            .get("date")
+            .map_or("", String::as_str);
+        let banner = |b: &Option<String>| b.as_ref().map(|b| expand_banner(b, filename, date));
+        let (mut woven_body, header_lines) = add_banners(
+            &prose.header,
+            &woven_body,
+            banner(&cfg.header).as_deref(),
+            banner(&cfg.footer).as_deref(),
+        );
+        // Any front matter kept in the output, and the header, push the woven body down.
+        placements.extend(placed.into_iter().map(|p| Placement {
+            line: p.line + header_lines,
+            ..p
+        }));
+        if let Some(max) = cfg.squeeze_blanks {
+            woven_body = squeeze_blank_lines(&woven_body, max);
+        }
+        if let Some(eol) = cfg.eol {
+            woven_body = eol.apply(&woven_body);
+        }
+        let out_file = write_output(cfg, filename, &woven_body)?;
 
-        // Create subdirectories if needed.
-        if let Some(out_subdir) = out_file.parent() {
-            fs::create_dir_all(&out_subdir)?
+        if cfg.sourcemap {
+            // The header and footer lines are prose as far as the map is concerned.
+            let mut lines = vec![None; header_lines];
+            lines.extend(source_map.into_iter().map(|(_, source)| source));
+            lines.resize(woven_body.lines().count(), None);
+            let source_map: SourceMap = lines
+                .into_iter()
+                .enumerate()
+                .map(|(i, source)| (i + 1, source))
+                .collect();
+            let mut map_file = out_file.into_os_string();
+            map_file.push(".map.json");
+            fs::write(map_file, serde_json::to_string(&source_map)?)?;
         }
+    }
+
+    if let Some(report) = &cfg.report {
+        eprintln!("Writing report to '{}'...", report);
+        fs::write(report, format_report(report, &placements)?)?;
+    }
+
+    Ok(())
+}
+
+// With `--inline-fragments`, remove the fragments defined in a prose file from its body, and return
+// them merged with the others. Fragments defined in the prose file are only visible to it, and
+// fragments from the source files win if their IDs collide.
+fn take_inline_fragments(
+    cfg: &Config,
+    filename: &str,
+    prose: &mut Prose,
+    annotations: &Annotations,
+) -> Result<Option<Annotations>, Box<dyn Error>> {
+    if !cfg.inline_fragments {
+        return Ok(None);
+    }
+    let (inline, body) = extract_inline_fragments(&prose.body, filename, &prose.symbols)?;
+    let mut merged = annotations.clone();
+    for f in inline {
+        merged.entry(f.id.to_owned()).or_insert_with(|| vec![f]);
+    }
+    prose.body = body;
+    Ok(Some(merged))
+}
+
+// Write the woven form of `filename` to its place in the output directory, and return that path.
+fn write_output(cfg: &Config, filename: &str, woven: &str) -> Result<PathBuf, Box<dyn Error>> {
+    let out_file = output_path(Path::new(&cfg.out_dir), filename)?;
+
+    // Create subdirectories if needed.
+    if let Some(out_subdir) = out_file.parent() {
+        create_output_dir(out_subdir)?
+    }
+
+    eprintln!("Writing result to {:?}...", out_file);
+    fs::write(&out_file, woven)?;
+    Ok(out_file)
+}
+
+// Read annotations from stdin, files, a directory of verso outputs, or a store, and index them by
+// ID.
+fn read_annotations(cfg: &mut Config, symbols: &SymbolKey) -> Result<Annotations, Box<dyn Error>> {
+    let raw_annotations: FragmentReader = match &cfg.fragments_dir {
+        // Each file was numbered on its own, so the indices would clash. Number the
+        // fragments in the order they are read instead.
+        Some(dir) => Box::new(
+            read_fragments_dir(Path::new(dir))?
+                .into_iter()
+                .map(|f| Ok(Fragment { index: 0, ..f })),
+        ),
+        None => match &cfg.store {
+            Some(dir) => Box::new(
+                read_store(cfg, Path::new(dir), symbols)?
+                    .into_iter()
+                    .map(Ok),
+            ),
+            None => {
+                // JSON Lines are indexed as they arrive, so only the map is held in memory.
+                let all: Vec<FragmentReader> = if cfg.fragments.is_empty() {
+                    vec![read_fragments(io::stdin().lock(), cfg.jsonl)?]
+                } else {
+                    cfg.fragments
+                        .iter()
+                        .map(|path| read_fragments_file(path, cfg.jsonl))
+                        .collect::<Result<_, _>>()?
+                };
+                combine_fragment_readers(all)
+            }
+        },
+    };
+
+    let mut index = FragmentIndex::new(cfg.on_duplicate);
+    for ann in raw_annotations {
+        let ann = ann?;
+        let id = ann.id.clone();
+        if index.add(ann)? {
+            eprintln!("Read annotation {}", id);
+        }
+    }
+
+    Ok(index.into_annotations())
+}
+
+// Weave the prose again whenever it or the fragments change, until interrupted. Only the prose
+// files which changed are woven, unless the fragments or a report need all of them. Errors are
+// printed rather than returned, so that a mistake made while editing doesn't stop the watch.
+fn watch(
+    cfg: &mut Config,
+    symbols: &SymbolKey,
+    mut annotations: Annotations,
+) -> Result<(), Box<dyn Error>> {
+    let sources: Vec<String> = cfg
+        .fragments
+        .iter()
+        .chain(&cfg.fragments_dir)
+        .chain(&cfg.store)
+        .cloned()
+        .collect();
+    if sources.is_empty() {
+        return Err("--watch needs fragments from a file or directory".into());
+    }
+    let targets = WatchTargets::new(&cfg.filenames, &sources)?;
+
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx)?;
+    for (path, recursive) in targets.watches() {
+        let mode = if recursive {
+            RecursiveMode::Recursive
+        } else {
+            RecursiveMode::NonRecursive
+        };
+        watcher.watch(&path, mode)?;
+    }
+
+    eprintln!("Watching for changes...");
+    loop {
+        let mut changed: Vec<PathBuf> = vec![];
+        while changed.is_empty() {
+            changed = changed_paths(rx.recv()?);
+        }
+        // Saving a file often causes several events in quick succession, so take them together.
+        while let Ok(event) = rx.recv_timeout(Duration::from_millis(100)) {
+            changed.append(&mut changed_paths(event));
+        }
+
+        let reload = targets.reloads(&changed);
+        if reload {
+            match read_annotations(cfg, symbols) {
+                Ok(reloaded) => annotations = reloaded,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    eprintln!("Watching for changes...");
+                    continue;
+                }
+            }
+        }
+        // The report covers every file, so it needs them all.
+        let to_weave = targets.to_weave(&changed, reload, cfg.report.is_some());
+        if to_weave.is_empty() {
+            continue;
+        }
+        if let Err(e) = build(cfg, &to_weave, &annotations, symbols) {
+            eprintln!("{}", e);
+        }
+        eprintln!("Watching for changes...");
+    }
+}
+
+// The paths which a file system event changed. Reading a file is not a change.
+fn changed_paths(event: notify::Result<notify::Event>) -> Vec<PathBuf> {
+    match event {
+        Ok(event) if !event.kind.is_access() => event.paths,
+        Ok(_) => vec![],
+        Err(e) => {
+            eprintln!("Warning: {}", e);
+            vec![]
+        }
+    }
+}
+
+// A report of fragment placements, as CSV if `report` names a `.csv` file and as JSON otherwise.
+fn format_report(report: &str, placements: &[Placement]) -> Result<String, Box<dyn Error>> {
+    if !report.to_ascii_lowercase().ends_with(".csv") {
+        return Ok(serde_json::to_string(placements)?);
+    }
+
+    let mut csv = String::from("file,id,kind,line\n");
+    for p in placements {
+        let kind = serde_json::to_value(&p.kind)?;
+        let fields = [
+            p.file.as_str(),
+            p.id.as_str(),
+            kind.as_str().unwrap_or_default(),
+            &p.line.to_string(),
+        ];
+        let quoted: Vec<String> = fields.iter().map(|f| csv_field(f)).collect();
+        csv.push_str(&quoted.join(","));
+        csv.push('\n');
+    }
+    Ok(csv)
+}
+
+// Quote a CSV field if it contains anything which would otherwise be misread.
+fn csv_field(field: &str) -> String {
+    if field.contains(&[',', '"', '\n', '\r'][..]) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+// Read the fragments from a store which the prose files refer to. Audits and checks look at every
+// fragment, so they read the whole store.
+fn read_store(
+    cfg: &Config,
+    dir: &Path,
+    symbols: &SymbolKey,
+) -> Result<Vec<Fragment>, Box<dyn Error>> {
+    let store = IndexedStore::open(dir)?;
+    let mut fragments = if cfg.audit || cfg.check_cmd.is_some() {
+        store.matching(&Regex::new("")?)?
+    } else {
+        load_referenced(&store, &read_references(cfg, symbols)?)?
+            .into_values()
+            .flatten()
+            .collect()
+    };
+    // Return them in the order `verso` emitted them, as if they had been read from its JSON.
+    fragments.sort_by_key(|f| f.index);
+    Ok(fragments)
+}
+
+fn read_references(cfg: &Config, symbols: &SymbolKey) -> Result<Vec<Reference>, Box<dyn Error>> {
+    let mut references = vec![];
+    for filename in &cfg.filenames {
+        let prose = read_prose(filename, symbols, cfg)?;
+        references.append(&mut find_references_with_options(
+            filename,
+            &prose.body,
+            &prose.symbols,
+            &cfg.weave_options,
+        ));
+    }
+    Ok(references)
+}
+
+fn run_audit(
+    cfg: &Config,
+    annotations: &Annotations,
+    symbols: &SymbolKey,
+) -> Result<(), Box<dyn Error>> {
+    let references = read_references(cfg, symbols)?;
+    let report = audit(annotations, &references);
+
+    println!("{}", report);
+
+    if cfg.strict && !report.is_clean() {
+        return Err("Audit found unreferenced fragments or undefined references".into());
+    }
+
+    Ok(())
+}
+
+// Weave every prose file without writing the results, and report all of the errors in them rather
+// than stopping at the first.
+fn run_check(
+    cfg: &Config,
+    annotations: &Annotations,
+    symbols: &SymbolKey,
+) -> Result<(), Box<dyn Error>> {
+    let mut errors = 0;
+    for filename in &cfg.filenames {
+        // A file which can't be read is one more error, and the rest are still checked.
+        let mut prose = match read_prose(filename, symbols, cfg) {
+            Ok(prose) => prose,
+            Err(e) => {
+                eprintln!("{}", e);
+                errors += 1;
+                continue;
+            }
+        };
+        let file_annotations = match take_inline_fragments(cfg, filename, &mut prose, annotations) {
+            Ok(file_annotations) => file_annotations,
+            Err(e) => {
+                eprintln!("{}", e);
+                errors += 1;
+                continue;
+            }
+        };
+        let file_annotations = file_annotations.as_ref().unwrap_or(annotations);
+
+        for e in weave_errors(
+            filename,
+            &prose.body,
+            file_annotations,
+            &prose.symbols,
+            &prose_options(&prose, cfg),
+        ) {
+            eprintln!("{}", e);
+            errors += 1;
+        }
+    }
+
+    if errors > 0 {
+        return Err(format!("Found {} error(s) in the prose", errors).into());
+    }
+
+    Ok(())
+}
+
+fn run_check_metadata(
+    cfg: &Config,
+    annotations: &Annotations,
+    symbols: &SymbolKey,
+) -> Result<(), Box<dyn Error>> {
+    let references = read_references(cfg, symbols)?;
+    let undefined = find_undefined_metadata(annotations, &references);
+
+    for r in &undefined {
+        eprintln!(
+            "Error: ({}:{}) metadata reference to unknown fragment '{}'",
+            r.file, r.line, r.target
+        );
+    }
+
+    if !undefined.is_empty() {
+        return Err(format!(
+            "Found {} metadata reference(s) to unknown fragments",
+            undefined.len()
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
+// Run `check_cmd` once per fragment, with the fragment's body on stdin, and report the fragments for
+// which it fails. If `extensions` is non-empty, only fragments from files with those extensions are
+// checked.
+fn check_fragments(
+    check_cmd: &str,
+    extensions: &[String],
+    annotations: &Annotations,
+) -> Result<(), Box<dyn Error>> {
+    let fragments = fragments_to_check(annotations, extensions);
+    let checked = fragments.len();
+    let mut failed = 0;
+    for fragment in fragments {
+        eprintln!("Checking fragment {}...", fragment.id);
+        if let Some(stderr) = run_check_cmd(check_cmd, &fragment.body)? {
+            failed += 1;
+            eprintln!(
+                "Error: ({}:{}) fragment '{}' failed the check:\n{}",
+                fragment.file, fragment.line, fragment.id, stderr
+            );
+        }
+    }
 
-        eprintln!("Writing result to {:?}...", out_file);
-        fs::write(out_file, woven_body)?;
+    if failed > 0 {
+        return Err(format!("{} of {} fragment(s) failed the check", failed, checked).into());
     }
 
     Ok(())
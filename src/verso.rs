@@ -1,11 +1,27 @@
 use std::env;
 use std::error::Error;
-use std::fs;
+use std::fs::{self, File};
 use std::io;
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
 use std::process;
 
+use regex::Regex;
+use serde::Serialize;
+
 use verso::SymbolKey;
-use verso::{extract_fragments, Fragment};
+use verso::{
+    audit, describe_config, diff_fragments, duplicate_id_message,
+    extract_fragments_all_with_warnings, extract_fragments_file, find_duplicate_ids,
+    find_references_with_options, find_similar_ids, fragment_location, fragments_schema,
+    halts_before_content, number_fragments, select_bodies, sort_by_location, split_archive,
+    split_patch, walk_dir, write_fragments, write_fragments_file, Annotations, CommentPrefixes,
+    CompatFragment, ExtractOptions, Fragment, IdPattern, IndexedStore, Syntax, TrimPolicy,
+    WeaveOptions,
+};
+
+// Matches lines like `=== src/main.rs ===`, which introduce each file in an archive.
+const DEFAULT_ARCHIVE_HEADER: &str = r"^=== (.+) ===$";
 
 fn main() {
     let args: Vec<String> = env::args().collect();
@@ -35,28 +51,456 @@ Hint: The 'verso' and 'recto' tools are meant to be used together, like this:
 #[derive(Debug, PartialEq, Eq)]
 pub struct Config {
     pub filenames: Vec<String>,
+    pub require_fragments: bool,
+    pub extract_options: ExtractOptions,
+    // When non-empty, print only the bodies of these fragments instead of JSON.
+    pub fragments: Vec<String>,
+    pub delimiter: String,
+    // When set, read concatenated files from stdin, each introduced by a line matching this pattern.
+    pub archive_header: Option<String>,
+    // Read a unified diff from stdin, and extract fragments from the new version of each file.
+    pub patch: bool,
+    // Emit only the fields which the first versions of verso did.
+    pub compat: bool,
+    // Write one fragment per line, so that recto can index them as they arrive.
+    pub jsonl: bool,
+    // Emit each file's fragments in the order they were closed, as older versions did, rather than
+    // the order they appear in.
+    pub close_order: bool,
+    // Write the fragments to this file instead of stdout.
+    pub output: Option<String>,
+    // When set, every fragment ID must match this pattern in full.
+    pub id_pattern: Option<String>,
+    // The comment token each file's symbols must follow, by extension.
+    pub comment_prefixes: CommentPrefixes,
+    // Print the settings that would be used and stop, without reading any files.
+    pub show_config: bool,
+    // Print the JSON Schema of the fragments verso writes and stop.
+    pub print_schema: bool,
+    // Compare two earlier outputs of verso, old and new, instead of extracting fragments.
+    pub diff: Option<(String, String)>,
+    // Write the fragments to an indexed store in this directory, instead of printing JSON.
+    pub store: Option<String>,
+    // Let several fragments share an ID, as when sources are deliberately concatenated.
+    pub allow_duplicate_ids: bool,
+    // Include files in directories even when `.gitignore` or `.ignore` says to skip them.
+    pub no_ignore: bool,
+    // Compare the fragments against the references in these prose files, instead of printing them.
+    pub audit: Vec<String>,
+    // The reference syntax of the prose files to audit.
+    pub syntax: Syntax,
+    // Fail if the audit finds anything.
+    pub strict: bool,
 }
 
 impl Config {
     pub fn new(args: &[String]) -> Result<Config, &'static str> {
-        let filenames = args[1..].to_vec();
+        let mut require_fragments = false;
+        let mut extract_options = ExtractOptions::default();
+        let mut fragments: Vec<String> = vec![];
+        let mut delimiter = String::from("\n");
+        let mut archive = false;
+        let mut patch = false;
+        let mut compat = false;
+        let mut jsonl = false;
+        let mut close_order = false;
+        let mut output = None;
+        let mut show_config = false;
+        let mut print_schema = false;
+        let mut diff = None;
+        let mut store = None;
+        let mut allow_duplicate_ids = false;
+        let mut no_ignore = false;
+        let mut audit = vec![];
+        let mut syntax = Syntax::default();
+        let mut strict = false;
+        let mut id_pattern = None;
+        let mut comment_prefixes = CommentPrefixes::default();
+        let mut archive_header = String::from(DEFAULT_ARCHIVE_HEADER);
+        let mut filenames: Vec<String> = vec![];
+
+        let mut args = args[1..].iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--require-fragments" => require_fragments = true,
+                "--dedent-nested" => extract_options.dedent_nested = true,
+                "--dedent" => extract_options.dedent = true,
+                "--collapse-nested-blanks" => extract_options.collapse_nested_blanks = true,
+                "--posix-paths" => extract_options.posix_paths = true,
+                "--source-lines" => extract_options.source_lines = true,
+                "--warn-empty" => extract_options.warn_empty = true,
+                "--trim" => {
+                    extract_options.trim = args
+                        .next()
+                        .and_then(|name| TrimPolicy::from_name(name))
+                        .ok_or("Expected a trim policy after --trim")?
+                }
+                "--fragment" => fragments.push(
+                    args.next()
+                        .ok_or("Expected an ID after --fragment")?
+                        .to_owned(),
+                ),
+                "--delimiter" => {
+                    delimiter = args
+                        .next()
+                        .ok_or("Expected a delimiter after --delimiter")?
+                        .to_owned()
+                }
+                "--archive" => archive = true,
+                "--patch" => patch = true,
+                "--compat" => compat = true,
+                "--jsonl" => jsonl = true,
+                "--close-order" => close_order = true,
+                "-o" | "--output" => {
+                    output = Some(
+                        args.next()
+                            .ok_or("Expected a file name after --output")?
+                            .to_owned(),
+                    )
+                }
+                "--allow-duplicate-ids" => allow_duplicate_ids = true,
+                "--no-ignore" => no_ignore = true,
+                "--audit" => audit.push(
+                    args.next()
+                        .ok_or("Expected a prose file after --audit")?
+                        .to_owned(),
+                ),
+                "--syntax" => {
+                    syntax = match args.next().map(String::as_str) {
+                        Some("symbols") => Syntax::Symbols,
+                        Some("mustache") => Syntax::Mustache,
+                        _ => return Err("Expected 'symbols' or 'mustache' after --syntax"),
+                    }
+                }
+                "--strict" => strict = true,
+                "--show-config" => show_config = true,
+                "--print-schema" => print_schema = true,
+                "--diff" => {
+                    let old = args.next().ok_or("Expected two files after --diff")?;
+                    let new = args.next().ok_or("Expected two files after --diff")?;
+                    diff = Some((old.to_owned(), new.to_owned()));
+                }
+                "--comment-prefix" => {
+                    comment_prefixes = CommentPrefixes::parse(
+                        args.next()
+                            .ok_or("Expected comment prefixes after --comment-prefix")?,
+                    )?
+                }
+                "--max-nesting" => {
+                    extract_options.max_nesting = Some(
+                        args.next()
+                            .and_then(|n| n.parse().ok())
+                            .ok_or("Expected a number after --max-nesting")?,
+                    )
+                }
+                "--store" => {
+                    store = Some(
+                        args.next()
+                            .ok_or("Expected a directory after --store")?
+                            .to_owned(),
+                    )
+                }
+                "--id-pattern" => {
+                    id_pattern = Some(
+                        args.next()
+                            .ok_or("Expected a pattern after --id-pattern")?
+                            .to_owned(),
+                    )
+                }
+                "--archive-header" => {
+                    archive_header = args
+                        .next()
+                        .ok_or("Expected a pattern after --archive-header")?
+                        .to_owned()
+                }
+                _ => filenames.push(arg.to_owned()),
+            }
+        }
 
-        Ok(Config { filenames })
+        if archive && !filenames.is_empty() {
+            return Err("--archive reads files from stdin, and takes no file arguments");
+        }
+        if patch && (archive || !filenames.is_empty()) {
+            return Err("--patch reads a diff from stdin, and takes no file arguments");
+        }
+        if output.is_some() && store.is_some() {
+            return Err("--output and --store can't be used together");
+        }
+
+        Ok(Config {
+            filenames,
+            require_fragments,
+            extract_options,
+            fragments,
+            delimiter,
+            archive_header: Some(archive_header).filter(|_| archive),
+            patch,
+            compat,
+            jsonl,
+            close_order,
+            output,
+            id_pattern,
+            comment_prefixes,
+            show_config,
+            print_schema,
+            diff,
+            store,
+            allow_duplicate_ids,
+            no_ignore,
+            audit,
+            syntax,
+            strict,
+        })
     }
 }
 
 pub fn run(cfg: Config) -> Result<(), Box<dyn Error>> {
+    if cfg.show_config {
+        let symbols = SymbolKey::from_project()?;
+        println!("{}", describe_config(&cfg, &symbols, vec![]));
+        return Ok(());
+    }
+    if cfg.print_schema {
+        println!("{}", serde_json::to_string_pretty(&fragments_schema())?);
+        return Ok(());
+    }
+    if let Some((old, new)) = &cfg.diff {
+        return run_diff(old, new);
+    }
+
     let mut annotations: Vec<Fragment> = vec![];
+    let id_pattern = match &cfg.id_pattern {
+        Some(pattern) => Some(IdPattern::new(pattern)?),
+        None => None,
+    };
+
+    // Sources without contents are files on disk, which are streamed as they are extracted.
+    let mut sources: Vec<(String, Option<String>)> = vec![];
+    if let Some(header) = &cfg.archive_header {
+        let header = Regex::new(header)?;
+        if header.captures_len() < 2 {
+            return Err("The archive header pattern must capture the file's path".into());
+        }
+        let mut archive = String::new();
+        io::stdin().read_to_string(&mut archive)?;
+        for (path, contents) in split_archive(&archive, &header) {
+            sources.push((path.to_owned(), Some(contents.to_owned())));
+        }
+    } else if cfg.patch {
+        let mut patch = String::new();
+        io::stdin().read_to_string(&mut patch)?;
+        for (path, contents) in split_patch(&patch) {
+            sources.push((path, Some(contents)));
+        }
+    } else {
+        for filename in &cfg.filenames {
+            let filenames = if Path::new(filename).is_dir() {
+                walk_dir(filename, cfg.no_ignore)?
+            } else {
+                vec![filename.to_owned()]
+            };
+            sources.extend(filenames.into_iter().map(|filename| (filename, None)));
+        }
+    }
 
     // Do the read and print in separate passes to enable clean error messages.
-    for filename in cfg.filenames {
-        let contents = fs::read_to_string(&filename)?;
-        let symbols = SymbolKey::from_environment();
-        let mut fragments = extract_fragments(&contents, &filename, &symbols)?;
+    let symbols = SymbolKey::from_project()?;
+    let mut parse_errors = 0;
+    for (filename, contents) in &sources {
+        let options = ExtractOptions {
+            comment_prefix: cfg.comment_prefixes.for_file(filename).map(String::from),
+            ..cfg.extract_options.clone()
+        };
+        // Report every error in every file, so that they can all be fixed in one go.
+        let extracted = match contents {
+            Some(contents) => {
+                extract_fragments_all_with_warnings(contents, filename, &symbols, &options)
+            }
+            None => match extract_fragments_file(filename, &symbols, &options)? {
+                Some(extracted) => extracted,
+                None => {
+                    eprintln!(
+                        "Warning: ({}) skipping a file which is not UTF-8 text",
+                        filename
+                    );
+                    continue;
+                }
+            },
+        };
+        let (mut fragments, warnings) = match extracted {
+            Ok(extracted) => extracted,
+            Err(errors) => {
+                for e in &errors {
+                    eprintln!("{}", e);
+                }
+                parse_errors += errors.len();
+                continue;
+            }
+        };
+        for warning in warnings {
+            eprintln!("{}", warning);
+        }
+
+        // A file which halts before any fragments has been deliberately excluded.
+        if cfg.require_fragments && fragments.is_empty() {
+            // Streamed files have to be read again, but only as far as their first content.
+            let halts = match contents {
+                Some(contents) => halts_before_content(contents.lines(), &symbols, &options),
+                None => {
+                    let lines = BufReader::new(File::open(filename)?).lines();
+                    halts_before_content(lines.map_while(Result::ok), &symbols, &options)
+                }
+            };
+            if !halts {
+                return Err(format!("No fragments found in '{}'", filename).into());
+            }
+        }
+
+        // Fragments are numbered consecutively across all files in the order they close, so the
+        // numbers depend on the order of the files on the command line, but not on how they are
+        // emitted. Each file's fragments are then emitted in source order.
+        number_fragments(&mut fragments, annotations.len() + 1);
+        if !cfg.close_order {
+            sort_by_location(&mut fragments);
+        }
+        if let Some(Err(errors)) = id_pattern.as_ref().map(|pattern| pattern.check(&fragments)) {
+            for e in &errors {
+                eprintln!("{}", e);
+            }
+            parse_errors += errors.len();
+        }
         annotations.append(&mut fragments);
     }
+    if parse_errors > 0 {
+        return Err(format!("Found {} parse error(s)", parse_errors).into());
+    }
+
+    let duplicates = find_duplicate_ids(&annotations);
+    if !cfg.allow_duplicate_ids && !duplicates.is_empty() {
+        for group in &duplicates {
+            eprintln!("Error: {}", duplicate_id_message(group));
+        }
+        return Err(format!(
+            "Found {} duplicate fragment ID(s); use --allow-duplicate-ids to keep them all",
+            duplicates.len()
+        )
+        .into());
+    }
+
+    // IDs that differ only by separators are legal, but almost always a typo.
+    for group in find_similar_ids(&annotations, &symbols) {
+        let locations: Vec<String> = group
+            .iter()
+            .map(|f| format!("{} ({})", f.id, fragment_location(f)))
+            .collect();
+        eprintln!(
+            "Warning: fragment IDs differ only by separators: {}",
+            locations.join(", ")
+        );
+    }
+
+    if !cfg.audit.is_empty() {
+        return run_audit(&cfg, annotations, &symbols);
+    }
+
+    if !cfg.fragments.is_empty() {
+        let bodies = select_bodies(&annotations, &cfg.fragments, &cfg.delimiter)
+            .map_err(|id| format!("No fragment found with ID '{}'", id))?;
+        println!("{}", bodies);
+        return Ok(());
+    }
+
+    if let Some(dir) = &cfg.store {
+        IndexedStore::write(Path::new(dir), &annotations)?;
+        return Ok(());
+    }
+
+    if cfg.compat {
+        let compat: Vec<CompatFragment> = annotations.iter().map(CompatFragment::from).collect();
+        output_fragments(&compat, cfg.jsonl, cfg.output.as_deref())
+    } else {
+        output_fragments(&annotations, cfg.jsonl, cfg.output.as_deref())
+    }
+}
+
+// Print fragments to stdout, or write them to `output`, as a JSON array or as JSON Lines with one
+// fragment on each.
+fn output_fragments<T: Serialize>(
+    fragments: &[T],
+    jsonl: bool,
+    output: Option<&str>,
+) -> Result<(), Box<dyn Error>> {
+    match output {
+        Some(path) => write_fragments_file(path, fragments, jsonl)?,
+        None => write_fragments(io::BufWriter::new(io::stdout().lock()), fragments, jsonl)?,
+    }
+    Ok(())
+}
+
+// Compare the fragments extracted from the sources against the references in the prose files given
+// to --audit, as `recto --audit` does.
+fn run_audit(
+    cfg: &Config,
+    fragments: Vec<Fragment>,
+    symbols: &SymbolKey,
+) -> Result<(), Box<dyn Error>> {
+    let mut annotations = Annotations::new();
+    for f in fragments {
+        annotations.entry(f.id.to_owned()).or_default().push(f);
+    }
+    let options = WeaveOptions {
+        syntax: cfg.syntax,
+        ..WeaveOptions::default()
+    };
+    let mut references = vec![];
+    for filename in &cfg.audit {
+        let contents = fs::read_to_string(filename)?;
+        references.extend(find_references_with_options(
+            filename, &contents, symbols, &options,
+        ));
+    }
+    let report = audit(&annotations, &references);
+    println!("{}", report);
 
-    serde_json::to_writer(io::stdout(), &annotations)?;
+    if cfg.strict && !report.is_clean() {
+        return Err("Audit found unreferenced fragments or undefined references".into());
+    }
 
     Ok(())
 }
+
+// Read one of verso's outputs, keeping every fragment with a reused ID as `recto` does.
+fn read_fragments(filename: &str) -> Result<Annotations, Box<dyn Error>> {
+    let fragments: Vec<Fragment> = serde_json::from_str(&fs::read_to_string(filename)?)?;
+    let mut annotations = Annotations::new();
+    for f in fragments {
+        annotations.entry(f.id.to_owned()).or_default().push(f);
+    }
+    Ok(annotations)
+}
+
+// Summarize the differences between two outputs of verso, failing if there are any.
+fn run_diff(old: &str, new: &str) -> Result<(), Box<dyn Error>> {
+    let old = read_fragments(old)?;
+    let new = read_fragments(new)?;
+    let diff = diff_fragments(&old, &new);
+
+    for id in &diff.added {
+        println!("Added: {} ({})", id, new[id][0].file);
+    }
+    for id in &diff.removed {
+        println!("Removed: {} ({})", id, old[id][0].file);
+    }
+    for id in &diff.changed {
+        println!("Changed: {} ({})", id, new[id][0].file);
+    }
+    for id in &diff.moved {
+        println!("Moved: {} ({} -> {})", id, old[id][0].file, new[id][0].file);
+    }
+
+    if diff.is_empty() {
+        Ok(())
+    } else {
+        Err("Fragments differ".into())
+    }
+}